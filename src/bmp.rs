@@ -1,15 +1,79 @@
+use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
-use std::io::Result as IoResult;
+use std::io::{ErrorKind, Result as IoResult};
 
 extern crate byteorder;
-use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use self::byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+//-------------------------------------------------------------------- BmpError
+
+#[derive(Debug)]
+pub enum BmpError {
+    /// The file didn't start with 'B' 'M'.
+    BadMagic(u8, u8),
+    /// The DIB header size doesn't match any known version.
+    UnsupportedHeader(u32),
+    UnsupportedBitsPerPixel(u16),
+    /// The compression method and the bits per pixel it was used with.
+    UnsupportedCompression(u32, u16),
+    /// The image dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The header gave a negative width; only a negative height means anything.
+    NegativeWidth(i32),
+    /// The file ended before all of the data described by its headers was read.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for BmpError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            BmpError::BadMagic(b, m) => write!(formatter, "expected 'BM' magic but found {:?}", [b, m]),
+            BmpError::UnsupportedHeader(size) => write!(formatter, "unsupported DIB header size {}", size),
+            BmpError::UnsupportedBitsPerPixel(bpp) => write!(formatter, "unsupported bits per pixel {}", bpp),
+            BmpError::UnsupportedCompression(c, bpp) => write!(formatter, "unsupported compression method {} for {} bits per pixel", c, bpp),
+            BmpError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            BmpError::NegativeWidth(width) => write!(formatter, "negative image width {}", width),
+            BmpError::Truncated => write!(formatter, "unexpected end of file"),
+            BmpError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for BmpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BmpError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for BmpError {
+    fn from(err: ::std::io::Error) -> BmpError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => BmpError::Truncated,
+            _ => BmpError::Io(err),
+        }
+    }
+}
+
+pub type BmpResult<T> = Result<T, BmpError>;
 
 //------------------------------------------------------------------- BmpHeader
 
+#[derive(Clone)]
 pub struct BmpHeader {
     _b: char, // should be the 'B' character
     _m: char, // should be the 'M' character
@@ -20,20 +84,25 @@ pub struct BmpHeader {
 }
 
 impl BmpHeader {
-    // pub fn new() -> BmpHeader {
-    //     BmpHeader {
-    //         _b: 'B',
-    //         _m: 'M',
-    //         _file_size: 0,
-    //         _reserved_1: 0,
-    //         _reserved_2: 0,
-    //         image_data_offset: 54,
-    //     }
-    // }
-    pub fn load<R>(file: &mut R) -> IoResult<BmpHeader> where R: ::std::io::Read {
+    pub fn new(file_size: u32, image_data_offset: u32) -> BmpHeader {
+        BmpHeader {
+            _b: 'B',
+            _m: 'M',
+            _file_size: file_size,
+            _reserved_1: 0,
+            _reserved_2: 0,
+            image_data_offset: image_data_offset,
+        }
+    }
+    pub fn load<R>(file: &mut R) -> BmpResult<BmpHeader> where R: ::std::io::Read {
+        let b = file.read_u8()?;
+        let m = file.read_u8()?;
+        if b != b'B' || m != b'M' {
+            return Err(BmpError::BadMagic(b, m));
+        }
         Ok(BmpHeader {
-            _b: file.read_u8()? as char,
-            _m: file.read_u8()? as char,
+            _b: b as char,
+            _m: m as char,
             _file_size: file.read_u32::<LittleEndian>()?,
             _reserved_1: file.read_u16::<LittleEndian>()?,
             _reserved_2: file.read_u16::<LittleEndian>()?,
@@ -51,67 +120,337 @@ impl BmpHeader {
     }
 }
 
+#[test]
+fn reject_bad_magic() {
+    let data = b"PK\x03\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+    match BmpHeader::load(&mut ::std::io::Cursor::new(&data[..])) {
+        Err(BmpError::BadMagic(b'P', b'K')) => (),
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+    match BmpHeader::load(&mut ::std::io::Cursor::new(&b"BM\x00"[..])) {
+        Err(BmpError::Truncated) => (),
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
+
 //------------------------------------------------------------------- DibHeader
 
+const LCS_SRGB: u32 = 0x7352_4742; // 'sRGB'
+const PROFILE_LINKED: u32 = 0x4c49_4e4b; // 'LINK'
+const PROFILE_EMBEDDED: u32 = 0x4d42_4544; // 'MBED'
+
+/// The color space information carried by BITMAPV4HEADER and BITMAPV5HEADER, kept so that it
+/// survives a load/save round trip.
+#[derive(Clone, Debug)]
+pub struct ColorSpace {
+    pub cs_type: u32,
+    pub endpoints: [i32; 9],
+    pub gamma: [u32; 3],
+    /// Only present in V5 headers.
+    pub intent: u32,
+    /// The embedded ICC profile, or the profile's file name for linked profiles.
+    pub profile: Vec<u8>,
+}
+
+#[derive(Clone)]
 pub struct DibHeader {
     width: u32,
     height: u32,
+    /// Rows are stored top to bottom; signalled in the file by a negative height.
+    top_down: bool,
+    /// OS/2 BITMAPCOREHEADER files use 3 byte color table entries instead of 4.
+    core: bool,
     bpp: u16,
+    compression: u32,
+    image_size: u32,
+    h_ppm: i32,
+    v_ppm: i32,
+    color_palette_size: u32,
+    red_mask: u32,
+    green_mask: u32,
+    blue_mask: u32,
+    alpha_mask: u32,
+    color_space: Option<ColorSpace>,
+    /// Where the ICC profile is found, relative to the start of this header.
+    profile_offset: u32,
+    profile_size: u32,
 }
 
 impl DibHeader {
-    // pub fn new(width: u32, height: u32) -> DibHeader {
-    //     DibHeader {
-    //         width: width,
-    //         height: height,
-    //         bpp: 24,
-    //     }
-    // }
-    pub fn load<R>(file: &mut R) -> IoResult<DibHeader> where R: ::std::io::Read {
-        let _header_size = file.read_u32::<LittleEndian>()?;
-        let width = file.read_u32::<LittleEndian>()?;
-        let height = file.read_u32::<LittleEndian>()?;
+    pub fn new(width: u32, height: u32, bpp: u16, color_palette_size: u32) -> DibHeader {
+        // 32 bpp images are written with explicit masks so that readers honor the alpha channel
+        let compression = if bpp == 32 { BI_BITFIELDS } else { BI_RGB };
+        let (red_mask, green_mask, blue_mask, alpha_mask) = DibHeader::default_masks(bpp);
+        let row_size = (width as usize * bpp as usize + 31) / 32 * 4;
+        DibHeader {
+            width: width,
+            height: height,
+            top_down: false,
+            core: false,
+            bpp: bpp,
+            compression: compression,
+            image_size: (row_size * height as usize) as u32,
+            h_ppm: 0,
+            v_ppm: 0,
+            color_palette_size: color_palette_size,
+            red_mask: red_mask,
+            green_mask: green_mask,
+            blue_mask: blue_mask,
+            alpha_mask: alpha_mask,
+            color_space: None,
+            profile_offset: 0,
+            profile_size: 0,
+        }
+    }
+    /// The channel masks implied by an uncompressed (`BI_RGB`) image of the given depth.
+    fn default_masks(bpp: u16) -> (u32, u32, u32, u32) {
+        match bpp {
+            16 => (0x7c00, 0x03e0, 0x001f, 0),
+            32 => (0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0xff00_0000),
+            _ => (0, 0, 0, 0),
+        }
+    }
+    pub fn load<R>(file: &mut R) -> BmpResult<DibHeader> where R: ::std::io::Read {
+        let header_size = file.read_u32::<LittleEndian>()?;
+        if header_size == 12 {
+            return DibHeader::load_core(file);
+        }
+        match header_size {
+            16 | 64 => (), // OS/2 BITMAPINFOHEADER2 and its truncated form
+            40 | 52 | 56 | 108 | 124 => (), // BITMAPINFOHEADER, V2, V3, V4 and V5
+            s => return Err(BmpError::UnsupportedHeader(s)),
+        }
+
+        // read the whole header at once; fields missing from the shorter versions stay zeroed
+        let mut header = vec![0u8; 124];
+        file.read_exact(&mut header[4..header_size as usize])?;
+        if header_size == 40 {
+            // with the 40 byte version bit field masks directly follow the header
+            match LittleEndian::read_u32(&header[16..]) {
+                BI_BITFIELDS => file.read_exact(&mut header[40..52])?,
+                BI_ALPHABITFIELDS => file.read_exact(&mut header[40..56])?,
+                _ => (),
+            }
+        }
+        let u32_at = |offset: usize| LittleEndian::read_u32(&header[offset..]);
+        let i32_at = |offset: usize| LittleEndian::read_i32(&header[offset..]);
+        let width = i32_at(4);
+        let height = i32_at(8);
+        if width < 0 {
+            return Err(BmpError::NegativeWidth(width));
+        }
+        let bpp = LittleEndian::read_u16(&header[14..]);
+        let compression = u32_at(16);
+        let image_size = u32_at(20);
+        let h_ppm = i32_at(24);
+        let v_ppm = i32_at(28);
+        let color_palette_size = u32_at(32);
+        let _important_colors = u32_at(36);
+
+        match bpp {
+            1 | 4 | 8 | 24 | 16 | 32 => (),
+            _ => return Err(BmpError::UnsupportedBitsPerPixel(bpp)),
+        }
+
+        // the OS/2 header has its own, unrelated, fields past the first 40 bytes
+        let windows = header_size != 16 && header_size != 64;
+        let (mut red_mask, mut green_mask, mut blue_mask, mut alpha_mask) = DibHeader::default_masks(bpp);
+        match compression {
+            BI_RGB => (),
+            BI_BITFIELDS | BI_ALPHABITFIELDS if windows && (bpp == 16 || bpp == 32) => {
+                red_mask = u32_at(40);
+                green_mask = u32_at(44);
+                blue_mask = u32_at(48);
+                alpha_mask = u32_at(52);
+            },
+            BI_RLE8 if bpp == 8 => (),
+            BI_RLE4 if bpp == 4 => (),
+            c => return Err(BmpError::UnsupportedCompression(c, bpp)),
+        }
+
+        let profile_size = match u32_at(56) {
+            PROFILE_EMBEDDED | PROFILE_LINKED if windows && header_size >= 124 => u32_at(116),
+            _ => 0,
+        };
+        let color_space = if windows && header_size >= 108 {
+            let cs_type = u32_at(56);
+            let mut endpoints = [0i32; 9];
+            for (i, e) in endpoints.iter_mut().enumerate() {
+                *e = i32_at(60 + i * 4);
+            }
+            Some(ColorSpace {
+                cs_type: cs_type,
+                endpoints: endpoints,
+                gamma: [u32_at(96), u32_at(100), u32_at(104)],
+                intent: u32_at(108),
+                profile: Vec::new(), // filled in by `Bmp::load`
+            })
+        } else {
+            None
+        };
+
+        Ok(DibHeader {
+            width: width as u32,
+            height: (height as i64).abs() as u32,
+            top_down: height < 0,
+            core: false,
+            bpp: bpp,
+            compression: compression,
+            image_size: image_size,
+            h_ppm: h_ppm,
+            v_ppm: v_ppm,
+            color_palette_size: color_palette_size,
+            red_mask: red_mask,
+            green_mask: green_mask,
+            blue_mask: blue_mask,
+            alpha_mask: alpha_mask,
+            color_space: color_space,
+            profile_offset: u32_at(112),
+            profile_size: profile_size,
+        })
+    }
+    /// Reads the remainder of a 12 byte OS/2 BITMAPCOREHEADER.
+    fn load_core<R>(file: &mut R) -> BmpResult<DibHeader> where R: ::std::io::Read {
+        let width = file.read_u16::<LittleEndian>()?;
+        let height = file.read_u16::<LittleEndian>()?;
         let _color_planes = file.read_u16::<LittleEndian>()?;
         let bpp = file.read_u16::<LittleEndian>()?;
-        let _compression = file.read_u32::<LittleEndian>()?;
-        let _image_size = file.read_u32::<LittleEndian>()?;
-        let _h_ppm = file.read_i32::<LittleEndian>()?;
-        let _v_ppm = file.read_i32::<LittleEndian>()?;
-        let _color_palette_size = file.read_u32::<LittleEndian>()?;
-        let _important_colors = file.read_u32::<LittleEndian>()?;
 
-        if bpp != 24 {
-            panic!("bits per pixel was {} instead of 24", bpp);
+        match bpp {
+            1 | 4 | 8 | 24 => (),
+            _ => return Err(BmpError::UnsupportedBitsPerPixel(bpp)),
         }
 
         Ok(DibHeader {
-            width: width,
-            height: height,
-            bpp: bpp,
+            core: true,
+            ..DibHeader::new(width as u32, height as u32, bpp, 0)
         })
     }
+    /// Decodes a 16 or 32 bpp pixel value using the header's channel masks.
+    fn unpack_masked(&self, value: u32) -> Pixel {
+        Pixel {
+            r: DibHeader::extract_channel(value, self.red_mask),
+            g: DibHeader::extract_channel(value, self.green_mask),
+            b: DibHeader::extract_channel(value, self.blue_mask),
+            a: if self.alpha_mask == 0 { 255 } else { DibHeader::extract_channel(value, self.alpha_mask) },
+        }
+    }
+    /// Scales the bits selected by `mask` to the full 0-255 range.
+    fn extract_channel(value: u32, mask: u32) -> u8 {
+        if mask == 0 {
+            return 0;
+        }
+        let shift = mask.trailing_zeros();
+        let max = (mask >> shift) as u64;
+        (((value & mask) >> shift) as u64 * 255 / max) as u8
+    }
+    /// The number of entries in the color table that follows this header.
+    fn palette_len(&self) -> usize {
+        match self.bpp {
+            1 | 4 | 8 if self.color_palette_size == 0 || self.color_palette_size > 1 << self.bpp => 1 << self.bpp,
+            1 | 4 | 8 => self.color_palette_size as usize,
+            _ => 0,
+        }
+    }
+    /// The number of bytes used by each color table entry.
+    fn palette_entry_size(&self) -> usize {
+        if self.core { 3 } else { 4 }
+    }
+    /// The number of bytes used by a single row of pixel data, including the padding
+    /// needed to align every row to a 4 byte boundary.
+    fn row_size(&self) -> usize {
+        (self.width as usize * self.bpp as usize + 31) / 32 * 4
+    }
+    /// The number of bytes `save` writes; color space information needs the 124 byte version,
+    /// bit fields need the 108 byte version to carry an alpha mask and everything else uses
+    /// the 40 byte version.
+    fn saved_size(&self) -> u32 {
+        if self.color_space.is_some() {
+            124
+        } else if self.compression == BI_BITFIELDS {
+            108
+        } else {
+            40
+        }
+    }
+    /// Bottom up rows are always written, regardless of how the image was loaded.
     pub fn save<W>(&self, file: &mut W) -> IoResult<()> where W: ::std::io::Write {
-        file.write_u32::<LittleEndian>(40)?; // always write the 40 byte version
+        let size = self.saved_size();
+        file.write_u32::<LittleEndian>(size)?;
         file.write_u32::<LittleEndian>(self.width)?;
         file.write_u32::<LittleEndian>(self.height)?;
         file.write_u16::<LittleEndian>(1)?; // color planes
-        file.write_u16::<LittleEndian>(24)?; // 24 bpp
-        file.write_u32::<LittleEndian>(0)?; // compression method
-        file.write_u32::<LittleEndian>(self.width * self.height * 3)?; // image size (3 bytes per pixel)
-        file.write_i32::<LittleEndian>(0)?; // horizontal ppm
-        file.write_i32::<LittleEndian>(0)?; // vertical ppm
-        file.write_u32::<LittleEndian>(0)?; // color palette size
+        file.write_u16::<LittleEndian>(self.bpp)?;
+        file.write_u32::<LittleEndian>(self.compression)?;
+        file.write_u32::<LittleEndian>(self.image_size)?;
+        file.write_i32::<LittleEndian>(self.h_ppm)?;
+        file.write_i32::<LittleEndian>(self.v_ppm)?;
+        file.write_u32::<LittleEndian>(self.color_palette_size)?;
         file.write_u32::<LittleEndian>(0)?; // important colors
+        if size >= 108 {
+            let (red_mask, green_mask, blue_mask, alpha_mask) = if self.compression == BI_BITFIELDS {
+                (self.red_mask, self.green_mask, self.blue_mask, self.alpha_mask)
+            } else {
+                (0, 0, 0, 0)
+            };
+            file.write_u32::<LittleEndian>(red_mask)?;
+            file.write_u32::<LittleEndian>(green_mask)?;
+            file.write_u32::<LittleEndian>(blue_mask)?;
+            file.write_u32::<LittleEndian>(alpha_mask)?;
+            match self.color_space {
+                Some(ref cs) => {
+                    file.write_u32::<LittleEndian>(cs.cs_type)?;
+                    for e in &cs.endpoints {
+                        file.write_i32::<LittleEndian>(*e)?;
+                    }
+                    for g in &cs.gamma {
+                        file.write_u32::<LittleEndian>(*g)?;
+                    }
+                },
+                None => {
+                    file.write_u32::<LittleEndian>(LCS_SRGB)?;
+                    file.write_all(&[0u8; 36 + 12])?; // unused endpoints and gamma
+                },
+            }
+        }
+        if size >= 124 {
+            let cs = self.color_space.as_ref().unwrap();
+            file.write_u32::<LittleEndian>(cs.intent)?;
+            file.write_u32::<LittleEndian>(if cs.profile.is_empty() { 0 } else { self.profile_offset })?;
+            file.write_u32::<LittleEndian>(cs.profile.len() as u32)?;
+            file.write_u32::<LittleEndian>(0)?; // reserved
+        }
         Ok(())
     }
 }
 
+#[test]
+fn row_size_is_padded() {
+    assert_eq!(4, DibHeader::new(1, 1, 24, 0).row_size());
+    assert_eq!(12, DibHeader::new(3, 1, 24, 0).row_size());
+    assert_eq!(4, DibHeader::new(32, 1, 1, 0).row_size());
+    assert_eq!(8, DibHeader::new(33, 1, 1, 0).row_size());
+    assert_eq!(8, DibHeader::new(5, 1, 8, 0).row_size());
+}
+
+#[test]
+fn extract_masked_channels() {
+    let rgb565 = DibHeader { compression: BI_BITFIELDS, red_mask: 0xf800, green_mask: 0x07e0, blue_mask: 0x001f, alpha_mask: 0, ..DibHeader::new(1, 1, 16, 0) };
+    assert_eq!(Pixel {r: 255, g: 0, b: 0, a: 255}, rgb565.unpack_masked(0xf800));
+    assert_eq!(Pixel {r: 0, g: 255, b: 255, a: 255}, rgb565.unpack_masked(0x07ff));
+    let rgb555 = DibHeader::new(1, 1, 16, 0);
+    assert_eq!(Pixel {r: 0, g: 255, b: 0, a: 255}, rgb555.unpack_masked(0x03e0));
+    let bgra = DibHeader::new(1, 1, 32, 0);
+    assert_eq!(Pixel {r: 1, g: 2, b: 3, a: 4}, bgra.unpack_masked(0x0401_0203));
+}
+
 //----------------------------------------------------------------------- Pixel
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Pixel {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    pub a: u8,
 }
 
 impl Pixel {
@@ -120,6 +459,7 @@ impl Pixel {
             r: 0u8,
             g: 0u8,
             b: 0u8,
+            a: 255,
         }
     }
     pub fn red() -> Pixel {
@@ -127,6 +467,7 @@ impl Pixel {
             r: 255,
             g: 0,
             b: 0,
+            a: 255,
         }
     }
     pub fn green() -> Pixel {
@@ -134,6 +475,7 @@ impl Pixel {
             r: 0,
             g: 255,
             b: 0,
+            a: 255,
         }
     }
     pub fn blue() -> Pixel {
@@ -141,6 +483,7 @@ impl Pixel {
             r: 0,
             g: 0,
             b: 255,
+            a: 255,
         }
     }
     pub fn cyan() -> Pixel {
@@ -148,6 +491,7 @@ impl Pixel {
             r: 0,
             g: 255,
             b: 255,
+            a: 255,
         }
     }
     pub fn magenta() -> Pixel {
@@ -155,6 +499,7 @@ impl Pixel {
             r: 255,
             g: 0,
             b: 255,
+            a: 255,
         }
     }
     pub fn yellow() -> Pixel {
@@ -162,6 +507,7 @@ impl Pixel {
             r: 255,
             g: 255,
             b: 0,
+            a: 255,
         }
     }
     pub fn white() -> Pixel {
@@ -169,6 +515,7 @@ impl Pixel {
             r: 255,
             g: 255,
             b: 255,
+            a: 255,
         }
     }
     pub fn black() -> Pixel {
@@ -176,8 +523,13 @@ impl Pixel {
             r: 0,
             g: 0,
             b: 0,
+            a: 255,
         }
     }
+    /// The perceived brightness of the color, using the Rec. 601 weights.
+    pub fn luma(&self) -> u8 {
+        ((self.r as u32 * 299 + self.g as u32 * 587 + self.b as u32 * 114 + 500) / 1000) as u8
+    }
     pub fn as_tuple(&self) -> (i32, i32, i32) {
         (self.r as i32, self.g as i32, self.b as i32)
     }
@@ -187,32 +539,42 @@ impl Pixel {
         let r = parts[0].parse::<u8>().unwrap();
         let g = parts[1].parse::<u8>().unwrap();
         let b = parts[2].parse::<u8>().unwrap();
-        Pixel {r, g, b}
+        Pixel {r, g, b, a: 255}
+    }
+    /// Whether the pixel is fully transparent and should be left alone by dithering.
+    pub fn is_transparent(&self) -> bool {
+        self.a == 0
     }
 }
 
 #[test]
 fn parse_colors() {
-    assert_eq!(Pixel {r: 0, g: 0, b: 0}, Pixel::parse("0,0,0"));
-    assert_eq!(Pixel {r: 255, g: 255, b: 128}, Pixel::parse("255,255,128"));
+    assert_eq!(Pixel {r: 0, g: 0, b: 0, a: 255}, Pixel::parse("0,0,0"));
+    assert_eq!(Pixel {r: 255, g: 255, b: 128, a: 255}, Pixel::parse("255,255,128"));
 }
 
 //------------------------------------------------------------------------- Bmp
 
+#[derive(Clone)]
 pub struct Bmp {
     _bmp_header: BmpHeader,
     dib_header: DibHeader,
     pub pixels: Vec<Vec<Pixel>>,
+    /// The colors the image was loaded with or dithered to, if any.  When every
+    /// pixel is found in this palette the image is saved as an indexed bitmap.
+    pub palette: Option<Vec<Pixel>>,
 }
 
 impl Bmp {
-    // pub fn new(width: u32, height: u32) -> Bmp {
-    //     Bmp {
-    //         _bmp_header: BmpHeader::new(),
-    //         dib_header: DibHeader::new(width, height),
-    //         pixels: Bmp::create_pixels(width as usize, height as usize),
-    //     }
-    // }
+    /// Creates an all black image.
+    pub fn new(width: u32, height: u32) -> Bmp {
+        Bmp {
+            _bmp_header: BmpHeader::new(0, 54),
+            dib_header: DibHeader::new(width, height, 24, 0),
+            pixels: Bmp::create_pixels(width as usize, height as usize),
+            palette: None,
+        }
+    }
     fn create_pixels(width: usize, height: usize) -> Vec<Vec<Pixel>> {
         let mut pixels = Vec::with_capacity(width);
         for _ in 0..width {
@@ -226,57 +588,646 @@ impl Bmp {
     pub fn height(&self) -> u32 {
         self.dib_header.height
     }
-    pub fn load(path_str: &str) -> IoResult<Bmp> {
+    pub fn load(path_str: &str) -> BmpResult<Bmp> {
+        Bmp::load_with(path_str, &LoadOptions::default())
+    }
+    pub fn load_with(path_str: &str, options: &LoadOptions) -> BmpResult<Bmp> {
         // Into<Path>
         let path = Path::new(&path_str);
         let file = File::open(&path)?;
-        let mut file = ::std::io::BufReader::new(file);
-        let bh = BmpHeader::load(&mut file)?;
-        let dh = DibHeader::load(&mut file)?;
+        Bmp::read_from_with(&mut ::std::io::BufReader::new(file), options)
+    }
+    pub fn read_from<R>(reader: &mut R) -> BmpResult<Bmp> where R: Read + Seek {
+        Bmp::read_from_with(reader, &LoadOptions::default())
+    }
+    pub fn read_from_with<R>(file: &mut R, options: &LoadOptions) -> BmpResult<Bmp> where R: Read + Seek {
+        let bh = BmpHeader::load(file)?;
+        let mut dh = DibHeader::load(file)?;
+        if !options.allows(dh.width, dh.height) {
+            return Err(BmpError::TooLarge(dh.width, dh.height));
+        }
+
+        // the color table immediately follows the DIB header and is stored as BGR0, or BGR for
+        // OS/2 core headers
+        let mut palette = Vec::with_capacity(dh.palette_len());
+        let mut entry = [0u8; 4];
+        for _ in 0..dh.palette_len() {
+            file.read_exact(&mut entry[..dh.palette_entry_size()])?;
+            palette.push(Pixel {r: entry[2], g: entry[1], b: entry[0], a: 255});
+        }
 
-        let mut pixels = Bmp::create_pixels(dh.width as usize, dh.height as usize);
+        let width = dh.width as usize;
+        let height = dh.height as usize;
+        let mut pixels = Bmp::create_pixels(width, height);
+        // BMPs are usually stored bottom up, unless the height was negative
+        let top_down = dh.top_down;
+        let row_to_y = |row: usize| if top_down { row } else { height - row - 1 };
 
         file.seek(SeekFrom::Start(bh.image_data_offset as u64))?;
-        for y in (0..dh.height as usize).rev()  { // BMPs are stored bottom up
-            for x in 0..dh.width as usize {
-                pixels[x][y] = Pixel {
-                    r: file.read_u8()?,
-                    g: file.read_u8()?,
-                    b: file.read_u8()?,
+        if dh.compression == BI_RLE8 || dh.compression == BI_RLE4 {
+            let indices = rle_decode(file, width, height, dh.bpp)?;
+            for r in 0..height {
+                for x in 0..width {
+                    let index = indices[r * width + x] as usize;
+                    pixels[x][row_to_y(r)] = Bmp::palette_color(&palette, index);
+                }
+            }
+        }
+        let mut row = vec![0u8; dh.row_size()];
+        let uncompressed_rows = if dh.compression == BI_RLE8 || dh.compression == BI_RLE4 { 0 } else { height };
+        for r in 0..uncompressed_rows {
+            file.read_exact(&mut row)?;
+            let y = row_to_y(r);
+            for x in 0..width {
+                pixels[x][y] = match dh.bpp {
+                    24 => Pixel {
+                        b: row[x * 3],
+                        g: row[x * 3 + 1],
+                        r: row[x * 3 + 2],
+                        a: 255,
+                    },
+                    16 => dh.unpack_masked(LittleEndian::read_u16(&row[x * 2..]) as u32),
+                    32 => dh.unpack_masked(LittleEndian::read_u32(&row[x * 4..])),
+                    bpp => Bmp::palette_color(&palette, Bmp::unpack_index(&row, x, bpp)),
                 };
             }
         }
 
+        if let Some(ref mut cs) = dh.color_space {
+            if dh.profile_size > 0 {
+                // read through `take` so that a bogus size can't allocate more than the file holds
+                file.seek(SeekFrom::Start(14 + dh.profile_offset as u64))?;
+                file.take(dh.profile_size as u64).read_to_end(&mut cs.profile)?;
+                if cs.profile.len() != dh.profile_size as usize {
+                    return Err(BmpError::Truncated);
+                }
+            }
+        }
+
+        if dh.bpp == 32 && dh.compression == BI_RGB {
+            // plain 32 bpp images usually leave the fourth byte zeroed; only treat it as alpha
+            // when something actually uses it
+            let uses_alpha = pixels.iter().any(|column| column.iter().any(|p| p.a != 0));
+            if !uses_alpha {
+                for p in pixels.iter_mut().flat_map(|column| column.iter_mut()) {
+                    p.a = 255;
+                }
+            }
+        }
+
         Ok(Bmp {
             _bmp_header: bh,
             dib_header: dh,
             pixels: pixels,
+            palette: if palette.is_empty() { None } else { Some(palette) },
+        })
+    }
+    fn palette_color(palette: &[Pixel], index: usize) -> Pixel {
+        match palette.get(index) {
+            Some(p) => *p,
+            None => Pixel::new(), // out-of-range indices are treated as black
+        }
+    }
+    /// Whether any pixel is less than fully opaque.
+    pub fn has_alpha(&self) -> bool {
+        self.pixels.iter().any(|column| column.iter().any(|p| p.a != 255))
+    }
+    /// Extracts the `x`th palette index from a row packed at `bpp` bits per pixel, most
+    /// significant bits first.
+    fn unpack_index(row: &[u8], x: usize, bpp: u16) -> usize {
+        let bpp = bpp as usize;
+        let per_byte = 8 / bpp;
+        let byte = row[x / per_byte];
+        let shift = 8 - bpp * (x % per_byte + 1);
+        ((byte >> shift) & ((1u16 << bpp) - 1) as u8) as usize
+    }
+    /// The smallest indexed bit depth able to address `colors` palette entries.
+    fn indexed_bpp(colors: usize) -> u16 {
+        match colors {
+            0..=2 => 1,
+            3..=16 => 4,
+            _ => 8,
+        }
+    }
+    /// Expresses every pixel as an index into the image's palette, if possible.  With
+    /// `transparency` fully transparent pixels are mapped to a transparent entry, which is
+    /// appended to the palette when it doesn't already have one; without it every palette
+    /// entry must be opaque.
+    pub fn indexed(&self, transparency: bool) -> Option<Indexed> {
+        let mut palette = match self.palette {
+            Some(ref palette) if !palette.is_empty() && palette.len() <= 256 => palette.clone(),
+            _ => return None,
+        };
+        if !transparency && palette.iter().any(|p| p.a != 255) {
+            return None;
+        }
+        let mut lookup = HashMap::new();
+        for (i, p) in palette.iter().enumerate().rev() { // prefer the first of any duplicates
+            lookup.insert(*p, i as u8);
+        }
+        let mut transparent = palette.iter().position(|p| p.is_transparent()).map(|i| i as u8);
+
+        let width = self.width() as usize;
+        let height = self.height() as usize;
+        let mut indices = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let p = &self.pixels[x][y];
+                let index = match lookup.get(p) {
+                    Some(index) => *index,
+                    None if transparency && p.is_transparent() => match transparent {
+                        Some(index) => index,
+                        None if palette.len() < 256 => {
+                            palette.push(Pixel {r: 0, g: 0, b: 0, a: 0});
+                            transparent = Some(palette.len() as u8 - 1);
+                            palette.len() as u8 - 1
+                        },
+                        None => return None,
+                    },
+                    None => return None,
+                };
+                indices.push(index);
+            }
+        }
+        Some(Indexed {
+            palette: palette,
+            indices: indices,
+            transparent: transparent,
         })
     }
-    pub fn save(&self, path_str: &str) -> IoResult<()> {
+    pub fn save(&self, path_str: &str) -> BmpResult<()> {
+        self.save_with(path_str, &SaveOptions::default())
+    }
+    pub fn save_with(&self, path_str: &str, options: &SaveOptions) -> BmpResult<()> {
         let path = Path::new(&path_str);
-        match File::create(&path) {
-            Ok(file) => {
-                let mut file = ::std::io::BufWriter::new(file);
-                self._bmp_header.save(&mut file)?;
-                self.dib_header.save(&mut file)?;
-                file.seek(SeekFrom::Start(self._bmp_header.image_data_offset as u64))?;
-                for y in (0..self.dib_header.height).rev() { // BMPs are stored bottom up
-                    for x in 0..self.dib_header.width {
-                        let pixel = &self.pixels[x as usize][y as usize];
-                        file.write_u8(pixel.r)?;
-                        file.write_u8(pixel.g)?;
-                        file.write_u8(pixel.b)?;
+        let file = File::create(&path)?;
+        let mut file = ::std::io::BufWriter::new(file);
+        self.write_to_with(&mut file, options)?;
+        file.flush()?;
+        Ok(())
+    }
+    pub fn write_to<W>(&self, writer: &mut W) -> BmpResult<()> where W: Write {
+        self.write_to_with(writer, &SaveOptions::default())
+    }
+    /// Writes the image sequentially, so `writer` needn't be seekable.
+    pub fn write_to_with<W>(&self, file: &mut W, options: &SaveOptions) -> BmpResult<()> where W: Write {
+        let indexed = self.indexed(false);
+        let has_alpha = self.has_alpha();
+        let (bpp, palette_len) = match indexed {
+            // run length encoding only exists for 4 and 8 bpp
+            Some(ref indexed) if options.rle => (Bmp::indexed_bpp(indexed.palette.len()).max(4), indexed.palette.len() as u32),
+            Some(ref indexed) => (Bmp::indexed_bpp(indexed.palette.len()), indexed.palette.len() as u32),
+            None if has_alpha => (32, 0),
+            None => (24, 0),
+        };
+        let mut dh = DibHeader::new(self.width(), self.height(), bpp, palette_len);
+        dh.h_ppm = self.dib_header.h_ppm;
+        dh.v_ppm = self.dib_header.v_ppm;
+        dh.color_space = self.dib_header.color_space.clone();
+
+        let width = dh.width as usize;
+        let height = dh.height as usize;
+        let mut data = Vec::with_capacity(dh.image_size as usize);
+        match indexed {
+            Some(ref indexed) if options.rle => {
+                let mut rows = Vec::with_capacity(width * height);
+                for y in (0..height).rev() { // BMPs are stored bottom up
+                    rows.extend_from_slice(&indexed.indices[y * width..(y + 1) * width]);
+                }
+                rle_encode(&rows, width, bpp, &mut data);
+                dh.compression = if bpp == 8 { BI_RLE8 } else { BI_RLE4 };
+                dh.image_size = data.len() as u32;
+            },
+            _ => {
+                let mut row = vec![0u8; dh.row_size()];
+                for y in (0..height).rev() { // BMPs are stored bottom up
+                    for b in row.iter_mut() {
+                        *b = 0;
                     }
+                    for x in 0..width {
+                        let pixel = &self.pixels[x][y];
+                        match indexed {
+                            Some(ref indexed) => {
+                                let bpp = bpp as usize;
+                                let per_byte = 8 / bpp;
+                                let shift = 8 - bpp * (x % per_byte + 1);
+                                row[x / per_byte] |= indexed.indices[y * width + x] << shift;
+                            },
+                            None if bpp == 32 => {
+                                row[x * 4] = pixel.b;
+                                row[x * 4 + 1] = pixel.g;
+                                row[x * 4 + 2] = pixel.r;
+                                row[x * 4 + 3] = pixel.a;
+                            },
+                            None => {
+                                row[x * 3] = pixel.b;
+                                row[x * 3 + 1] = pixel.g;
+                                row[x * 3 + 2] = pixel.r;
+                            },
+                        }
+                    }
+                    data.extend_from_slice(&row);
                 }
-
-                file.flush()
             },
-            Err(err) => panic!(err)
         }
+
+        let image_data_offset = 14 + dh.saved_size() + palette_len * 4;
+        // any ICC profile goes after the pixel data
+        let profile = dh.color_space.as_ref().map_or(&[][..], |cs| &cs.profile[..]);
+        dh.profile_offset = image_data_offset - 14 + dh.image_size;
+        let bh = BmpHeader::new(image_data_offset + dh.image_size + profile.len() as u32, image_data_offset);
+        bh.save(file)?;
+        dh.save(file)?;
+        if let Some(ref indexed) = indexed {
+            for p in &indexed.palette {
+                file.write_u8(p.b)?;
+                file.write_u8(p.g)?;
+                file.write_u8(p.r)?;
+                file.write_u8(0)?;
+            }
+        }
+        file.write_all(&data)?;
+        file.write_all(profile)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn stream_round_trip() {
+    let mut bmp = Bmp::new(5, 3);
+    bmp.pixels[0][0] = Pixel::red();
+    bmp.pixels[4][2] = Pixel::blue();
+    bmp.pixels[2][1] = Pixel {r: 1, g: 2, b: 3, a: 4};
+    let mut data = Vec::new();
+    bmp.write_to(&mut data).unwrap();
+    let loaded = Bmp::read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+    assert_eq!((5, 3), (loaded.width(), loaded.height()));
+    assert_eq!(bmp.pixels, loaded.pixels);
+
+    // indexed and run length encoded
+    bmp.pixels[2][1] = Pixel::white();
+    bmp.palette = Some(vec![Pixel::black(), Pixel::white(), Pixel::red(), Pixel::blue()]);
+    for rle in &[false, true] {
+        let mut data = Vec::new();
+        bmp.write_to_with(&mut data, &SaveOptions { rle: *rle }).unwrap();
+        let loaded = Bmp::read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+        assert_eq!(bmp.pixels, loaded.pixels);
+        assert_eq!(bmp.palette, loaded.palette);
     }
 }
 
+#[test]
+fn indexed_round_trip_at_each_depth() {
+    // palettes just big enough for 1, 4 and 8 bpp, on an odd width so every row is padded
+    for &(colors, bpp, row_size) in &[(2, 1, 4), (16, 4, 4), (256, 8, 8)] {
+        let palette: Vec<Pixel> = (0..colors).map(|i| Pixel {r: i as u8, g: 255 - i as u8, b: 7, a: 255}).collect();
+        let mut bmp = Bmp::new(7, 3);
+        for x in 0..7 {
+            for y in 0..3 {
+                bmp.pixels[x][y] = palette[(x * 3 + y * 5) % colors];
+            }
+        }
+        bmp.palette = Some(palette.clone());
+        let indexed = bmp.indexed(false).unwrap();
+        assert_eq!(palette, indexed.palette);
+        assert_eq!(((6 * 3 + 2 * 5) % colors) as u8, indexed.indices[2 * 7 + 6]);
+
+        let mut data = Vec::new();
+        bmp.write_to(&mut data).unwrap();
+        assert_eq!(bpp, LittleEndian::read_u16(&data[28..]));
+        let offset = LittleEndian::read_u32(&data[10..]) as usize;
+        assert_eq!(14 + 40 + colors * 4, offset);
+        assert_eq!(offset + row_size * 3, data.len());
+        // the padding after the last pixel of each row is zeroed
+        let used = (7 * bpp as usize).div_ceil(8);
+        for row in data[offset..].chunks(row_size) {
+            assert!(row[used..].iter().all(|b| *b == 0));
+        }
+        let loaded = Bmp::read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+        assert_eq!(bmp.pixels, loaded.pixels);
+        assert_eq!(Some(palette), loaded.palette);
+    }
+}
+
+#[test]
+fn indexed_needs_every_pixel_in_the_palette() {
+    let mut bmp = Bmp::new(2, 1);
+    assert!(bmp.indexed(false).is_none());
+    bmp.palette = Some(vec![Pixel::black(), Pixel::white()]);
+    bmp.pixels[1][0] = Pixel::red();
+    assert!(bmp.indexed(false).is_none());
+    // transparent pixels get an entry of their own
+    bmp.pixels[1][0] = Pixel {r: 9, g: 9, b: 9, a: 0};
+    assert!(bmp.indexed(false).is_none());
+    let indexed = bmp.indexed(true).unwrap();
+    assert_eq!(vec![0, 2], indexed.indices);
+    assert_eq!(Some(2), indexed.transparent);
+    assert_eq!(3, indexed.palette.len());
+}
+
+#[test]
+fn reject_huge_headers() {
+    // each side is within the limits, but together they'd need gigabytes of pixels
+    let mut headers = Vec::new();
+    DibHeader::new(16384, 16384, 24, 0).save(&mut headers).unwrap();
+    match Bmp::read_from(&mut ::std::io::Cursor::new(bmp_file(&headers, &[]))) {
+        Err(BmpError::TooLarge(16384, 16384)) => (),
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+    let options = LoadOptions { max_pixels: 16384 * 16384, ..LoadOptions::default() };
+    assert!(options.allows(16384, 16384));
+    assert!(!options.allows(16385, 1));
+}
+
+/// Prefixes a DIB header, color table and pixel data with a file header.
+#[cfg(test)]
+fn bmp_file(headers: &[u8], pixels: &[u8]) -> Vec<u8> {
+    let mut data = b"BM".to_vec();
+    let offset = 14 + headers.len() as u32;
+    data.write_u32::<LittleEndian>(offset + pixels.len() as u32).unwrap();
+    data.write_u32::<LittleEndian>(0).unwrap();
+    data.write_u32::<LittleEndian>(offset).unwrap();
+    data.extend_from_slice(headers);
+    data.extend_from_slice(pixels);
+    data
+}
+
+/// A BITMAPV5HEADER with the given masks and ICC profile location.
+#[cfg(test)]
+fn v5_header(width: i32, height: i32, bpp: u16, masks: [u32; 4], cs_type: u32, profile: (u32, u32)) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.write_u32::<LittleEndian>(124).unwrap();
+    header.write_i32::<LittleEndian>(width).unwrap();
+    header.write_i32::<LittleEndian>(height).unwrap();
+    header.write_u16::<LittleEndian>(1).unwrap();
+    header.write_u16::<LittleEndian>(bpp).unwrap();
+    header.write_u32::<LittleEndian>(BI_BITFIELDS).unwrap();
+    header.extend_from_slice(&[0; 20]); // image size, resolution and color counts
+    for mask in &masks {
+        header.write_u32::<LittleEndian>(*mask).unwrap();
+    }
+    header.write_u32::<LittleEndian>(cs_type).unwrap();
+    header.extend_from_slice(&[0; 48]); // endpoints and gamma
+    header.write_u32::<LittleEndian>(4).unwrap(); // LCS_GM_IMAGES
+    header.write_u32::<LittleEndian>(profile.0).unwrap();
+    header.write_u32::<LittleEndian>(profile.1).unwrap();
+    header.write_u32::<LittleEndian>(0).unwrap();
+    header
+}
+
+#[test]
+fn read_v5_bitfields() {
+    // RGB565, which only bit fields can express at 16 bpp
+    let header = v5_header(2, 1, 16, [0xf800, 0x07e0, 0x001f, 0], LCS_SRGB, (0, 0));
+    let data = bmp_file(&header, &[0x00, 0xf8, 0xe0, 0x07]);
+    let bmp = Bmp::read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+    assert_eq!(vec![Pixel::red()], bmp.pixels[0]);
+    assert_eq!(vec![Pixel::green()], bmp.pixels[1]);
+    assert_eq!(LCS_SRGB, bmp.dib_header.color_space.unwrap().cs_type);
+}
+
+#[test]
+fn read_os2_core_header() {
+    // a 12 byte header followed by a color table of BGR triples
+    let mut headers = Vec::new();
+    headers.write_u32::<LittleEndian>(12).unwrap();
+    headers.write_u16::<LittleEndian>(3).unwrap();
+    headers.write_u16::<LittleEndian>(1).unwrap();
+    headers.write_u16::<LittleEndian>(1).unwrap();
+    headers.write_u16::<LittleEndian>(1).unwrap();
+    headers.extend_from_slice(&[0xff, 0, 0, 0, 0, 0xff]);
+    let bmp = Bmp::read_from(&mut ::std::io::Cursor::new(bmp_file(&headers, &[0xa0, 0, 0, 0]))).unwrap();
+    assert_eq!(Some(vec![Pixel::blue(), Pixel::red()]), bmp.palette);
+    assert_eq!(vec![Pixel::red(), Pixel::blue(), Pixel::red()], bmp.pixels.iter().map(|column| column[0]).collect::<Vec<_>>());
+}
+
+#[test]
+fn read_top_down() {
+    // a negative height marks the first row as the top one
+    let mut headers = Vec::new();
+    DibHeader::new(1, 2, 24, 0).save(&mut headers).unwrap();
+    LittleEndian::write_i32(&mut headers[8..], -2);
+    let bmp = Bmp::read_from(&mut ::std::io::Cursor::new(bmp_file(&headers, &[0, 0, 0xff, 0, 0xff, 0, 0, 0]))).unwrap();
+    assert_eq!((1, 2), (bmp.width(), bmp.height()));
+    assert_eq!(vec![Pixel::red(), Pixel::blue()], bmp.pixels[0]);
+
+    // but there's no right-to-left
+    LittleEndian::write_i32(&mut headers[4..], -1);
+    match Bmp::read_from(&mut ::std::io::Cursor::new(bmp_file(&headers, &[0; 8]))) {
+        Err(BmpError::NegativeWidth(-1)) => (),
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn icc_profile_round_trip() {
+    let profile = b"not really an ICC profile";
+    // the profile follows a single 32 bpp pixel
+    let header = v5_header(1, 1, 32, [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0], PROFILE_EMBEDDED, (128, profile.len() as u32));
+    let mut pixels = vec![0, 0, 0xff, 0];
+    pixels.extend_from_slice(profile);
+    let bmp = Bmp::read_from(&mut ::std::io::Cursor::new(bmp_file(&header, &pixels))).unwrap();
+    assert_eq!(vec![Pixel::red()], bmp.pixels[0]);
+    assert_eq!(&profile[..], &bmp.dib_header.color_space.as_ref().unwrap().profile[..]);
+
+    let mut data = Vec::new();
+    bmp.write_to(&mut data).unwrap();
+    assert_eq!(124, LittleEndian::read_u32(&data[14..]));
+    let loaded = Bmp::read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+    let cs = loaded.dib_header.color_space.unwrap();
+    assert_eq!(PROFILE_EMBEDDED, cs.cs_type);
+    assert_eq!(&profile[..], &cs.profile[..]);
+    assert_eq!(bmp.pixels, loaded.pixels);
+}
+
+/// An image expressed as indices into a palette of at most 256 colors, as produced by
+/// `Bmp::indexed`.
+#[derive(Clone, Debug)]
+pub struct Indexed {
+    pub palette: Vec<Pixel>,
+    /// One palette index per pixel, row by row from the top left.
+    pub indices: Vec<u8>,
+    /// The palette entry fully transparent pixels were mapped to, if any.
+    pub transparent: Option<u8>,
+}
+
+/// Options controlling how `Bmp::load_with` decodes an image.
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    /// Images wider than this are rejected before any pixel storage is allocated.
+    pub max_width: u32,
+    /// Images taller than this are rejected before any pixel storage is allocated.
+    pub max_height: u32,
+    /// Images with more pixels than this are rejected before any pixel storage is
+    /// allocated, however their width and height are shaped.
+    pub max_pixels: u64,
+}
+
+impl LoadOptions {
+    /// Whether an image of the given size is within the limits.
+    pub fn allows(&self, width: u32, height: u32) -> bool {
+        width <= self.max_width && height <= self.max_height && width as u64 * height as u64 <= self.max_pixels
+    }
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions {
+            max_width: 16384,
+            max_height: 16384,
+            // 256 MiB of pixels
+            max_pixels: 1 << 26,
+        }
+    }
+}
+
+/// Options controlling how `Bmp::save_with` encodes an image.
+#[derive(Clone, Debug, Default)]
+pub struct SaveOptions {
+    /// Run length encode indexed images as `BI_RLE4` or `BI_RLE8`.  Images that can't be
+    /// saved as indexed are written uncompressed regardless.
+    pub rle: bool,
+}
+
+//------------------------------------------------------------------------- Rle
+
+/// Decodes `BI_RLE4`/`BI_RLE8` data into one palette index per pixel, in the same bottom up
+/// row order as the file.  Pixels skipped by delta or end-of-line escapes are left as index 0.
+fn rle_decode<R>(file: &mut R, width: usize, height: usize, bpp: u16) -> IoResult<Vec<u8>> where R: Read {
+    let mut indices = vec![0u8; width * height];
+    let mut x = 0;
+    let mut y = 0;
+    {
+        let mut put = |x: usize, y: usize, index: u8| {
+            if x < width && y < height {
+                indices[y * width + x] = index;
+            }
+        };
+        while y < height {
+            let count = file.read_u8()? as usize;
+            let value = file.read_u8()?;
+            if count > 0 {
+                // encoded mode; RLE4 alternates between the two nibbles of `value`
+                for i in 0..count {
+                    let index = match (bpp, i % 2) {
+                        (8, _) => value,
+                        (_, 0) => value >> 4,
+                        _ => value & 0x0f,
+                    };
+                    put(x, y, index);
+                    x += 1;
+                }
+                continue;
+            }
+            match value {
+                0 => { // end of line
+                    x = 0;
+                    y += 1;
+                },
+                1 => break, // end of bitmap
+                2 => { // delta
+                    x += file.read_u8()? as usize;
+                    y += file.read_u8()? as usize;
+                },
+                n => { // absolute mode, padded to a 16 bit boundary
+                    let n = n as usize;
+                    let bytes = if bpp == 8 { n } else { (n + 1) / 2 };
+                    let mut run = vec![0u8; bytes + bytes % 2];
+                    file.read_exact(&mut run)?;
+                    for i in 0..n {
+                        let index = match (bpp, i % 2) {
+                            (8, _) => run[i],
+                            (_, 0) => run[i / 2] >> 4,
+                            _ => run[i / 2] & 0x0f,
+                        };
+                        put(x, y, index);
+                        x += 1;
+                    }
+                },
+            }
+        }
+    }
+    Ok(indices)
+}
+
+/// Run length encodes rows of palette indices as `BI_RLE4` (`bpp` 4) or `BI_RLE8` (`bpp` 8).
+fn rle_encode(indices: &[u8], width: usize, bpp: u16, out: &mut Vec<u8>) {
+    let pack = |run: &[u8], out: &mut Vec<u8>| {
+        let start = out.len();
+        if bpp == 8 {
+            out.extend_from_slice(run);
+        } else {
+            for pair in run.chunks(2) {
+                out.push(pair[0] << 4 | pair.get(1).map_or(0, |lo| *lo));
+            }
+        }
+        if (out.len() - start) % 2 == 1 {
+            out.push(0);
+        }
+    };
+    for row in indices.chunks(width) {
+        let mut x = 0;
+        while x < row.len() {
+            let mut run = 1;
+            while x + run < row.len() && run < 255 && row[x + run] == row[x] {
+                run += 1;
+            }
+            if run > 1 {
+                let value = if bpp == 8 { row[x] } else { row[x] << 4 | row[x] };
+                out.push(run as u8);
+                out.push(value);
+                x += run;
+                continue;
+            }
+
+            // gather pixels up to the start of the next repeat
+            let mut literal = 1;
+            while x + literal < row.len() && literal < 255 &&
+                  !(x + literal + 1 < row.len() && row[x + literal] == row[x + literal + 1]) {
+                literal += 1;
+            }
+            if literal < 3 {
+                // absolute mode needs at least 3 pixels; use single pixel runs instead
+                for &index in &row[x..x + literal] {
+                    out.push(1);
+                    out.push(if bpp == 8 { index } else { index << 4 });
+                }
+            } else {
+                out.push(0);
+                out.push(literal as u8);
+                pack(&row[x..x + literal], out);
+            }
+            x += literal;
+        }
+        out.push(0);
+        out.push(0); // end of line
+    }
+    out.push(0);
+    out.push(1); // end of bitmap
+}
+
+#[test]
+fn rle_round_trip() {
+    let rows = vec![1, 1, 1, 1, 2, 3, 4, 5, 5, 6,
+                    7, 8, 9, 9, 9, 9, 9, 9, 9, 0];
+    for bpp in &[4, 8] {
+        let mut data = Vec::new();
+        rle_encode(&rows, 10, *bpp, &mut data);
+        let decoded = rle_decode(&mut ::std::io::Cursor::new(data), 10, 2, *bpp).unwrap();
+        assert_eq!(rows, decoded);
+    }
+}
+
+#[test]
+fn rle_decode_escapes() {
+    // 2 pixels of 7, a delta of (1, 1), 1 pixel of 3, end of line, absolute 3 pixels, end of bitmap
+    let data = vec![2, 7, 0, 2, 1, 1, 1, 3, 0, 0, 0, 3, 4, 5, 6, 0, 0, 1];
+    let decoded = rle_decode(&mut ::std::io::Cursor::new(data), 4, 3, 8).unwrap();
+    assert_eq!(vec![7, 7, 0, 0,
+                    0, 0, 0, 3,
+                    4, 5, 6, 0], decoded);
+}
+
 impl Debug for Bmp {
     fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
         write!(formatter, "bits-per-pixel={}, width={}, height={}", self.dib_header.bpp, self.width(), self.height())
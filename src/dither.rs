@@ -1,223 +1,542 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
 use bmp::{Bmp, Pixel};
 
-struct Matrix {
-    values: (((), (), (), i32, i32),
-             (i32, i32, i32, i32, i32),
-             (i32, i32, i32, i32, i32)),
+//----------------------------------------------------------------- KernelError
+
+#[derive(Debug, PartialEq)]
+pub enum KernelError {
+    /// There was no `*` marking the current pixel, or more than one.
+    Origin,
+    /// A weight or the divisor wasn't a number.
+    BadNumber(String),
+    /// The rows weren't all the same width.
+    Ragged,
+    /// A weight fell on a pixel that's already been dithered.
+    Behind,
+    /// The divisor was zero or negative.
+    Divisor,
+    /// The weights' magnitudes added up to more than an `i32` holds.
+    TooLarge,
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            KernelError::Origin => write!(formatter, "a kernel needs exactly one `*` for the current pixel"),
+            KernelError::BadNumber(ref cell) => write!(formatter, "'{}' isn't a number", cell),
+            KernelError::Ragged => write!(formatter, "kernel rows differ in width"),
+            KernelError::Behind => write!(formatter, "kernel weights must be after the current pixel"),
+            KernelError::Divisor => write!(formatter, "a kernel's divisor must be positive"),
+            KernelError::TooLarge => write!(formatter, "kernel weights are too large"),
+        }
+    }
+}
+
+impl Error for KernelError {}
+
+//---------------------------------------------------------------------- Kernel
+
+/// An error diffusion kernel of any size.  Each pixel's error is divided by `divisor` and
+/// spread to its neighbors by the weights, a grid of rows laid over the image with
+/// `origin`, a column and row, on the current pixel.  Weights before the origin in scan
+/// order fall on pixels already dithered and must be zero.
+///
+/// Kernels are written as rows separated by `;`, with `*` for the current pixel, `-` for
+/// pixels already dithered, and an optional `/ divisor` that defaults to the sum of the
+/// weights; Floyd-Steinberg is `- * 7; 3 5 1 / 16`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Kernel {
+    weights: Vec<Vec<i32>>,
+    origin: (usize, usize),
     divisor: i32,
 }
 
+impl Kernel {
+    /// Checks that `origin` lies within `weights`, that the rows are the same width, that
+    /// every weight up to and including the origin's is zero and that `divisor` is positive.
+    pub fn new(weights: Vec<Vec<i32>>, origin: (usize, usize), divisor: i32) -> Result<Kernel, KernelError> {
+        if origin.1 >= weights.len() || origin.0 >= weights[origin.1].len() {
+            return Err(KernelError::Origin);
+        }
+        if weights.iter().any(|row| row.len() != weights[0].len()) {
+            return Err(KernelError::Ragged);
+        }
+        let behind = weights[..origin.1].iter().any(|row| row.iter().any(|&w| w != 0))
+            || weights[origin.1][..=origin.0].iter().any(|&w| w != 0);
+        if behind {
+            return Err(KernelError::Behind);
+        }
+        if divisor <= 0 {
+            return Err(KernelError::Divisor);
+        }
+        // bounds the running totals `matrix_dither_with` spreads error by
+        if weights.iter().flat_map(|row| row.iter()).map(|&w| (w as i64).abs()).sum::<i64>() > i32::MAX as i64 {
+            return Err(KernelError::TooLarge);
+        }
+        Ok(Kernel {
+            weights: weights,
+            origin: origin,
+            divisor: divisor,
+        })
+    }
+    /// The rows of weights, including those before the origin, which are zero.
+    pub fn weights(&self) -> &Vec<Vec<i32>> {
+        &self.weights
+    }
+    /// The current pixel's column and row within `weights`.
+    pub fn origin(&self) -> (usize, usize) {
+        self.origin
+    }
+    /// What the weights are divided by; the error they pass on is their sum over this.
+    pub fn divisor(&self) -> i32 {
+        self.divisor
+    }
+    /// The nonzero weights as column and row offsets from the current pixel.
+    fn offsets(&self) -> Vec<(isize, usize, i32)> {
+        let mut offsets = vec![];
+        for (row, weights) in self.weights.iter().enumerate().skip(self.origin.1) {
+            for (column, &weight) in weights.iter().enumerate() {
+                if weight != 0 {
+                    offsets.push((column as isize - self.origin.0 as isize, row - self.origin.1, weight));
+                }
+            }
+        }
+        offsets
+    }
+}
+
+impl FromStr for Kernel {
+    type Err = KernelError;
+
+    fn from_str(text: &str) -> Result<Kernel, KernelError> {
+        let (cells, divisor) = match text.find('/') {
+            Some(i) => (&text[..i], Some(text[i + 1..].trim())),
+            None => (text, None),
+        };
+        let mut weights = vec![];
+        let mut origin = None;
+        for (y, row) in cells.split(';').enumerate() {
+            let mut values = vec![];
+            for (x, cell) in row.split_whitespace().enumerate() {
+                match cell {
+                    "*" if origin.is_none() => {
+                        origin = Some((x, y));
+                        values.push(0);
+                    },
+                    "*" => return Err(KernelError::Origin),
+                    "-" => values.push(0),
+                    _ => values.push(cell.parse().map_err(|_| KernelError::BadNumber(cell.to_string()))?),
+                }
+            }
+            weights.push(values);
+        }
+        let origin = origin.ok_or(KernelError::Origin)?;
+        let divisor = match divisor {
+            Some(divisor) => divisor.parse().map_err(|_| KernelError::BadNumber(divisor.to_string()))?,
+            None => {
+                let sum = weights.iter().flat_map(|row| row.iter()).map(|&w| w as i64).sum::<i64>();
+                if sum > i32::MAX as i64 {
+                    return Err(KernelError::TooLarge);
+                }
+                sum.max(1) as i32
+            },
+        };
+        Kernel::new(weights, origin, divisor)
+    }
+}
+
+impl fmt::Display for Kernel {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        for (y, row) in self.weights.iter().enumerate() {
+            if y > 0 {
+                write!(formatter, "; ")?;
+            }
+            for (x, weight) in row.iter().enumerate() {
+                if x > 0 {
+                    write!(formatter, " ")?;
+                }
+                if (x, y) == self.origin {
+                    write!(formatter, "*")?;
+                } else if (y, x) < (self.origin.1, self.origin.0) {
+                    write!(formatter, "-")?;
+                } else {
+                    write!(formatter, "{}", weight)?;
+                }
+            }
+        }
+        write!(formatter, " / {}", self.divisor)
+    }
+}
+
+/// Maps every pixel to its closest color without spreading any error.
+pub const CLOSEST: &str = "* 0 / 1";
+/// Carries all of the error to the next pixel on the row.
+pub const DIFFUSE: &str = "* 1 / 1";
+/// Floyd-Steinberg.
+pub const FLOYD: &str = "- * 7; 3 5 1 / 16";
+/// The simplified "false" Floyd-Steinberg.
+pub const FALSE_FLOYD: &str = "* 3; 3 2 / 8";
+/// Jarvis, Judice and Ninke.
+pub const JJN: &str = "- - * 7 5; 3 5 7 5 3; 1 3 5 3 1 / 48";
+/// Stucki.
+pub const STUCKI: &str = "- - * 8 4; 2 4 8 4 2; 1 2 4 2 1 / 42";
+/// Atkinson, which spreads only 6/8 of the error.
+pub const ATKINSON: &str = "- * 1 1; 1 1 1 0; 0 1 0 0 / 8";
+/// Burkes.
+pub const BURKES: &str = "- - * 8 4; 2 4 8 4 2 / 32";
+/// Sierra.
+pub const SIERRA: &str = "- - * 5 3; 2 4 5 4 2; 0 2 3 2 0 / 32";
+/// Two row Sierra.
+pub const SIERRA2: &str = "- - * 4 3; 1 2 3 2 1 / 16";
+/// Sierra Lite.
+pub const SIERRA_LITE: &str = "- * 2; 1 1 0 / 4";
+/// Fan, which reaches further left than Floyd-Steinberg.
+pub const FAN: &str = "- - * 7; 1 3 5 0 / 16";
+/// Shiau-Fan.
+pub const SHIAU_FAN: &str = "- - * 4; 1 1 2 0 / 8";
+/// Shiau-Fan's five cell variant.
+pub const SHIAU_FAN2: &str = "- - - * 8; 1 1 2 4 0 / 16";
+
+impl Kernel {
+    /// One of the built-in kernels by the name the command line uses: `closest`, `diffuse`,
+    /// `floyd`, `ffloyd`, `jarvis`, `stucki`, `atkinson`, `burkes`, `sierra`, `sierra2`,
+    /// `sierra_lite`, `fan`, `shiau_fan` or `shiau_fan2`.
+    pub fn named(name: &str) -> Option<Kernel> {
+        let text = match name {
+            "closest" => CLOSEST,
+            "diffuse" => DIFFUSE,
+            "floyd" => FLOYD,
+            "ffloyd" => FALSE_FLOYD,
+            "jarvis" => JJN,
+            "stucki" => STUCKI,
+            "atkinson" => ATKINSON,
+            "burkes" => BURKES,
+            "sierra" => SIERRA,
+            "sierra2" => SIERRA2,
+            "sierra_lite" => SIERRA_LITE,
+            "fan" => FAN,
+            "shiau_fan" => SHIAU_FAN,
+            "shiau_fan2" => SHIAU_FAN2,
+            _ => return None,
+        };
+        Some(kernel(text))
+    }
+}
+
+/// The fixed point scale diffused error is carried at, in steps per color level.
+const ERROR_SCALE: i32 = 256;
+
+/// The most error a pixel can gather per channel, far beyond what a palette spanning the
+/// image leaves, so that palettes out of the image's gamut can't overflow the carry.
+const MAX_CARRY: i32 = 1024 * ERROR_SCALE;
+
+/// Options for `matrix_dither_with`.
+#[derive(Clone, Debug)]
+pub struct DiffusionOptions {
+    /// Scan alternate rows right to left with the kernel mirrored, which breaks up the
+    /// diagonal "worms" a fixed scan direction leaves.
+    pub serpentine: bool,
+    /// The percentage of each pixel's error that's diffused, from 0 to 100.
+    pub strength: u8,
+    /// Clamp each pixel plus the error it gathered to the palette's range of colors, so
+    /// error can't build up where the palette can never catch up with it.
+    pub clamp: bool,
+    /// The most error, in color levels per channel, a pixel passes on to its neighbours.
+    pub error_limit: Option<u8>,
+}
+
+impl Default for DiffusionOptions {
+    fn default() -> DiffusionOptions {
+        DiffusionOptions {
+            serpentine: false,
+            strength: 100,
+            clamp: false,
+            error_limit: None,
+        }
+    }
+}
+
+/// Parses one of the built-in kernels.
+fn kernel(text: &str) -> Kernel {
+    text.parse().unwrap()
+}
+
+/// Maps every pixel to its closest color with the default `DiffusionOptions`.  For
+/// serpentine scanning or the other options, pass `Kernel::named("closest")` to
+/// `matrix_dither_with` instead.
 pub fn closest_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 0, 0),
-                           ( 0,  0,  0, 0, 0),
-                           ( 0,  0,  0, 0, 0)),
-                  divisor: 1 },
-        colors);
+    matrix_dither_with(bmp, &kernel(CLOSEST), colors, &DiffusionOptions::default());
 }
 
+/// Carries all of each pixel's error to the next with the default options; see
+/// `Kernel::named("diffuse")`.
 pub fn diffuse_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 1, 0),
-                           ( 0,  0,  0, 0, 0),
-                           ( 0,  0,  0, 0, 0)),
-                  divisor: 1 },
-        colors);
+    matrix_dither_with(bmp, &kernel(DIFFUSE), colors, &DiffusionOptions::default());
 }
 
+/// Floyd-Steinberg with the default options; see `Kernel::named("floyd")`.
 pub fn floyd_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 7, 0),
-                           ( 0,  3,  5, 1, 0),
-                           ( 0,  0,  0, 0, 0)),
-                  divisor: 16 },
-        colors);
+    matrix_dither_with(bmp, &kernel(FLOYD), colors, &DiffusionOptions::default());
 }
 
+/// False Floyd-Steinberg with the default options; see `Kernel::named("ffloyd")`.
 pub fn false_floyd_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 3, 0),
-                           ( 0,  0,  3, 2, 0),
-                           ( 0,  0,  0, 0, 0)),
-                  divisor: 8 },
-        colors);
+    matrix_dither_with(bmp, &kernel(FALSE_FLOYD), colors, &DiffusionOptions::default());
 }
 
+/// Jarvis, Judice and Ninke with the default options; see `Kernel::named("jarvis")`.
 pub fn jjn_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 7, 5),
-                           ( 3,  5,  7, 5, 3),
-                           ( 1,  3,  5, 3, 1)),
-                  divisor: 48 },
-        colors);
+    matrix_dither_with(bmp, &kernel(JJN), colors, &DiffusionOptions::default());
 }
 
+/// Stucki with the default options; see `Kernel::named("stucki")`.
 pub fn stucki_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 8, 4),
-                           ( 2,  4,  8, 4, 2),
-                           ( 1,  2,  4, 2, 1)),
-                  divisor: 42 },
-        colors);
+    matrix_dither_with(bmp, &kernel(STUCKI), colors, &DiffusionOptions::default());
 }
 
+/// Atkinson with the default options; see `Kernel::named("atkinson")`.
 pub fn atkinson_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 1, 1),
-                           ( 0,  1,  1, 1, 0),
-                           ( 0,  0,  1, 0, 0)),
-                  divisor: 8 },
-        colors);
+    matrix_dither_with(bmp, &kernel(ATKINSON), colors, &DiffusionOptions::default());
 }
 
+/// Burkes with the default options; see `Kernel::named("burkes")`.
 pub fn burkes_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 8, 4),
-                           ( 2,  4,  8, 4, 2),
-                           ( 0,  0,  0, 0, 0)),
-                  divisor: 32 },
-        colors);
+    matrix_dither_with(bmp, &kernel(BURKES), colors, &DiffusionOptions::default());
 }
 
+/// Sierra with the default options; see `Kernel::named("sierra")`.
 pub fn sierra_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 5, 3),
-                           ( 2,  4,  5, 4, 2),
-                           ( 0,  2,  3, 2, 0)),
-                  divisor: 32 },
-        colors);
+    matrix_dither_with(bmp, &kernel(SIERRA), colors, &DiffusionOptions::default());
 }
 
+/// Two row Sierra with the default options; see `Kernel::named("sierra2")`.
 pub fn sierra2_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 4, 3),
-                           ( 1,  2,  3, 2, 1),
-                           ( 0,  0,  0, 0, 0)),
-                  divisor: 16 },
-        colors);
+    matrix_dither_with(bmp, &kernel(SIERRA2), colors, &DiffusionOptions::default());
 }
 
+/// Sierra Lite with the default options; see `Kernel::named("sierra_lite")`.
 pub fn sierra_lite_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    matrix_dither(
-        bmp,
-        &Matrix { values: (((), (), (), 2, 0),
-                           ( 0,  1,  1, 0, 0),
-                           ( 0,  0,  0, 0, 0)),
-                  divisor: 4 },
-        colors);
+    matrix_dither_with(bmp, &kernel(SIERRA_LITE), colors, &DiffusionOptions::default());
+}
+
+/// Fan with the default options; see `Kernel::named("fan")`.
+pub fn fan_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
+    matrix_dither_with(bmp, &kernel(FAN), colors, &DiffusionOptions::default());
+}
+
+/// Shiau-Fan with the default options; see `Kernel::named("shiau_fan")`.
+pub fn shiau_fan_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
+    matrix_dither_with(bmp, &kernel(SHIAU_FAN), colors, &DiffusionOptions::default());
+}
+
+/// Five cell Shiau-Fan with the default options; see `Kernel::named("shiau_fan2")`.
+pub fn shiau_fan2_matrix_dither(bmp: &mut Bmp, colors: &Vec<Pixel>) {
+    matrix_dither_with(bmp, &kernel(SHIAU_FAN2), colors, &DiffusionOptions::default());
 }
 
 pub fn bayer_4x4(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    let mut matrix = Vec::with_capacity(4);
-    matrix.push(vec![1, 9, 3, 11]);
-    matrix.push(vec![13, 5, 15, 7]);
-    matrix.push(vec![4, 12, 2, 10]);
-    matrix.push(vec![16, 8, 14, 6]);
-    ordered_dither(bmp, &matrix, colors);
+    bayer_dither(bmp, 4, colors);
 }
 
 pub fn bayer_8x8(bmp: &mut Bmp, colors: &Vec<Pixel>) {
-    let mut matrix = Vec::with_capacity(8);
-    matrix.push(vec![1, 49, 13, 61, 4, 52, 16, 64]);
-    matrix.push(vec![33, 17, 45, 29, 36, 20, 48, 32]);
-    matrix.push(vec![9, 57, 5, 53, 12, 60, 8, 56]);
-    matrix.push(vec![41, 25, 37, 21, 44, 28, 40, 24]);
-    matrix.push(vec![3, 51, 15, 63, 2, 50, 14, 62]);
-    matrix.push(vec![35, 19, 47, 31, 34, 18, 46, 30]);
-    matrix.push(vec![11, 59, 7, 55, 10, 58, 6, 54]);
-    matrix.push(vec![43, 27, 39, 23, 42, 26, 38, 22]);
-    ordered_dither(bmp, &matrix, colors);
+    bayer_dither(bmp, 8, colors);
+}
+
+/// Ordered dithering with a `size` by `size` Bayer matrix.
+pub fn bayer_dither(bmp: &mut Bmp, size: usize, colors: &Vec<Pixel>) {
+    ordered_dither(bmp, &bayer_matrix(size), colors);
+}
+
+/// The Bayer threshold matrix of `size` by `size`, holding each of `0..size * size` once.
+/// The size must be a power of two from 2 to 64.
+pub fn bayer_matrix(size: usize) -> Vec<Vec<i32>> {
+    assert!(size.is_power_of_two() && (2..=64).contains(&size), "Bayer matrices are 2x2 to 64x64, not {}x{}", size, size);
+    let mut matrix = vec![vec![0]];
+    while matrix.len() < size {
+        // each quadrant is the smaller matrix interleaved at its own offset
+        let n = matrix.len();
+        let mut next = vec![vec![0; n * 2]; n * 2];
+        for x in 0..n * 2 {
+            for y in 0..n * 2 {
+                let offset = [[0, 2], [3, 1]][x / n][y / n];
+                next[x][y] = matrix[x % n][y % n] * 4 + offset;
+            }
+        }
+        matrix = next;
+    }
+    matrix
+}
+
+/// The palette's average distance from each color to its nearest neighbour, measured by
+/// the channel that differs the most.
+fn palette_spacing(colors: &[Pixel]) -> i32 {
+    if colors.len() < 2 {
+        return 0;
+    }
+    let total: i32 = colors.iter().enumerate().map(|(i, a)| {
+        colors.iter().enumerate()
+            .filter(|&(j, _)| j != i)
+            .map(|(_, b)| {
+                let d = sub(&a.as_tuple(), &b.as_tuple());
+                d.0.abs().max(d.1.abs()).max(d.2.abs())
+            })
+            .min()
+            .unwrap()
+    }).sum();
+    total / colors.len() as i32
 }
 
+/// Offsets each pixel by its threshold, normalized to -1/2..1/2 and scaled by the
+/// palette's spacing, then picks the closest color.  Averaged over the matrix, a pixel
+/// between two palette colors comes out as each in proportion to how close it is.
 fn ordered_dither(bmp: &mut Bmp, matrix: &Vec<Vec<i32>>, colors: &Vec<Pixel>) {
     let size = matrix.len();
-    let factor = size as i32 * size as i32 + 1;
+    let levels = (size * size) as i32;
+    let spacing = palette_spacing(colors);
     for y in 0..bmp.height() as usize {
         for x in 0..bmp.width() as usize {
             let p = bmp.pixels[x][y];
-            let pt = p.as_tuple();
+            if p.is_transparent() {
+                continue;
+            }
             let v = matrix[x % size][y % size];
-            let pv = mul(&div(&mul(&pt, factor), 255), v);
+            // the middle of the threshold's slot, so the offsets are centered on zero
+            let offset = scale_down(&(spacing * (2 * v + 1 - levels), 0, 0), 2 * levels).0;
+            let pv = add(&p.as_tuple(), &(offset, offset, offset));
             let new_val = closest_color(&pv, colors);
-            bmp.pixels[x][y] = new_val;
+            bmp.pixels[x][y] = Pixel { a: p.a, ..new_val };
         }
     }
+    bmp.palette = Some(colors.clone());
 }
 
-fn matrix_dither(bmp: &mut Bmp, matrix: &Matrix, colors: &Vec<Pixel>) {
-    let mut err_next_1;
-    let mut err_next_2;
-    let mut err_cur_row = vec![(0, 0, 0); bmp.width() as usize];
-    let mut err_next_row_1 = vec![(0, 0, 0); bmp.width() as usize];
-    let mut err_next_row_2 = vec![(0, 0, 0); bmp.width() as usize];
-
-    let (((), (), (), d, e),
-         (f, g, h, i, j),
-         (k, l, m, n, o)) = matrix.values;
+/// Dithers the image with the error diffusion `kernel`.
+pub fn matrix_dither_with(bmp: &mut Bmp, kernel: &Kernel, colors: &Vec<Pixel>, options: &DiffusionOptions) {
+    let width = bmp.width() as usize;
+    let offsets = kernel.offsets();
+    // the error gathered for the current row and each row the kernel reaches below it
+    // in 1/ERROR_SCALE steps of a color level, so small errors aren't rounded away
+    let mut err_rows = vec![vec![(0, 0, 0); width]; kernel.weights.len() - kernel.origin.1];
+    // the palette's gamut, scaled like the error
+    let lowest = colors.iter().fold((255, 255, 255), |m, p| (m.0.min(p.r as i32), m.1.min(p.g as i32), m.2.min(p.b as i32)));
+    let highest = colors.iter().fold((0, 0, 0), |m, p| (m.0.max(p.r as i32), m.1.max(p.g as i32), m.2.max(p.b as i32)));
+    let (lowest, highest) = (mul(&lowest, ERROR_SCALE), mul(&highest, ERROR_SCALE));
+    let limit = options.error_limit.map(|limit| limit as i32 * ERROR_SCALE);
 
     for y in 0..bmp.height() as usize {
-        // reset for each line
-        err_next_1 = (0, 0, 0);
-        err_next_2 = (0, 0, 0);
-        // 3-way swap/rotation
-        ::std::mem::swap(&mut err_cur_row, &mut err_next_row_1); // now err_cur_row = err_next_row_1 => err_cur_row is correct
-        ::std::mem::swap(&mut err_next_row_1, &mut err_next_row_2); // now err_next_row_1 = err_next_row_2 => err_next_row_1 is correct
-        for pixel in err_next_row_2.iter_mut() {
-            *pixel = (0, 0, 0);
-        } // now err_next_row_2 is empty and is correct
-
-        for x in 0..bmp.width() as usize {
-            let pixel = bmp.pixels[x][y].as_tuple();
-            let adjusted = (
-                pixel.0 + err_next_1.0 + err_cur_row[x].0,
-                pixel.1 + err_next_1.1 + err_cur_row[x].1,
-                pixel.2 + err_next_1.2 + err_cur_row[x].2,
-            );
-
-            let new_val = closest_color(&adjusted, colors);
-            let pixel_error = sub(&adjusted, &new_val.as_tuple());
-
-            let individual_error = (pixel_error.0 / matrix.divisor, pixel_error.1 / matrix.divisor, pixel_error.2 / matrix.divisor);
-            err_next_1 = add(&mul(&individual_error, d), &err_next_2);
-            err_next_2 = mul(&individual_error, e);
-
-            if x > 1 {
-                // able to write pixels 2 to the left of the current; e.g., `f` and `k`
-                err_next_row_1[x - 2] = add(&err_next_row_1[x - 2], &mul(&individual_error, f));
-                err_next_row_2[x - 2] = add(&err_next_row_2[x - 2], &mul(&individual_error, k));
+        // serpentine scans run right to left on odd rows, mirroring the kernel
+        let reverse = options.serpentine && y % 2 == 1;
+        for step in 0..width {
+            let x = if reverse { width - 1 - step } else { step };
+            let original = bmp.pixels[x][y];
+            if original.is_transparent() {
+                // leave the pixel alone and don't let it absorb or spread any error
+                continue;
+            }
+            let mut adjusted = add(&mul(&original.as_tuple(), ERROR_SCALE), &err_rows[0][x]);
+            if options.clamp {
+                adjusted = clamp(&adjusted, &lowest, &highest);
             }
-            if x > 0 {
-                // able to write pixels 1 to the left of the current; e.g., `g` and `l`
-                err_next_row_1[x - 1] = add(&err_next_row_1[x - 1], &mul(&individual_error, g));
-                err_next_row_2[x - 1] = add(&err_next_row_2[x - 1], &mul(&individual_error, l));
+
+            let new_val = closest_color(&scale_down(&adjusted, ERROR_SCALE), colors);
+            let mut pixel_error = sub(&adjusted, &mul(&new_val.as_tuple(), ERROR_SCALE));
+            if options.strength < 100 {
+                pixel_error = scale_down(&mul(&pixel_error, options.strength as i32), 100);
             }
-            // set pixels in same column; e.g., `h` and `m`
-            err_next_row_1[x] = add(&err_next_row_1[x], &mul(&individual_error, h));
-            err_next_row_2[x] = add(&err_next_row_2[x], &mul(&individual_error, m));
-            if (x as u32) < (bmp.width() - 1) {
-                // able to write to pixels 1 to the right of the current; e.g., `i` and `n`
-                err_next_row_1[x + 1] = add(&err_next_row_1[x + 1], &mul(&individual_error, i));
-                err_next_row_2[x + 1] = add(&err_next_row_2[x + 1], &mul(&individual_error, n));
+            if let Some(limit) = limit {
+                pixel_error = clamp(&pixel_error, &(-limit, -limit, -limit), &(limit, limit, limit));
             }
-            if (x as u32) < (bmp.width() - 2) {
-                // able to write to pixels 2 right of the current; e.g., `j` and `o`
-                err_next_row_1[x + 2] = add(&err_next_row_1[x + 2], &mul(&individual_error, j));
-                err_next_row_2[x + 2] = add(&err_next_row_2[x + 2], &mul(&individual_error, o));
+
+            // each share is the step between rounded running totals, so the shares add up
+            // to exactly the error the weights pass on; the totals are as wide as the
+            // error times the kernel's weights, so they're kept in i64
+            let divisor = kernel.divisor as i64;
+            let round = |n: i64| (2 * n + divisor).div_euclid(2 * divisor);
+            let mut total = 0i64;
+            let mut spread = (0i64, 0i64, 0i64);
+            for &(dx, dy, weight) in &offsets {
+                total += weight as i64;
+                let next = (round(pixel_error.0 as i64 * total), round(pixel_error.1 as i64 * total), round(pixel_error.2 as i64 * total));
+                let share = (next.0 - spread.0, next.1 - spread.1, next.2 - spread.2);
+                spread = next;
+                let target = if reverse { x as isize - dx } else { x as isize + dx };
+                if target < 0 || target >= width as isize {
+                    // the kernel hangs off the edge of the image
+                    continue;
+                }
+                let target = target as usize;
+                let carry = |c: i32, s: i64| (c as i64 + s).max(-MAX_CARRY as i64).min(MAX_CARRY as i64) as i32;
+                let current = err_rows[dy][target];
+                err_rows[dy][target] = (carry(current.0, share.0), carry(current.1, share.1), carry(current.2, share.2));
             }
 
-            bmp.pixels[x][y] = new_val;
+            bmp.pixels[x][y] = Pixel { a: original.a, ..new_val };
+        }
+        // move on to the next row's error, reusing this row's for the furthest one
+        err_rows.rotate_left(1);
+        let last = err_rows.len() - 1;
+        for pixel in err_rows[last].iter_mut() {
+            *pixel = (0, 0, 0);
         }
     }
+    bmp.palette = Some(colors.clone());
+}
+
+/// Dithers the frames of a sequence in order, keeping the dither pattern of still regions
+/// from one frame to the next.  Ordered dithers are already stable; error diffusion isn't,
+/// since a change anywhere shifts the error carried to every later pixel and the pattern
+/// crawls across the frame.
+pub struct Sequence {
+    /// How far, in any channel, a pixel may drift from the color it was last dithered from
+    /// before it's dithered afresh.  `None` dithers every frame independently.
+    pub tolerance: Option<u8>,
+    /// The source colors the current output was dithered from, and that output.
+    previous: Option<(Bmp, Bmp)>,
+}
+
+impl Sequence {
+    pub fn new(tolerance: Option<u8>) -> Sequence {
+        Sequence {
+            tolerance: tolerance,
+            previous: None,
+        }
+    }
+
+    /// Dithers the next frame with `delegate`, then puts back the previous output for every
+    /// pixel still within the tolerance of the color it was last dithered from.
+    pub fn dither<F: Fn(&mut Bmp, &Vec<Pixel>)>(&mut self, frame: &mut Bmp, colors: &Vec<Pixel>, delegate: F) {
+        let source = frame.clone();
+        delegate(frame, colors);
+        let tolerance = match self.tolerance {
+            Some(tolerance) => tolerance as i32,
+            None => return,
+        };
+        if let Some((ref mut reference, ref mut output)) = self.previous {
+            if reference.width() == frame.width() && reference.height() == frame.height() {
+                for x in 0..frame.width() as usize {
+                    for y in 0..frame.height() as usize {
+                        let (then, now) = (reference.pixels[x][y], source.pixels[x][y]);
+                        let still = (then.r as i32 - now.r as i32).abs() <= tolerance
+                            && (then.g as i32 - now.g as i32).abs() <= tolerance
+                            && (then.b as i32 - now.b as i32).abs() <= tolerance
+                            && then.a == now.a;
+                        if still {
+                            frame.pixels[x][y] = output.pixels[x][y];
+                        } else {
+                            reference.pixels[x][y] = now;
+                        }
+                    }
+                }
+                *output = frame.clone();
+                return;
+            }
+        }
+        self.previous = Some((source, frame.clone()));
+    }
 }
 
 fn closest_color(p: &(i32, i32, i32), colors: &Vec<Pixel>) -> Pixel {
@@ -253,6 +572,200 @@ fn mul(t: &(i32, i32, i32), v: i32) -> (i32, i32, i32) {
     (t.0 * v, t.1 * v, t.2 * v)
 }
 
-fn div(t: &(i32, i32, i32), v: i32) -> (i32, i32, i32) {
-    (t.0 / v, t.1 / v, t.2 / v)
+fn clamp(t: &(i32, i32, i32), low: &(i32, i32, i32), high: &(i32, i32, i32)) -> (i32, i32, i32) {
+    (t.0.max(low.0).min(high.0), t.1.max(low.1).min(high.1), t.2.max(low.2).min(high.2))
+}
+
+/// Divides by `v`, rounding to the nearest integer with halves rounded up.
+fn scale_down(t: &(i32, i32, i32), v: i32) -> (i32, i32, i32) {
+    let round = |n: i32| (2 * n + v).div_euclid(2 * v);
+    (round(t.0), round(t.1), round(t.2))
+}
+
+#[test]
+fn sequence_keeps_still_regions() {
+    let mut frame = Bmp::new(8, 8);
+    for x in 0..8 {
+        for y in 0..8 {
+            let v = (x * 8 + y) as u8 * 4;
+            frame.pixels[x][y] = Pixel {r: v, g: v, b: v, a: 255};
+        }
+    }
+    let colors = vec![Pixel::black(), Pixel::white()];
+    let mut sequence = Sequence::new(Some(0));
+    let mut first = frame.clone();
+    sequence.dither(&mut first, &colors, floyd_matrix_dither);
+
+    // only the changed pixel is dithered afresh
+    frame.pixels[0][0] = Pixel::white();
+    let mut second = frame.clone();
+    sequence.dither(&mut second, &colors, floyd_matrix_dither);
+    assert_eq!(Pixel::white(), second.pixels[0][0]);
+    for x in 0..8 {
+        for y in 0..8 {
+            if (x, y) != (0, 0) {
+                assert_eq!(first.pixels[x][y], second.pixels[x][y]);
+            }
+        }
+    }
+}
+
+#[test]
+fn serpentine_mirrors_odd_rows() {
+    let colors = vec![Pixel::black(), Pixel::white()];
+    let gray = |v: usize| Pixel {r: (v * 9) as u8, g: (v * 9) as u8, b: (v * 9) as u8, a: 255};
+    let mut bmp = Bmp::new(24, 2);
+    for x in 0..24 {
+        bmp.pixels[x][0] = gray(x);
+        bmp.pixels[x][1] = gray(x);
+    }
+    let mut reversed = Bmp::new(24, 1);
+    for x in 0..24 {
+        reversed.pixels[x][0] = gray(23 - x);
+    }
+    // with only the carry along the row, the second row is the first dithered backwards
+    matrix_dither_with(&mut bmp, &kernel(DIFFUSE), &colors, &DiffusionOptions { serpentine: true, ..DiffusionOptions::default() });
+    diffuse_matrix_dither(&mut reversed, &colors);
+    for x in 0..24 {
+        assert_eq!(reversed.pixels[23 - x][0], bmp.pixels[x][1]);
+    }
+    assert!((0..24).any(|x| bmp.pixels[x][0] != bmp.pixels[x][1]));
+}
+
+#[test]
+fn parse_kernels() {
+    let floyd: Kernel = FLOYD.parse().unwrap();
+    assert_eq!(Kernel { weights: vec![vec![0, 0, 7], vec![3, 5, 1]], origin: (1, 0), divisor: 16 }, floyd);
+    assert_eq!(FLOYD, floyd.to_string());
+    assert_eq!(vec![(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)], floyd.offsets());
+    // the divisor defaults to the sum of the weights
+    assert_eq!(Ok(16), "- * 7; 3 5 1".parse::<Kernel>().map(|k| k.divisor));
+
+    assert_eq!(Err(KernelError::Origin), "- 7; 3 5 1".parse::<Kernel>());
+    assert_eq!(Err(KernelError::Behind), "1 * 7; 3 5 1".parse::<Kernel>());
+    assert_eq!(Err(KernelError::Ragged), "- * 7; 3 5".parse::<Kernel>());
+    assert_eq!(Err(KernelError::BadNumber("x".to_string())), "* x".parse::<Kernel>());
+    assert_eq!(Err(KernelError::Divisor), "* 1 / 0".parse::<Kernel>());
+    assert_eq!(Err(KernelError::TooLarge), "* 2000000000 2000000000".parse::<Kernel>());
+
+    // the constructor checks what parsing does
+    assert_eq!(Err(KernelError::Origin), Kernel::new(vec![vec![0, 7]], (0, 1), 7));
+    assert_eq!(Err(KernelError::Origin), Kernel::new(vec![], (0, 0), 1));
+    assert_eq!(Err(KernelError::Behind), Kernel::new(vec![vec![1, 7]], (0, 0), 8));
+    assert_eq!(Err(KernelError::Ragged), Kernel::new(vec![vec![0, 7], vec![1]], (0, 0), 8));
+    assert_eq!(Err(KernelError::Divisor), Kernel::new(vec![vec![0, 7]], (0, 0), 0));
+    assert_eq!(Ok(floyd), Kernel::new(vec![vec![0, 0, 7], vec![3, 5, 1]], (1, 0), 16));
+}
+
+#[test]
+fn named_kernels() {
+    assert_eq!(Some(kernel(JJN)), Kernel::named("jarvis"));
+    assert_eq!(None, Kernel::named("bayer4"));
+}
+
+#[test]
+fn large_weights_match_their_ratio() {
+    let gray = Pixel {r: 100, g: 100, b: 100, a: 255};
+    let colors = vec![Pixel::black(), Pixel::white()];
+    let dither = |text: &str| {
+        let mut bmp = Bmp::new(4, 4);
+        for column in bmp.pixels.iter_mut() {
+            for pixel in column.iter_mut() {
+                *pixel = gray;
+            }
+        }
+        matrix_dither_with(&mut bmp, &kernel(text), &colors, &DiffusionOptions::default());
+        bmp.pixels
+    };
+    // Floyd-Steinberg scaled by 10000
+    assert_eq!(dither(FLOYD), dither("- * 70000; 30000 50000 10000 / 160000"));
+}
+
+#[test]
+fn gradients_keep_their_brightness() {
+    let colors = vec![Pixel::black(), Pixel::white()];
+    for &text in &[FLOYD, JJN, STUCKI, SIERRA] {
+        let mut bmp = Bmp::new(256, 64);
+        for x in 0..256 {
+            for y in 0..64 {
+                bmp.pixels[x][y] = Pixel {r: x as u8, g: x as u8, b: x as u8, a: 255};
+            }
+        }
+        matrix_dither_with(&mut bmp, &kernel(text), &colors, &DiffusionOptions::default());
+        // each quarter of the ramp, where truncated error used to darken the shadows
+        for quarter in 0..4 {
+            let columns = quarter * 64..(quarter + 1) * 64;
+            let expected = columns.clone().sum::<usize>() as f64 / 64.0;
+            let total: usize = columns.map(|x| bmp.pixels[x].iter().map(|p| p.r as usize).sum::<usize>()).sum();
+            let mean = total as f64 / (64.0 * 64.0);
+            assert!((mean - expected).abs() < 2.0, "{} quarter {} mean {} not {}", text, quarter, mean, expected);
+        }
+    }
+}
+
+#[test]
+fn diffusion_options_bound_the_error() {
+    let gray = Pixel {r: 128, g: 128, b: 128, a: 255};
+    let colors = vec![Pixel::black(), gray];
+    // white is out of the palette's gamut, so it always leaves error behind
+    let mut bmp = Bmp::new(16, 1);
+    for x in 0..8 {
+        bmp.pixels[x][0] = Pixel::white();
+    }
+    let mut bled = bmp.clone();
+    matrix_dither_with(&mut bled, &kernel(DIFFUSE), &colors, &DiffusionOptions::default());
+    assert_eq!(gray, bled.pixels[12][0]);
+    let mut clamped = bmp.clone();
+    matrix_dither_with(&mut clamped, &kernel(DIFFUSE), &colors, &DiffusionOptions { clamp: true, ..DiffusionOptions::default() });
+    assert!((8..16).all(|x| clamped.pixels[x][0] == Pixel::black()));
+
+    // with no error passed on, every kernel picks the closest colors
+    let mut closest = Bmp::new(16, 16);
+    for x in 0..16 {
+        for y in 0..16 {
+            closest.pixels[x][y] = Pixel {r: (x * 16) as u8, g: (y * 16) as u8, b: 100, a: 255};
+        }
+    }
+    let colors = vec![Pixel::black(), Pixel::white(), Pixel::red()];
+    let mut weak = closest.clone();
+    let mut capped = closest.clone();
+    closest_matrix_dither(&mut closest, &colors);
+    matrix_dither_with(&mut weak, &kernel(FLOYD), &colors, &DiffusionOptions { strength: 0, ..DiffusionOptions::default() });
+    matrix_dither_with(&mut capped, &kernel(FLOYD), &colors, &DiffusionOptions { error_limit: Some(0), ..DiffusionOptions::default() });
+    assert_eq!(closest.pixels, weak.pixels);
+    assert_eq!(closest.pixels, capped.pixels);
+}
+
+#[test]
+fn bayer_thresholds() {
+    assert_eq!(vec![vec![0, 2], vec![3, 1]], bayer_matrix(2));
+    assert_eq!(vec![0, 8, 2, 10], bayer_matrix(4)[0]);
+    assert_eq!(vec![15, 7, 13, 5], bayer_matrix(4)[3]);
+    let mut values: Vec<i32> = bayer_matrix(64).into_iter().flatten().collect();
+    values.sort();
+    assert_eq!((0..4096).collect::<Vec<i32>>(), values);
+
+    // a gray between two palette colors is a mix of just those two, in proportion
+    let colors = vec![Pixel::black(), Pixel::parse("85,85,85"), Pixel::parse("170,170,170"), Pixel::white()];
+    assert_eq!(85, palette_spacing(&colors));
+    let mut bmp = Bmp::new(8, 8);
+    for x in 0..8 {
+        for y in 0..8 {
+            bmp.pixels[x][y] = Pixel {r: 106, g: 106, b: 106, a: 255};
+        }
+    }
+    bayer_8x8(&mut bmp, &colors);
+    let lighter = (0..8).flat_map(|x| (0..8).map(move |y| (x, y))).filter(|&(x, y)| bmp.pixels[x][y] == colors[2]).count();
+    let darker = (0..8).flat_map(|x| (0..8).map(move |y| (x, y))).filter(|&(x, y)| bmp.pixels[x][y] == colors[1]).count();
+    assert_eq!(64, lighter + darker);
+    assert_eq!(16, lighter);
+}
+
+#[test]
+fn out_of_gamut_error_is_bounded() {
+    // every pixel leaves a full level's worth of error that nothing can take up, which
+    // used to overflow the carry partway along a wide row
+    let mut bmp = Bmp::new(40000, 1);
+    matrix_dither_with(&mut bmp, &kernel(DIFFUSE), &vec![Pixel::white()], &DiffusionOptions::default());
+    assert!(bmp.pixels.iter().all(|column| column[0] == Pixel::white()));
 }
@@ -0,0 +1,193 @@
+use std::io::{Result, Write};
+
+use bmp::{Bmp, Pixel};
+use source;
+use source::Language;
+
+/// The e-paper panel types, each with its fixed palette and native buffer layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Panel {
+    /// Black and white at 1 bit per pixel, set bits white.
+    Bw,
+    /// Black, white and red as two 1 bit planes: a black and white plane like `Bw`, where
+    /// red reads as white, and a red plane with set bits for red.
+    Bwr,
+    /// Four grays at 2 bits per pixel, from white at 0 to black at 3 as Waveshare's 4-gray
+    /// buffers have them.
+    Gray4,
+    /// The seven ACeP colors at 4 bits per pixel, in the controller's index order.
+    Acep7,
+}
+
+impl Panel {
+    /// Looks up a panel by its name: `bw`, `bwr`, `gray4` or `acep`.
+    pub fn from_name(name: &str) -> Option<Panel> {
+        match name {
+            "bw" => Some(Panel::Bw),
+            "bwr" => Some(Panel::Bwr),
+            "gray4" => Some(Panel::Gray4),
+            "acep" => Some(Panel::Acep7),
+            _ => None,
+        }
+    }
+
+    /// The colors the panel shows, in index order.
+    pub fn palette(&self) -> Vec<Pixel> {
+        let rgb = |r, g, b| Pixel {r: r, g: g, b: b, a: 255};
+        match *self {
+            Panel::Bw => vec![Pixel::black(), Pixel::white()],
+            Panel::Bwr => vec![Pixel::black(), Pixel::white(), Pixel::red()],
+            Panel::Gray4 => vec![rgb(0xff, 0xff, 0xff), rgb(0xaa, 0xaa, 0xaa), rgb(0x55, 0x55, 0x55), rgb(0, 0, 0)],
+            Panel::Acep7 => vec![
+                Pixel::black(),
+                Pixel::white(),
+                Pixel::green(),
+                Pixel::blue(),
+                Pixel::red(),
+                Pixel::yellow(),
+                rgb(0xff, 0x80, 0),
+            ],
+        }
+    }
+
+    /// Bits per pixel in each plane.
+    pub fn bits_per_pixel(&self) -> usize {
+        match *self {
+            Panel::Bw | Panel::Bwr => 1,
+            Panel::Gray4 => 2,
+            Panel::Acep7 => 4,
+        }
+    }
+}
+
+/// Where the first pixel of a byte goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOrder {
+    /// The first pixel takes the most significant bits.
+    Msb,
+    /// The first pixel takes the least significant bits.
+    Lsb,
+}
+
+/// Which plane comes first for panels with more than one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlaneOrder {
+    BlackFirst,
+    ColorFirst,
+}
+
+#[derive(Clone, Debug)]
+pub struct EpaperOptions {
+    pub bit_order: BitOrder,
+    pub plane_order: PlaneOrder,
+    /// Flip every value for controllers with the opposite polarity: set bits for black,
+    /// clear bits for red and 0 for black in the 4-gray buffer.  ACeP indices are unchanged.
+    pub invert: bool,
+}
+
+impl Default for EpaperOptions {
+    fn default() -> EpaperOptions {
+        EpaperOptions {
+            bit_order: BitOrder::Msb,
+            plane_order: PlaneOrder::BlackFirst,
+            invert: false,
+        }
+    }
+}
+
+/// The palette index of the panel color nearest to `p`.  Dithered pixels match exactly;
+/// transparent ones are left white, the panel's blank state.
+fn panel_index(p: Pixel, palette: &[Pixel]) -> usize {
+    let p = if p.is_transparent() { Pixel::white() } else { p };
+    let distance = |c: &Pixel| {
+        let (dr, dg, db) = (c.r as i32 - p.r as i32, c.g as i32 - p.g as i32, c.b as i32 - p.b as i32);
+        dr * dr + dg * dg + db * db
+    };
+    (0..palette.len()).min_by_key(|&i| distance(&palette[i])).unwrap_or(0)
+}
+
+/// Packs a plane of `bits` wide values row by row, each row padded to a whole byte.
+fn pack_plane<F: Fn(usize, usize) -> u8>(width: usize, height: usize, bits: usize, order: BitOrder, value: F) -> Vec<u8> {
+    let per_byte = 8 / bits;
+    let row_size = (width + per_byte - 1) / per_byte;
+    let mut data = vec![0u8; row_size * height];
+    for y in 0..height {
+        for x in 0..width {
+            let slot = x % per_byte;
+            let shift = match order {
+                BitOrder::Msb => 8 - bits * (slot + 1),
+                BitOrder::Lsb => bits * slot,
+            };
+            data[y * row_size + x / per_byte] |= value(x, y) << shift;
+        }
+    }
+    data
+}
+
+/// Maps every pixel of a dithered image back to its index in the panel's palette and packs
+/// the result into the panel's native buffer, planes one after the other.
+pub fn pack(bmp: &Bmp, panel: Panel, options: &EpaperOptions) -> Vec<u8> {
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let palette = panel.palette();
+    let mut indices = vec![0u8; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            indices[y * width + x] = panel_index(bmp.pixels[x][y], &palette) as u8;
+        }
+    }
+    let index = |x: usize, y: usize| indices[y * width + x];
+    let bits = panel.bits_per_pixel();
+    let flip = |value: u8| if options.invert { value ^ ((1 << bits) - 1) } else { value };
+    match panel {
+        Panel::Bwr => {
+            let black = pack_plane(width, height, bits, options.bit_order, |x, y| flip((index(x, y) != 0) as u8));
+            let red = pack_plane(width, height, bits, options.bit_order, |x, y| flip((index(x, y) == 2) as u8));
+            match options.plane_order {
+                PlaneOrder::BlackFirst => [black, red].concat(),
+                PlaneOrder::ColorFirst => [red, black].concat(),
+            }
+        },
+        Panel::Acep7 => pack_plane(width, height, bits, options.bit_order, index),
+        _ => pack_plane(width, height, bits, options.bit_order, |x, y| flip(index(x, y))),
+    }
+}
+
+/// Writes the packed panel buffer as raw bytes, ready to send to the controller.
+pub fn write_binary<W: Write>(bmp: &Bmp, writer: &mut W, panel: Panel, options: &EpaperOptions) -> Result<()> {
+    writer.write_all(&pack(bmp, panel, options))
+}
+
+/// Writes the packed panel buffer as a C array named `name`, with its size as defines.
+pub fn write_c_array<W: Write>(bmp: &Bmp, writer: &mut W, panel: Panel, options: &EpaperOptions, name: &str) -> Result<()> {
+    writer.write_all(b"#include <stdint.h>\n\n")?;
+    source::write_constant(writer, Language::C, &format!("{}_width", name), bmp.width() as usize)?;
+    source::write_constant(writer, Language::C, &format!("{}_height", name), bmp.height() as usize)?;
+    writer.write_all(b"\n")?;
+    source::write_array(writer, Language::C, name, &pack(bmp, panel, options))
+}
+
+#[test]
+fn pack_planes_and_nibbles() {
+    let mut bmp = Bmp::new(3, 1);
+    bmp.pixels[0][0] = Pixel::white();
+    bmp.pixels[2][0] = Pixel::red();
+    let options = EpaperOptions::default();
+    // red reads as white on the black plane
+    assert_eq!(vec![0b1010_0000, 0b0010_0000], pack(&bmp, Panel::Bwr, &options));
+    let options = EpaperOptions { bit_order: BitOrder::Lsb, plane_order: PlaneOrder::ColorFirst, invert: false };
+    assert_eq!(vec![0b0000_0100, 0b0000_0101], pack(&bmp, Panel::Bwr, &options));
+
+    assert_eq!(vec![0x10, 0x40], pack(&bmp, Panel::Acep7, &EpaperOptions::default()));
+    assert_eq!(vec![0x01, 0x04], pack(&bmp, Panel::Acep7, &options));
+
+    // the opposite polarity flips both planes, but not the padding
+    let inverted = EpaperOptions { invert: true, ..EpaperOptions::default() };
+    assert_eq!(vec![0b0100_0000, 0b1100_0000], pack(&bmp, Panel::Bwr, &inverted));
+
+    // Waveshare's 4-gray order, from white at 0 to black at 3
+    bmp.pixels[1][0] = Pixel {r: 0x55, g: 0x55, b: 0x55, a: 255};
+    bmp.pixels[2][0] = Pixel::black();
+    assert_eq!(vec![0b0010_1100], pack(&bmp, Panel::Gray4, &EpaperOptions::default()));
+    assert_eq!(vec![0b1101_0000], pack(&bmp, Panel::Gray4, &inverted));
+}
@@ -0,0 +1,138 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use bmp::Bmp;
+use gif;
+use jpeg;
+use pcx;
+use png;
+use pnm;
+use qoi;
+use tga;
+
+pub type FormatResult<T> = Result<T, Box<dyn Error>>;
+
+/// The image codecs the loader can dispatch to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Bmp,
+    Gif,
+    Jpeg,
+    Pcx,
+    Png,
+    Pnm,
+    Qoi,
+    Tga,
+}
+
+impl Format {
+    pub fn from_extension(path_str: &str) -> Option<Format> {
+        let extension = Path::new(path_str).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "bmp" | "dib" => Some(Format::Bmp),
+            "gif" => Some(Format::Gif),
+            "jpg" | "jpeg" | "jpe" | "jfif" => Some(Format::Jpeg),
+            "pcx" => Some(Format::Pcx),
+            "png" => Some(Format::Png),
+            "pbm" | "pgm" | "ppm" | "pnm" | "pam" => Some(Format::Pnm),
+            "qoi" => Some(Format::Qoi),
+            "tga" | "icb" | "vda" | "vst" => Some(Format::Tga),
+            _ => None,
+        }
+    }
+    /// Identifies a format from the first bytes of a file.
+    pub fn from_magic(data: &[u8]) -> Option<Format> {
+        match data {
+            [b'B', b'M', ..] => Some(Format::Bmp),
+            [b'G', b'I', b'F', b'8', ..] => Some(Format::Gif),
+            [0xff, 0xd8, 0xff, ..] => Some(Format::Jpeg),
+            [0x89, b'P', b'N', b'G', ..] => Some(Format::Png),
+            [b'P', b'1'..=b'7', ..] => Some(Format::Pnm),
+            [b'q', b'o', b'i', b'f', ..] => Some(Format::Qoi),
+            [0x0a, 0..=5, 0..=1, ..] => Some(Format::Pcx),
+            // TGA has no magic number; it's only recognized by extension
+            _ => None,
+        }
+    }
+    /// Whether images can be saved in the format.
+    pub fn is_writable(self) -> bool {
+        self != Format::Jpeg
+    }
+}
+
+/// Identifies the format from the magic bytes at the start of `reader`, leaving the reader
+/// where it was.
+pub fn sniff<R: Read + Seek>(reader: &mut R) -> FormatResult<Option<Format>> {
+    let start = reader.seek(SeekFrom::Current(0))?;
+    let mut magic = Vec::new();
+    reader.take(8).read_to_end(&mut magic)?;
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(Format::from_magic(&magic))
+}
+
+/// Reads an image, identifying its format from its magic bytes.
+pub fn read_from<R: Read + Seek>(reader: &mut R) -> FormatResult<Bmp> {
+    match sniff(reader)? {
+        Some(format) => read_as(reader, format),
+        None => Err(From::from("unrecognized image format")),
+    }
+}
+
+pub fn read_as<R: Read + Seek>(reader: &mut R, format: Format) -> FormatResult<Bmp> {
+    Ok(match format {
+        Format::Bmp => Bmp::read_from(reader)?,
+        Format::Gif => gif::read_from(reader)?,
+        Format::Jpeg => jpeg::read_from(reader)?,
+        Format::Pcx => pcx::read_from(reader)?,
+        Format::Png => png::read_from(reader)?,
+        Format::Pnm => pnm::read_from(reader)?,
+        Format::Qoi => qoi::read_from(reader)?,
+        Format::Tga => tga::read_from(reader)?,
+    })
+}
+
+/// Loads an image, preferring the format its contents identify and falling back to its
+/// extension.
+pub fn load(path_str: &str) -> FormatResult<Bmp> {
+    let mut file = BufReader::new(File::open(Path::new(path_str))?);
+    match sniff(&mut file)?.or_else(|| Format::from_extension(path_str)) {
+        Some(format) => read_as(&mut file, format),
+        None => Err(From::from(format!("unrecognized image format for '{}'", path_str))),
+    }
+}
+
+/// Lossy formats would undo the dithering, so they're only supported as input.
+fn unwritable(format: Format) -> Box<dyn Error> {
+    From::from(format!("{:?} output isn't supported", format))
+}
+
+pub fn write_to<W: Write>(bmp: &Bmp, writer: &mut W, format: Format) -> FormatResult<()> {
+    match format {
+        Format::Bmp => bmp.write_to(writer)?,
+        Format::Gif => gif::write_to(bmp, writer)?,
+        Format::Jpeg => return Err(unwritable(format)),
+        Format::Pcx => pcx::write_to(bmp, writer)?,
+        Format::Png => png::write_to(bmp, writer)?,
+        Format::Pnm => pnm::write_to(bmp, writer)?,
+        Format::Qoi => qoi::write_to(bmp, writer)?,
+        Format::Tga => tga::write_to(bmp, writer)?,
+    }
+    Ok(())
+}
+
+/// Saves an image in the format implied by its extension, defaulting to BMP.
+pub fn save(bmp: &Bmp, path_str: &str) -> FormatResult<()> {
+    match Format::from_extension(path_str).unwrap_or(Format::Bmp) {
+        Format::Bmp => bmp.save(path_str)?,
+        Format::Gif => gif::save(bmp, path_str)?,
+        Format::Jpeg => return Err(unwritable(Format::Jpeg)),
+        Format::Pcx => pcx::save(bmp, path_str)?,
+        Format::Png => png::save(bmp, path_str)?,
+        Format::Pnm => pnm::save(bmp, path_str)?,
+        Format::Qoi => qoi::save(bmp, path_str)?,
+        Format::Tga => tga::save(bmp, path_str)?,
+    }
+    Ok(())
+}
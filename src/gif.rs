@@ -0,0 +1,860 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+extern crate byteorder;
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp::{Bmp, Indexed, LoadOptions, Pixel};
+
+const EXTENSION: u8 = 0x21;
+const IMAGE_DESCRIPTOR: u8 = 0x2c;
+const TRAILER: u8 = 0x3b;
+
+const GRAPHIC_CONTROL: u8 = 0xf9;
+const APPLICATION: u8 = 0xff;
+
+/// The largest code LZW streams may use.
+const MAX_CODES: usize = 4096;
+
+/// The interlaced row passes as (y start, y step).
+const INTERLACE: [(usize, usize); 4] = [(0, 8), (4, 8), (2, 4), (1, 2)];
+
+//-------------------------------------------------------------------- GifError
+
+#[derive(Debug)]
+pub enum GifError {
+    /// The file didn't start with `GIF87a` or `GIF89a`.
+    BadSignature,
+    /// A block held invalid values or the LZW data was corrupt.
+    Malformed(String),
+    /// An image or frame has more colors than a 256 entry color table can hold.
+    TooManyColors,
+    /// The image dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The file ended before the image data did.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for GifError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            GifError::BadSignature => write!(formatter, "missing GIF signature"),
+            GifError::Malformed(ref message) => write!(formatter, "malformed GIF: {}", message),
+            GifError::TooManyColors => write!(formatter, "image has more than 256 colors; dither it to a palette first"),
+            GifError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            GifError::Truncated => write!(formatter, "unexpected end of file"),
+            GifError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for GifError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            GifError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for GifError {
+    fn from(err: ::std::io::Error) -> GifError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => GifError::Truncated,
+            _ => GifError::Io(err),
+        }
+    }
+}
+
+pub type GifResult<T> = Result<T, GifError>;
+
+//------------------------------------------------------------------- Animation
+
+/// What happens to a frame's area once its delay has passed and the next frame is drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Disposal {
+    /// No disposal was specified; decoders treat this like `Keep`.
+    Unspecified,
+    /// Leave the frame in place.
+    Keep,
+    /// Clear the frame's area to the background, which is transparent in practice.
+    Background,
+    /// Restore the frame's area to what it was before the frame was drawn.
+    Previous,
+}
+
+impl Disposal {
+    fn from_bits(bits: u8) -> Disposal {
+        match bits {
+            1 => Disposal::Keep,
+            2 => Disposal::Background,
+            3 => Disposal::Previous,
+            _ => Disposal::Unspecified,
+        }
+    }
+    fn bits(&self) -> u8 {
+        match *self {
+            Disposal::Unspecified => 0,
+            Disposal::Keep => 1,
+            Disposal::Background => 2,
+            Disposal::Previous => 3,
+        }
+    }
+}
+
+/// One image of an animation, placed at an offset on the logical screen.
+pub struct Frame {
+    /// The frame's pixels.  Its palette, when set, seeds the frame's color table and fully
+    /// transparent pixels are written with the transparent index.
+    pub image: Bmp,
+    pub left: u16,
+    pub top: u16,
+    /// How long the frame is shown, in hundredths of a second.
+    pub delay: u16,
+    pub disposal: Disposal,
+    /// Write a local color table for this frame even when its colors are all in the global
+    /// table.  Set on decoded frames that had one.
+    pub local_palette: bool,
+}
+
+impl Frame {
+    /// Creates a frame at the screen's top left with no delay.
+    pub fn new(image: Bmp) -> Frame {
+        Frame {
+            image: image,
+            left: 0,
+            top: 0,
+            delay: 0,
+            disposal: Disposal::Unspecified,
+            local_palette: false,
+        }
+    }
+}
+
+/// A sequence of frames sharing a logical screen and, optionally, a global color table.
+pub struct Animation {
+    pub width: u16,
+    pub height: u16,
+    /// The global color table.  When unset the first frame's palette is used.
+    pub palette: Option<Vec<Pixel>>,
+    /// How many times to repeat the animation, with 0 looping forever.  `None` omits the
+    /// `NETSCAPE2.0` extension so the frames play once.
+    pub loop_count: Option<u16>,
+    pub frames: Vec<Frame>,
+}
+
+impl Animation {
+    pub fn new(width: u16, height: u16) -> Animation {
+        Animation {
+            width: width,
+            height: height,
+            palette: None,
+            loop_count: Some(0),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Plays the animation, returning the logical screen as it looks while each frame is
+    /// shown.  The screen starts transparent, transparent frame pixels let what's beneath
+    /// show through, and each frame's disposal is applied before the next is drawn.
+    pub fn composite(&self) -> Vec<Bmp> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mut screen = Bmp::new(width as u32, height as u32);
+        for column in screen.pixels.iter_mut() {
+            for p in column.iter_mut() {
+                p.a = 0;
+            }
+        }
+        let mut screens = Vec::with_capacity(self.frames.len());
+        for frame in &self.frames {
+            let saved = if frame.disposal == Disposal::Previous { Some(screen.clone()) } else { None };
+            let (left, top) = (frame.left as usize, frame.top as usize);
+            let right = (left + frame.image.width() as usize).min(width);
+            let bottom = (top + frame.image.height() as usize).min(height);
+            for x in left..right {
+                for y in top..bottom {
+                    let p = frame.image.pixels[x - left][y - top];
+                    if !p.is_transparent() {
+                        screen.pixels[x][y] = p;
+                    }
+                }
+            }
+            screens.push(screen.clone());
+            match frame.disposal {
+                Disposal::Background => {
+                    for x in left..right {
+                        for y in top..bottom {
+                            screen.pixels[x][y] = Pixel {r: 0, g: 0, b: 0, a: 0};
+                        }
+                    }
+                },
+                Disposal::Previous => screen = saved.unwrap(),
+                Disposal::Keep | Disposal::Unspecified => {},
+            }
+        }
+        screens
+    }
+}
+
+//------------------------------------------------------------------------- Lzw
+
+/// Decompresses LZW `data` into at most `limit` color indices.
+fn lzw_decode(data: &[u8], min_code_size: u8, limit: usize) -> GifResult<Vec<u8>> {
+    if !(2..=11).contains(&min_code_size) {
+        return Err(GifError::Malformed(format!("LZW minimum code size {}", min_code_size)));
+    }
+    let clear = 1usize << min_code_size;
+    let end = clear + 1;
+    // each code is stored as its prefix code and final index, with its first index and
+    // length cached to expand it without recursion
+    let mut prefix = vec![0u16; MAX_CODES];
+    let mut suffix = vec![0u8; MAX_CODES];
+    let mut first = vec![0u8; MAX_CODES];
+    let mut length = vec![0usize; MAX_CODES];
+    for code in 0..clear {
+        suffix[code] = code as u8;
+        first[code] = code as u8;
+        length[code] = 1;
+    }
+
+    let mut out = Vec::with_capacity(limit);
+    let mut code_size = min_code_size as usize + 1;
+    let mut next = end + 1;
+    let mut previous: Option<usize> = None;
+    let mut bits = 0u32;
+    let mut bit_count = 0;
+    let mut bytes = data.iter();
+    while out.len() < limit {
+        while bit_count < code_size {
+            match bytes.next() {
+                Some(byte) => {
+                    bits |= (*byte as u32) << bit_count;
+                    bit_count += 8;
+                },
+                None => return Ok(out),
+            }
+        }
+        let code = (bits & ((1 << code_size) - 1)) as usize;
+        bits >>= code_size;
+        bit_count -= code_size;
+
+        if code == clear {
+            code_size = min_code_size as usize + 1;
+            next = end + 1;
+            previous = None;
+            continue;
+        }
+        if code == end {
+            break;
+        }
+        let previous_code = match previous {
+            None => {
+                if code >= clear {
+                    return Err(GifError::Malformed(format!("LZW code {} before any entries", code)));
+                }
+                out.push(code as u8);
+                previous = Some(code);
+                continue;
+            },
+            Some(previous_code) => previous_code,
+        };
+        if code > next || (code == next && next >= MAX_CODES) {
+            return Err(GifError::Malformed(format!("LZW code {} out of range", code)));
+        }
+        if next < MAX_CODES {
+            // for the code being defined right now (KwKwK) the new entry ends with its own
+            // first index, which is the previous code's first index
+            let k = if code == next { first[previous_code] } else { first[code] };
+            prefix[next] = previous_code as u16;
+            suffix[next] = k;
+            first[next] = first[previous_code];
+            length[next] = length[previous_code] + 1;
+            next += 1;
+            if next == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+        }
+
+        let start = out.len();
+        out.resize(start + length[code], 0);
+        let mut c = code;
+        for i in (start..out.len()).rev() {
+            out[i] = suffix[c];
+            c = prefix[c] as usize;
+        }
+        previous = Some(code);
+    }
+    out.truncate(limit);
+    Ok(out)
+}
+
+/// Packs codes least significant bit first, as GIF requires.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    bit_count: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: usize, size: usize) {
+        self.bits |= (code as u32) << self.bit_count;
+        self.bit_count += size;
+        while self.bit_count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// Compresses color indices, each below `1 << min_code_size`.
+fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1usize << min_code_size;
+    let end = clear + 1;
+    let mut writer = BitWriter { out: Vec::new(), bits: 0, bit_count: 0 };
+    let mut table: HashMap<(usize, u8), usize> = HashMap::new();
+    let mut code_size = min_code_size as usize + 1;
+    let mut next = end + 1;
+
+    writer.write(clear, code_size);
+    let mut current: Option<usize> = None;
+    for &index in indices {
+        let prefix = match current {
+            None => {
+                current = Some(index as usize);
+                continue;
+            },
+            Some(prefix) => prefix,
+        };
+        if let Some(&code) = table.get(&(prefix, index)) {
+            current = Some(code);
+            continue;
+        }
+        writer.write(prefix, code_size);
+        if next < MAX_CODES {
+            table.insert((prefix, index), next);
+            next += 1;
+            // the decoder defines each entry one code later, so it widens its codes then
+            if next > 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+        } else {
+            writer.write(clear, code_size);
+            table.clear();
+            code_size = min_code_size as usize + 1;
+            next = end + 1;
+        }
+        current = Some(index as usize);
+    }
+    if let Some(code) = current {
+        writer.write(code, code_size);
+    }
+    writer.write(end, code_size);
+    writer.finish()
+}
+
+//---------------------------------------------------------------------- Reader
+
+/// Reads a sequence of data sub-blocks up to the zero length terminator.
+fn read_sub_blocks<R: Read>(reader: &mut R) -> GifResult<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let size = reader.read_u8()? as u64;
+        if size == 0 {
+            return Ok(data);
+        }
+        let read = reader.take(size).read_to_end(&mut data)?;
+        if read as u64 != size {
+            return Err(GifError::Truncated);
+        }
+    }
+}
+
+fn read_color_table<R: Read>(reader: &mut R, packed: u8) -> GifResult<Vec<Pixel>> {
+    let mut data = vec![0u8; 3 << ((packed & 0x07) + 1)];
+    reader.read_exact(&mut data)?;
+    Ok(data.chunks(3).map(|c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}).collect())
+}
+
+pub fn load(path_str: &str) -> GifResult<Bmp> {
+    let file = File::open(Path::new(path_str))?;
+    read_from(&mut ::std::io::BufReader::new(file))
+}
+
+pub fn load_animation(path_str: &str) -> GifResult<Animation> {
+    let file = File::open(Path::new(path_str))?;
+    read_animation_from(&mut ::std::io::BufReader::new(file))
+}
+
+pub fn read_from<R: Read>(reader: &mut R) -> GifResult<Bmp> {
+    read_from_with(reader, &LoadOptions::default())
+}
+
+/// Reads the first frame, placed on an otherwise transparent image the size of the logical
+/// screen.
+pub fn read_from_with<R: Read>(reader: &mut R, options: &LoadOptions) -> GifResult<Bmp> {
+    let animation = read_animation_from_with(reader, options)?;
+    let frame = match animation.frames.into_iter().next() {
+        Some(frame) => frame,
+        None => return Err(GifError::Malformed("no image data".to_string())),
+    };
+    let mut bmp = Bmp::new(animation.width as u32, animation.height as u32);
+    for column in bmp.pixels.iter_mut() {
+        for p in column.iter_mut() {
+            p.a = 0;
+        }
+    }
+    for x in 0..frame.image.width() as usize {
+        for y in 0..frame.image.height() as usize {
+            let (sx, sy) = (frame.left as usize + x, frame.top as usize + y);
+            if sx < bmp.width() as usize && sy < bmp.height() as usize {
+                bmp.pixels[sx][sy] = frame.image.pixels[x][y];
+            }
+        }
+    }
+    bmp.palette = frame.image.palette;
+    Ok(bmp)
+}
+
+pub fn read_animation_from<R: Read>(reader: &mut R) -> GifResult<Animation> {
+    read_animation_from_with(reader, &LoadOptions::default())
+}
+
+/// Reads every frame as it's stored, without compositing them onto the logical screen.
+pub fn read_animation_from_with<R: Read>(reader: &mut R, options: &LoadOptions) -> GifResult<Animation> {
+    let mut signature = [0u8; 6];
+    reader.read_exact(&mut signature)?;
+    if &signature != b"GIF87a" && &signature != b"GIF89a" {
+        return Err(GifError::BadSignature);
+    }
+    let width = reader.read_u16::<LittleEndian>()?;
+    let height = reader.read_u16::<LittleEndian>()?;
+    if !options.allows(width as u32, height as u32) {
+        return Err(GifError::TooLarge(width as u32, height as u32));
+    }
+    let packed = reader.read_u8()?;
+    let _background = reader.read_u8()?;
+    let _aspect = reader.read_u8()?;
+
+    let mut animation = Animation::new(width, height);
+    animation.loop_count = None;
+    if packed & 0x80 != 0 {
+        animation.palette = Some(read_color_table(reader, packed)?);
+    }
+
+    // the graphic control extension applies to the next image only
+    let mut delay = 0;
+    let mut disposal = Disposal::Unspecified;
+    let mut transparent: Option<u8> = None;
+    loop {
+        let introducer = match reader.read_u8() {
+            Ok(introducer) => introducer,
+            // plenty of encoders leave off the trailer
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof && !animation.frames.is_empty() => break,
+            Err(err) => return Err(From::from(err)),
+        };
+        match introducer {
+            EXTENSION => {
+                let label = reader.read_u8()?;
+                let data = read_sub_blocks(reader)?;
+                match label {
+                    GRAPHIC_CONTROL if data.len() >= 4 => {
+                        disposal = Disposal::from_bits((data[0] >> 2) & 0x07);
+                        delay = data[1] as u16 | (data[2] as u16) << 8;
+                        transparent = if data[0] & 0x01 != 0 { Some(data[3]) } else { None };
+                    },
+                    APPLICATION if data.len() >= 14 && (&data[..11] == b"NETSCAPE2.0" || &data[..11] == b"ANIMEXTS1.0") && data[11] == 1 => {
+                        animation.loop_count = Some(data[12] as u16 | (data[13] as u16) << 8);
+                    },
+                    _ => (),
+                }
+            },
+            IMAGE_DESCRIPTOR => {
+                let left = reader.read_u16::<LittleEndian>()?;
+                let top = reader.read_u16::<LittleEndian>()?;
+                let frame_width = reader.read_u16::<LittleEndian>()? as usize;
+                let frame_height = reader.read_u16::<LittleEndian>()? as usize;
+                if !options.allows(frame_width as u32, frame_height as u32) {
+                    return Err(GifError::TooLarge(frame_width as u32, frame_height as u32));
+                }
+                let packed = reader.read_u8()?;
+                let local = packed & 0x80 != 0;
+                let mut palette = if local {
+                    read_color_table(reader, packed)?
+                } else {
+                    match animation.palette {
+                        Some(ref palette) => palette.clone(),
+                        None => return Err(GifError::Malformed("image without a color table".to_string())),
+                    }
+                };
+                if let Some(index) = transparent {
+                    if let Some(p) = palette.get_mut(index as usize) {
+                        p.a = 0;
+                    }
+                }
+
+                let min_code_size = reader.read_u8()?;
+                let data = read_sub_blocks(reader)?;
+                let indices = lzw_decode(&data, min_code_size, frame_width * frame_height)?;
+                if indices.len() < frame_width * frame_height {
+                    return Err(GifError::Truncated);
+                }
+
+                let rows: Vec<usize> = if packed & 0x40 != 0 {
+                    INTERLACE.iter().flat_map(|&(start, step)| (start..frame_height).step_by(step)).collect()
+                } else {
+                    (0..frame_height).collect()
+                };
+                let mut image = Bmp::new(frame_width as u32, frame_height as u32);
+                for (row, &y) in rows.iter().enumerate() {
+                    for x in 0..frame_width {
+                        let index = indices[row * frame_width + x];
+                        image.pixels[x][y] = match palette.get(index as usize) {
+                            Some(p) => *p,
+                            None => return Err(GifError::Malformed(format!("color index {} out of range", index))),
+                        };
+                    }
+                }
+                image.palette = Some(palette);
+                animation.frames.push(Frame {
+                    image: image,
+                    left: left,
+                    top: top,
+                    delay: delay,
+                    disposal: disposal,
+                    local_palette: local,
+                });
+                delay = 0;
+                disposal = Disposal::Unspecified;
+                transparent = None;
+            },
+            TRAILER => break,
+            b => return Err(GifError::Malformed(format!("unknown block 0x{:02x}", b))),
+        }
+    }
+    Ok(animation)
+}
+
+//---------------------------------------------------------------------- Writer
+
+/// Whether a pixel is written as transparent; GIF transparency is all or nothing.
+fn is_clear(p: &Pixel) -> bool {
+    p.a < 128
+}
+
+/// Maps the image's pixels onto `palette`, comparing colors only since GIF has no partial
+/// transparency.  With `grow` a transparent entry is added when a pixel needs one and the
+/// palette has room.
+fn index_pixels(bmp: &Bmp, palette: &[Pixel], grow: bool) -> Option<Indexed> {
+    let mut palette = palette.to_vec();
+    let mut lookup = HashMap::new();
+    for (i, p) in palette.iter().enumerate().rev() {
+        if !p.is_transparent() {
+            lookup.insert((p.r, p.g, p.b), i as u8);
+        }
+    }
+    let mut transparent = palette.iter().position(|p| p.is_transparent()).map(|i| i as u8);
+
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let mut indices = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let p = &bmp.pixels[x][y];
+            let index = if is_clear(p) {
+                match transparent {
+                    Some(index) => index,
+                    None if grow && palette.len() < 256 => {
+                        palette.push(Pixel {r: 0, g: 0, b: 0, a: 0});
+                        transparent = Some(palette.len() as u8 - 1);
+                        palette.len() as u8 - 1
+                    },
+                    None => return None,
+                }
+            } else {
+                *lookup.get(&(p.r, p.g, p.b))?
+            };
+            indices.push(index);
+        }
+    }
+    if palette.len() > 256 {
+        return None;
+    }
+    Some(Indexed {
+        palette: palette,
+        indices: indices,
+        transparent: transparent,
+    })
+}
+
+/// The image's palette, or its distinct opaque colors for images that were never dithered.
+fn frame_palette(bmp: &Bmp) -> Vec<Pixel> {
+    if let Some(ref palette) = bmp.palette {
+        return palette.clone();
+    }
+    let mut colors = Vec::new();
+    let mut seen = HashMap::new();
+    for column in &bmp.pixels {
+        for p in column {
+            if !is_clear(p) && !seen.contains_key(&(p.r, p.g, p.b)) {
+                seen.insert((p.r, p.g, p.b), ());
+                colors.push(Pixel { a: 255, ..*p });
+                if colors.len() > 256 {
+                    return colors;
+                }
+            }
+        }
+    }
+    colors
+}
+
+/// The size field for a color table of `len` entries, which is padded to a power of two.
+fn table_bits(len: usize) -> u8 {
+    let mut bits = 1;
+    while 1 << bits < len {
+        bits += 1;
+    }
+    bits
+}
+
+fn write_color_table<W: Write>(writer: &mut W, palette: &[Pixel]) -> GifResult<()> {
+    for p in palette {
+        writer.write_all(&[p.r, p.g, p.b])?;
+    }
+    for _ in palette.len()..1 << table_bits(palette.len()) {
+        writer.write_all(&[0, 0, 0])?;
+    }
+    Ok(())
+}
+
+pub fn save(bmp: &Bmp, path_str: &str) -> GifResult<()> {
+    let file = File::create(Path::new(path_str))?;
+    let mut file = ::std::io::BufWriter::new(file);
+    write_to(bmp, &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+pub fn save_animation(animation: &Animation, path_str: &str) -> GifResult<()> {
+    let file = File::create(Path::new(path_str))?;
+    let mut file = ::std::io::BufWriter::new(file);
+    write_animation_to(animation, &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes a single frame GIF.  The image must already fit in 256 colors, which dithering
+/// to a palette guarantees.
+pub fn write_to<W: Write>(bmp: &Bmp, writer: &mut W) -> GifResult<()> {
+    if bmp.width() > 0xffff || bmp.height() > 0xffff {
+        return Err(GifError::TooLarge(bmp.width(), bmp.height()));
+    }
+    let indexed = match index_pixels(bmp, &frame_palette(bmp), true) {
+        Some(indexed) => indexed,
+        None => return Err(GifError::TooManyColors),
+    };
+    write_screen(writer, bmp.width() as u16, bmp.height() as u16, Some(&indexed.palette), None)?;
+    write_image(writer, &Frame::new(Bmp::new(0, 0)), bmp, &indexed, false)?;
+    writer.write_u8(TRAILER)?;
+    Ok(())
+}
+
+/// Writes every frame, sharing the global color table with the frames whose colors it
+/// holds and giving the rest a local one.
+pub fn write_animation_to<W: Write>(animation: &Animation, writer: &mut W) -> GifResult<()> {
+    let mut global = animation.palette.clone()
+        .or_else(|| animation.frames.first().map(|f| frame_palette(&f.image)))
+        .filter(|palette| !palette.is_empty() && palette.len() <= 256);
+    if let Some(ref mut global) = global {
+        // make room for transparency up front so frames needing it can still share the table
+        let needs_transparency = animation.frames.iter()
+            .any(|f| f.image.pixels.iter().any(|column| column.iter().any(is_clear)));
+        if needs_transparency && global.len() < 256 && !global.iter().any(|p| p.is_transparent()) {
+            global.push(Pixel {r: 0, g: 0, b: 0, a: 0});
+        }
+    }
+
+    write_screen(writer, animation.width, animation.height, global.as_ref().map(|g| &g[..]), animation.loop_count)?;
+    for frame in &animation.frames {
+        let shared = match global {
+            Some(ref global) if !frame.local_palette => index_pixels(&frame.image, global, false),
+            _ => None,
+        };
+        let (indexed, local) = match shared {
+            Some(indexed) => (indexed, false),
+            None => match index_pixels(&frame.image, &frame_palette(&frame.image), true) {
+                Some(indexed) => (indexed, true),
+                None => return Err(GifError::TooManyColors),
+            },
+        };
+        write_image(writer, frame, &frame.image, &indexed, local)?;
+    }
+    writer.write_u8(TRAILER)?;
+    Ok(())
+}
+
+/// Writes the header, logical screen descriptor, global color table and looping extension.
+fn write_screen<W: Write>(writer: &mut W, width: u16, height: u16, palette: Option<&[Pixel]>, loop_count: Option<u16>) -> GifResult<()> {
+    writer.write_all(b"GIF89a")?;
+    writer.write_u16::<LittleEndian>(width)?;
+    writer.write_u16::<LittleEndian>(height)?;
+    match palette {
+        Some(palette) => {
+            let bits = table_bits(palette.len());
+            // global table present, 8 bits per primary color
+            writer.write_all(&[0x80 | 0x70 | (bits - 1), 0, 0])?;
+            write_color_table(writer, palette)?;
+        },
+        None => writer.write_all(&[0, 0, 0])?,
+    }
+    if let Some(loop_count) = loop_count {
+        writer.write_all(&[EXTENSION, APPLICATION, 11])?;
+        writer.write_all(b"NETSCAPE2.0")?;
+        writer.write_all(&[3, 1])?;
+        writer.write_u16::<LittleEndian>(loop_count)?;
+        writer.write_u8(0)?;
+    }
+    Ok(())
+}
+
+/// Writes one image, with its graphic control extension when it has timing, disposal or
+/// transparency to record.
+fn write_image<W: Write>(writer: &mut W, frame: &Frame, image: &Bmp, indexed: &Indexed, local: bool) -> GifResult<()> {
+    if image.width() > 0xffff || image.height() > 0xffff {
+        return Err(GifError::TooLarge(image.width(), image.height()));
+    }
+    if frame.delay != 0 || frame.disposal != Disposal::Unspecified || indexed.transparent.is_some() {
+        let flags = frame.disposal.bits() << 2 | if indexed.transparent.is_some() { 1 } else { 0 };
+        writer.write_all(&[EXTENSION, GRAPHIC_CONTROL, 4, flags])?;
+        writer.write_u16::<LittleEndian>(frame.delay)?;
+        writer.write_all(&[indexed.transparent.unwrap_or(0), 0])?;
+    }
+
+    writer.write_u8(IMAGE_DESCRIPTOR)?;
+    writer.write_u16::<LittleEndian>(frame.left)?;
+    writer.write_u16::<LittleEndian>(frame.top)?;
+    writer.write_u16::<LittleEndian>(image.width() as u16)?;
+    writer.write_u16::<LittleEndian>(image.height() as u16)?;
+    let bits = table_bits(indexed.palette.len());
+    if local {
+        writer.write_u8(0x80 | (bits - 1))?;
+        write_color_table(writer, &indexed.palette)?;
+    } else {
+        writer.write_u8(0)?;
+    }
+
+    // LZW needs a code size of at least 2 even for two color tables
+    let min_code_size = bits.max(2);
+    writer.write_u8(min_code_size)?;
+    for block in lzw_encode(&indexed.indices, min_code_size).chunks(255) {
+        writer.write_u8(block.len() as u8)?;
+        writer.write_all(block)?;
+    }
+    writer.write_u8(0)?;
+    Ok(())
+}
+
+#[test]
+fn lzw_round_trip() {
+    // enough pseudo random data to fill the code table and force clear codes
+    let mut seed = 12345u32;
+    let noise: Vec<u8> = (0..20000).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8 % 5
+    }).collect();
+    let runs: Vec<u8> = (0..5000).map(|i| (i / 700 % 4) as u8).collect();
+    for (data, min_code_size) in vec![(noise, 3), (runs, 2), (vec![1], 2), (vec![], 8)] {
+        let encoded = lzw_encode(&data, min_code_size);
+        assert_eq!(data, lzw_decode(&encoded, min_code_size, data.len()).unwrap());
+    }
+}
+
+#[test]
+fn animation_round_trip() {
+    let mut first = Bmp::new(4, 3);
+    first.palette = Some(vec![Pixel::black(), Pixel::white()]);
+    first.pixels[1][1] = Pixel::white();
+    first.pixels[3][2] = Pixel {r: 0, g: 0, b: 0, a: 0};
+    let mut second = Bmp::new(2, 2);
+    second.palette = Some(vec![Pixel::red(), Pixel::blue(), Pixel::green()]);
+    second.pixels = vec![vec![Pixel::red(), Pixel::blue()], vec![Pixel::blue(), Pixel::blue()]];
+    second.pixels[1][1] = Pixel::green();
+
+    let mut animation = Animation::new(4, 3);
+    animation.frames.push(Frame { delay: 10, ..Frame::new(first) });
+    animation.frames.push(Frame { left: 1, top: 1, delay: 25, disposal: Disposal::Previous, ..Frame::new(second) });
+    let mut data = Vec::new();
+    write_animation_to(&animation, &mut data).unwrap();
+
+    let loaded = read_animation_from(&mut ::std::io::Cursor::new(&data)).unwrap();
+    assert_eq!((4, 3, Some(0)), (loaded.width, loaded.height, loaded.loop_count));
+    assert_eq!(2, loaded.frames.len());
+    for (expected, actual) in animation.frames.iter().zip(loaded.frames.iter()) {
+        assert_eq!((expected.left, expected.top, expected.delay, expected.disposal),
+                   (actual.left, actual.top, actual.delay, actual.disposal));
+        for x in 0..expected.image.width() as usize {
+            for y in 0..expected.image.height() as usize {
+                let (e, a) = (expected.image.pixels[x][y], actual.image.pixels[x][y]);
+                assert_eq!(e.is_transparent(), a.is_transparent());
+                if !e.is_transparent() {
+                    assert_eq!(e, a);
+                }
+            }
+        }
+    }
+    // the second frame's colors aren't in the global table
+    assert!(!loaded.frames[0].local_palette);
+    assert!(loaded.frames[1].local_palette);
+
+    let bmp = read_from(&mut ::std::io::Cursor::new(&data)).unwrap();
+    assert_eq!(Pixel::white(), bmp.pixels[1][1]);
+    assert!(bmp.pixels[3][2].is_transparent());
+}
+
+#[test]
+fn composite_disposal() {
+    let clear = Pixel {r: 0, g: 0, b: 0, a: 0};
+    let mut white = Bmp::new(3, 2);
+    for p in white.pixels.iter_mut().flat_map(|column| column.iter_mut()) {
+        *p = Pixel::white();
+    }
+    let mut red = Bmp::new(1, 1);
+    red.pixels[0][0] = Pixel::red();
+    let mut blue = Bmp::new(2, 1);
+    blue.pixels = vec![vec![Pixel::blue()], vec![clear]];
+    let mut green = Bmp::new(1, 1);
+    green.pixels[0][0] = Pixel::green();
+    let mut hole = Bmp::new(1, 1);
+    hole.pixels[0][0] = clear;
+
+    let mut animation = Animation::new(3, 2);
+    animation.frames.push(Frame { disposal: Disposal::Keep, ..Frame::new(white) });
+    animation.frames.push(Frame { disposal: Disposal::Previous, ..Frame::new(red) });
+    animation.frames.push(Frame { left: 1, disposal: Disposal::Background, ..Frame::new(blue) });
+    animation.frames.push(Frame { top: 1, disposal: Disposal::Unspecified, ..Frame::new(green) });
+    animation.frames.push(Frame { left: 2, top: 1, disposal: Disposal::Keep, ..Frame::new(hole) });
+    let screens = animation.composite();
+    let row = |screen: &Bmp, y: usize| (0..3).map(|x| screen.pixels[x][y]).collect::<Vec<Pixel>>();
+
+    assert_eq!(vec![Pixel::red(), Pixel::white(), Pixel::white()], row(&screens[1], 0));
+    // the red pixel is restored to what was under it, and the transparent pixel shows
+    // the white beneath
+    assert_eq!(vec![Pixel::white(), Pixel::blue(), Pixel::white()], row(&screens[2], 0));
+    // the blue frame's area is cleared to the background, transparent pixel and all
+    assert_eq!(vec![Pixel::white(), clear, clear], row(&screens[3], 0));
+    assert_eq!(vec![Pixel::green(), Pixel::white(), Pixel::white()], row(&screens[3], 1));
+    // frames without a disposal method are left in place
+    assert_eq!(screens[3].pixels, screens[4].pixels);
+}
@@ -0,0 +1,945 @@
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+use bmp::{Bmp, LoadOptions, Pixel};
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOS: u8 = 0xda;
+const DQT: u8 = 0xdb;
+const DNL: u8 = 0xdc;
+const DRI: u8 = 0xdd;
+const DHT: u8 = 0xc4;
+const DAC: u8 = 0xcc;
+const SOF_BASELINE: u8 = 0xc0;
+const SOF_EXTENDED: u8 = 0xc1;
+const SOF_PROGRESSIVE: u8 = 0xc2;
+const RST0: u8 = 0xd0;
+const RST7: u8 = 0xd7;
+const APP0: u8 = 0xe0;
+const APP1: u8 = 0xe1;
+const APP14: u8 = 0xee;
+
+/// Maps the zigzag order coefficients are coded in to their natural row major position.
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Codes up to this many bits are decoded with a single table lookup.
+const LOOKUP_BITS: u32 = 9;
+
+//------------------------------------------------------------------- JpegError
+
+#[derive(Debug)]
+pub enum JpegError {
+    /// The file didn't start with a start of image marker.
+    BadMagic,
+    /// A marker segment held invalid values or the entropy coded data was corrupt.
+    Malformed(String),
+    /// The process (arithmetic coding, lossless, 12 bit samples) or layout isn't supported.
+    Unsupported(String),
+    /// The image dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The file ended before a marker segment did.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for JpegError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            JpegError::BadMagic => write!(formatter, "missing JPEG start of image marker"),
+            JpegError::Malformed(ref message) => write!(formatter, "malformed JPEG: {}", message),
+            JpegError::Unsupported(ref message) => write!(formatter, "unsupported JPEG: {}", message),
+            JpegError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            JpegError::Truncated => write!(formatter, "unexpected end of file"),
+            JpegError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for JpegError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            JpegError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for JpegError {
+    fn from(err: ::std::io::Error) -> JpegError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => JpegError::Truncated,
+            _ => JpegError::Io(err),
+        }
+    }
+}
+
+pub type JpegResult<T> = Result<T, JpegError>;
+
+//--------------------------------------------------------------------- Huffman
+
+struct HuffmanTable {
+    /// (code length, value) for every `LOOKUP_BITS` bit prefix, with length 0 for longer codes.
+    lookup: Vec<(u8, u8)>,
+    /// The largest code of each length, or -1 when there are none.
+    max_code: [i32; 17],
+    /// The index into `values` of the first code of each length, less that code.
+    offset: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn new(counts: &[u8], values: &[u8]) -> JpegResult<HuffmanTable> {
+        let mut table = HuffmanTable {
+            lookup: vec![(0, 0); 1 << LOOKUP_BITS],
+            max_code: [-1; 17],
+            offset: [0; 17],
+            values: values.to_vec(),
+        };
+        let mut code = 0i32;
+        let mut index = 0i32;
+        for length in 1..17 {
+            let count = counts[length - 1] as i32;
+            if code + count > 1 << length {
+                return Err(JpegError::Malformed("Huffman table has too many codes".to_string()));
+            }
+            table.offset[length] = index - code;
+            for _ in 0..count {
+                if length as u32 <= LOOKUP_BITS {
+                    let shift = LOOKUP_BITS - length as u32;
+                    for fill in 0..1 << shift {
+                        table.lookup[((code << shift) | fill) as usize] = (length as u8, values[index as usize]);
+                    }
+                }
+                code += 1;
+                index += 1;
+            }
+            if count > 0 {
+                table.max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        Ok(table)
+    }
+}
+
+/// Reads entropy coded bits, removing stuffed zero bytes and stopping at the next marker.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+    at_marker: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> BitReader<'a> {
+        BitReader { data: data, pos: pos, bits: 0, count: 0, at_marker: false }
+    }
+    fn fill(&mut self) {
+        while self.count <= 24 {
+            // past a marker the data is padded with zeros, as corrupt files deserve
+            let mut byte = 0;
+            if !self.at_marker && self.pos < self.data.len() {
+                byte = self.data[self.pos];
+                if byte == 0xff {
+                    if self.data.get(self.pos + 1) == Some(&0) {
+                        self.pos += 2;
+                    } else {
+                        self.at_marker = true;
+                        byte = 0;
+                    }
+                } else {
+                    self.pos += 1;
+                }
+            }
+            self.bits |= (byte as u32) << (24 - self.count);
+            self.count += 8;
+        }
+    }
+    fn bits(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        self.fill();
+        let value = self.bits >> (32 - n);
+        self.bits <<= n;
+        self.count -= n;
+        value
+    }
+    fn bit(&mut self) -> bool {
+        self.bits(1) == 1
+    }
+    /// Reads an `n` bit magnitude category value as a signed integer.
+    fn receive_extend(&mut self, n: u32) -> i32 {
+        let value = self.bits(n) as i32;
+        if n > 0 && value < 1 << (n - 1) {
+            value - (1 << n) + 1
+        } else {
+            value
+        }
+    }
+    fn decode(&mut self, table: &HuffmanTable) -> JpegResult<u8> {
+        self.fill();
+        let (length, value) = table.lookup[(self.bits >> (32 - LOOKUP_BITS)) as usize];
+        if length > 0 {
+            self.bits <<= length;
+            self.count -= length as u32;
+            return Ok(value);
+        }
+        for length in LOOKUP_BITS as usize + 1..17 {
+            let code = (self.bits >> (32 - length)) as i32;
+            if code <= table.max_code[length] {
+                self.bits <<= length;
+                self.count -= length as u32;
+                return Ok(table.values[(table.offset[length] + code) as usize]);
+            }
+        }
+        Err(JpegError::Malformed("invalid Huffman code".to_string()))
+    }
+    /// Discards buffered bits and skips the restart marker that should follow.
+    fn restart(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+        self.at_marker = false;
+        while self.pos + 1 < self.data.len() && self.data[self.pos] == 0xff && self.data[self.pos + 1] == 0xff {
+            self.pos += 1;
+        }
+        if self.pos + 1 < self.data.len() && self.data[self.pos] == 0xff
+            && self.data[self.pos + 1] >= RST0 && self.data[self.pos + 1] <= RST7 {
+            self.pos += 2;
+        }
+    }
+}
+
+//----------------------------------------------------------------------- Frame
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant: usize,
+    /// Blocks per line and per column, padded out to whole MCUs.
+    blocks_wide: usize,
+    blocks_high: usize,
+    /// Every block's coefficients in natural order, dequantized once all scans are read.
+    coefficients: Vec<i32>,
+    dc_table: usize,
+    ac_table: usize,
+    dc_prediction: i32,
+}
+
+struct Frame {
+    width: usize,
+    height: usize,
+    progressive: bool,
+    components: Vec<Component>,
+    h_max: usize,
+    v_max: usize,
+    mcus_wide: usize,
+    mcus_high: usize,
+}
+
+/// The parameters of a single scan.
+struct Scan {
+    components: Vec<usize>,
+    start: usize,
+    end: usize,
+    high: u32,
+    low: u32,
+}
+
+fn read_u16(data: &[u8], pos: usize) -> JpegResult<usize> {
+    match data.get(pos..pos + 2) {
+        Some(bytes) => Ok((bytes[0] as usize) << 8 | bytes[1] as usize),
+        None => Err(JpegError::Truncated),
+    }
+}
+
+fn read_frame(segment: &[u8], progressive: bool, options: &LoadOptions) -> JpegResult<Frame> {
+    if segment.len() < 6 {
+        return Err(JpegError::Truncated);
+    }
+    if segment[0] != 8 {
+        return Err(JpegError::Unsupported(format!("{} bit samples", segment[0])));
+    }
+    let height = read_u16(segment, 1)?;
+    let width = read_u16(segment, 3)?;
+    let count = segment[5] as usize;
+    if height == 0 {
+        return Err(JpegError::Unsupported("height defined by a DNL marker".to_string()));
+    }
+    if width == 0 {
+        return Err(JpegError::Malformed("zero width".to_string()));
+    }
+    if !options.allows(width as u32, height as u32) {
+        return Err(JpegError::TooLarge(width as u32, height as u32));
+    }
+    if count != 1 && count != 3 && count != 4 {
+        return Err(JpegError::Unsupported(format!("{} components", count)));
+    }
+    if segment.len() < 6 + count * 3 {
+        return Err(JpegError::Truncated);
+    }
+    let mut components = Vec::with_capacity(count);
+    for i in 0..count {
+        let c = &segment[6 + i * 3..9 + i * 3];
+        let (h, v) = ((c[1] >> 4) as usize, (c[1] & 0x0f) as usize);
+        if h < 1 || h > 4 || v < 1 || v > 4 || c[2] > 3 {
+            return Err(JpegError::Malformed(format!("component {} sampling {}x{} or table {}", c[0], h, v, c[2])));
+        }
+        components.push(Component {
+            id: c[0],
+            h: h,
+            v: v,
+            quant: c[2] as usize,
+            blocks_wide: 0,
+            blocks_high: 0,
+            coefficients: Vec::new(),
+            dc_table: 0,
+            ac_table: 0,
+            dc_prediction: 0,
+        });
+    }
+    let h_max = components.iter().map(|c| c.h).max().unwrap_or(1);
+    let v_max = components.iter().map(|c| c.v).max().unwrap_or(1);
+    let mcus_wide = (width + 8 * h_max - 1) / (8 * h_max);
+    let mcus_high = (height + 8 * v_max - 1) / (8 * v_max);
+    for c in components.iter_mut() {
+        c.blocks_wide = mcus_wide * c.h;
+        c.blocks_high = mcus_high * c.v;
+        c.coefficients = vec![0; c.blocks_wide * c.blocks_high * 64];
+    }
+    Ok(Frame {
+        width: width,
+        height: height,
+        progressive: progressive,
+        components: components,
+        h_max: h_max,
+        v_max: v_max,
+        mcus_wide: mcus_wide,
+        mcus_high: mcus_high,
+    })
+}
+
+//------------------------------------------------------------------------ Scan
+
+fn dc_size(size: u8) -> JpegResult<u32> {
+    if size > 11 {
+        return Err(JpegError::Malformed(format!("DC difference of {} bits", size)));
+    }
+    Ok(size as u32)
+}
+
+/// Decodes one block's coefficients for the current scan into `block`.
+fn decode_block(reader: &mut BitReader, frame_progressive: bool, scan: &Scan, component: &mut Component,
+                block: usize, tables: &[Option<HuffmanTable>; 8], eob_run: &mut u32) -> JpegResult<()> {
+    let dc_table = tables[component.dc_table].as_ref();
+    let ac_table = tables[4 + component.ac_table].as_ref();
+    let missing = || JpegError::Malformed("scan uses an undefined Huffman table".to_string());
+    let coefficients = &mut component.coefficients[block * 64..block * 64 + 64];
+
+    if !frame_progressive {
+        let (dc_table, ac_table) = (dc_table.ok_or_else(missing)?, ac_table.ok_or_else(missing)?);
+        let size = dc_size(reader.decode(dc_table)?)?;
+        // a corrupt scan can push the prediction anywhere, but mustn't overflow it
+        component.dc_prediction = component.dc_prediction.wrapping_add(reader.receive_extend(size));
+        coefficients[0] = component.dc_prediction;
+        let mut k = 1;
+        while k < 64 {
+            let rs = reader.decode(ac_table)?;
+            let (run, size) = ((rs >> 4) as usize, (rs & 0x0f) as u32);
+            if size == 0 {
+                if run != 15 {
+                    break;
+                }
+                k += 16;
+                continue;
+            }
+            k += run;
+            if k > 63 {
+                return Err(JpegError::Malformed("coefficient index past the end of a block".to_string()));
+            }
+            coefficients[ZIGZAG[k]] = reader.receive_extend(size);
+            k += 1;
+        }
+        return Ok(());
+    }
+
+    if scan.start == 0 {
+        if scan.high == 0 {
+            let size = dc_size(reader.decode(dc_table.ok_or_else(missing)?)?)?;
+            component.dc_prediction = component.dc_prediction.wrapping_add(reader.receive_extend(size));
+            coefficients[0] = component.dc_prediction << scan.low;
+        } else if reader.bit() {
+            coefficients[0] |= 1 << scan.low;
+        }
+        return Ok(());
+    }
+
+    let ac_table = ac_table.ok_or_else(missing)?;
+    if scan.high == 0 {
+        // first pass over a band of AC coefficients
+        if *eob_run > 0 {
+            *eob_run -= 1;
+            return Ok(());
+        }
+        let mut k = scan.start;
+        while k <= scan.end {
+            let rs = reader.decode(ac_table)?;
+            let (run, size) = ((rs >> 4) as u32, (rs & 0x0f) as u32);
+            if size == 0 {
+                if run < 15 {
+                    *eob_run = (1 << run) - 1 + reader.bits(run);
+                    break;
+                }
+                k += 16;
+                continue;
+            }
+            k += run as usize;
+            if k > 63 {
+                return Err(JpegError::Malformed("coefficient index past the end of a block".to_string()));
+            }
+            coefficients[ZIGZAG[k]] = reader.receive_extend(size) * (1 << scan.low);
+            k += 1;
+        }
+        return Ok(());
+    }
+
+    // refinement of a band of AC coefficients, one bit at a time
+    let positive = 1 << scan.low;
+    let negative = -1 << scan.low;
+    let mut k = scan.start;
+    if *eob_run == 0 {
+        while k <= scan.end {
+            let rs = reader.decode(ac_table)?;
+            let (mut run, size) = ((rs >> 4) as i32, (rs & 0x0f) as u32);
+            let mut value = 0;
+            if size == 0 {
+                if run < 15 {
+                    *eob_run = (1 << run) + reader.bits(run as u32);
+                    break;
+                }
+            } else {
+                value = if reader.bit() { positive } else { negative };
+            }
+            while k <= scan.end {
+                let c = &mut coefficients[ZIGZAG[k]];
+                if *c != 0 {
+                    if reader.bit() && *c & positive == 0 {
+                        *c += if *c >= 0 { positive } else { negative };
+                    }
+                } else {
+                    if run == 0 {
+                        if value != 0 {
+                            *c = value;
+                        }
+                        k += 1;
+                        break;
+                    }
+                    run -= 1;
+                }
+                k += 1;
+            }
+        }
+    }
+    if *eob_run > 0 {
+        while k <= scan.end {
+            let c = &mut coefficients[ZIGZAG[k]];
+            if *c != 0 && reader.bit() && *c & positive == 0 {
+                *c += if *c >= 0 { positive } else { negative };
+            }
+            k += 1;
+        }
+        *eob_run -= 1;
+    }
+    Ok(())
+}
+
+/// Decodes the entropy coded data starting at `pos`, returning where the next marker is.
+fn decode_scan(data: &[u8], pos: usize, frame: &mut Frame, scan: &Scan, tables: &[Option<HuffmanTable>; 8],
+               restart_interval: usize) -> JpegResult<usize> {
+    let mut reader = BitReader::new(data, pos);
+    let mut eob_run = 0;
+    for &c in &scan.components {
+        frame.components[c].dc_prediction = 0;
+    }
+
+    // a single component scan isn't interleaved; its MCU is one block and it only covers
+    // the blocks the component's own dimensions need
+    let single = scan.components.len() == 1;
+    let (mcus_wide, mcus_high) = if single {
+        let c = &frame.components[scan.components[0]];
+        ((((frame.width * c.h + frame.h_max - 1) / frame.h_max) + 7) / 8,
+         (((frame.height * c.v + frame.v_max - 1) / frame.v_max) + 7) / 8)
+    } else {
+        (frame.mcus_wide, frame.mcus_high)
+    };
+
+    let total = mcus_wide * mcus_high;
+    for mcu in 0..total {
+        if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+            reader.restart();
+            eob_run = 0;
+            for &c in &scan.components {
+                frame.components[c].dc_prediction = 0;
+            }
+        }
+        let (mx, my) = (mcu % mcus_wide, mcu / mcus_wide);
+        for &c in &scan.components {
+            let component = &mut frame.components[c];
+            if single {
+                let block = my * component.blocks_wide + mx;
+                decode_block(&mut reader, frame.progressive, scan, component, block, tables, &mut eob_run)?;
+                continue;
+            }
+            for by in 0..component.v {
+                for bx in 0..component.h {
+                    let block = (my * component.v + by) * component.blocks_wide + mx * component.h + bx;
+                    decode_block(&mut reader, frame.progressive, scan, component, block, tables, &mut eob_run)?;
+                }
+            }
+        }
+    }
+
+    // skip anything left before the next real marker
+    let mut end = reader.pos;
+    while end + 1 < data.len() {
+        if data[end] == 0xff && data[end + 1] != 0 && data[end + 1] != 0xff && !(RST0..=RST7).contains(&data[end + 1]) {
+            break;
+        }
+        end += 1;
+    }
+    Ok(end)
+}
+
+//------------------------------------------------------------------------ Idct
+
+/// `cosines[x][u]` holds C(u) / 2 * cos((2x + 1)uπ / 16).
+fn idct_cosines() -> [[f32; 8]; 8] {
+    let mut cosines = [[0f32; 8]; 8];
+    for x in 0..8 {
+        for u in 0..8 {
+            let scale = if u == 0 { 1.0 / 2f32.sqrt() } else { 1.0 };
+            cosines[x][u] = scale / 2.0 * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+        }
+    }
+    cosines
+}
+
+/// Transforms dequantized coefficients into level shifted samples.
+fn idct(coefficients: &[f32], cosines: &[[f32; 8]; 8], out: &mut [u8; 64]) {
+    let mut rows = [0f32; 64];
+    for v in 0..8 {
+        for x in 0..8 {
+            let mut sum = 0.0;
+            for u in 0..8 {
+                sum += cosines[x][u] * coefficients[v * 8 + u];
+            }
+            rows[v * 8 + x] = sum;
+        }
+    }
+    for x in 0..8 {
+        for y in 0..8 {
+            let mut sum = 0.0;
+            for v in 0..8 {
+                sum += cosines[y][v] * rows[v * 8 + x];
+            }
+            out[y * 8 + x] = (sum + 128.0).round().max(0.0).min(255.0) as u8;
+        }
+    }
+}
+
+//------------------------------------------------------------------------ Exif
+
+/// Finds the orientation tag in an `Exif` APP1 segment, returning 1 (upright) when absent.
+fn exif_orientation(segment: &[u8]) -> u16 {
+    if segment.len() < 14 || &segment[..6] != b"Exif\0\0" {
+        return 1;
+    }
+    let tiff = &segment[6..];
+    let big_endian = &tiff[..2] == b"MM";
+    let read16 = |pos: usize| -> Option<u16> {
+        let b = tiff.get(pos..pos + 2)?;
+        Some(if big_endian { (b[0] as u16) << 8 | b[1] as u16 } else { (b[1] as u16) << 8 | b[0] as u16 })
+    };
+    let read32 = |pos: usize| -> Option<usize> {
+        let b = tiff.get(pos..pos + 4)?;
+        let b: Vec<usize> = b.iter().map(|b| *b as usize).collect();
+        Some(if big_endian { b[0] << 24 | b[1] << 16 | b[2] << 8 | b[3] } else { b[3] << 24 | b[2] << 16 | b[1] << 8 | b[0] })
+    };
+    let find = || -> Option<u16> {
+        let ifd = read32(4)?;
+        let count = read16(ifd)? as usize;
+        for i in 0..count {
+            let entry = ifd + 2 + i * 12;
+            if read16(entry)? == 0x0112 {
+                return read16(entry + 8);
+            }
+        }
+        None
+    };
+    match find() {
+        Some(orientation) if orientation >= 1 && orientation <= 8 => orientation,
+        _ => 1,
+    }
+}
+
+/// Rotates and mirrors an image so that EXIF `orientation` becomes upright.
+fn orient(bmp: Bmp, orientation: u16) -> Bmp {
+    if orientation <= 1 {
+        return bmp;
+    }
+    let (width, height) = (bmp.width() as usize, bmp.height() as usize);
+    let transposed = orientation >= 5;
+    let mut out = if transposed { Bmp::new(height as u32, width as u32) } else { Bmp::new(width as u32, height as u32) };
+    for x in 0..out.width() as usize {
+        for y in 0..out.height() as usize {
+            let (sx, sy) = match orientation {
+                2 => (width - 1 - x, y),
+                3 => (width - 1 - x, height - 1 - y),
+                4 => (x, height - 1 - y),
+                5 => (y, x),
+                6 => (y, height - 1 - x),
+                7 => (width - 1 - y, height - 1 - x),
+                _ => (width - 1 - y, x),
+            };
+            out.pixels[x][y] = bmp.pixels[sx][sy];
+        }
+    }
+    out
+}
+
+//---------------------------------------------------------------------- Reader
+
+pub fn load(path_str: &str) -> JpegResult<Bmp> {
+    let file = File::open(Path::new(path_str))?;
+    read_from(&mut ::std::io::BufReader::new(file))
+}
+
+pub fn read_from<R: Read>(reader: &mut R) -> JpegResult<Bmp> {
+    read_from_with(reader, &LoadOptions::default())
+}
+
+/// Decodes a baseline or progressive Huffman coded JPEG, turned upright according to its
+/// EXIF orientation.
+pub fn read_from_with<R: Read>(reader: &mut R, options: &LoadOptions) -> JpegResult<Bmp> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    if data.len() < 2 || data[0] != 0xff || data[1] != SOI {
+        return Err(JpegError::BadMagic);
+    }
+
+    let mut quant_tables = [[0u16; 64]; 4];
+    let mut tables: [Option<HuffmanTable>; 8] = [None, None, None, None, None, None, None, None];
+    let mut frame: Option<Frame> = None;
+    let mut restart_interval = 0;
+    let mut orientation = 1;
+    let mut adobe_transform: Option<u8> = None;
+    let mut jfif = false;
+
+    let mut pos = 2;
+    loop {
+        // markers may be preceded by any number of fill bytes
+        while pos < data.len() && data[pos] != 0xff {
+            pos += 1;
+        }
+        while pos < data.len() && data[pos] == 0xff {
+            pos += 1;
+        }
+        let marker = match data.get(pos) {
+            Some(marker) => *marker,
+            None if frame.is_some() => break,
+            None => return Err(JpegError::Truncated),
+        };
+        pos += 1;
+        if marker == EOI {
+            break;
+        }
+        if marker == SOI || (RST0..=RST7).contains(&marker) {
+            continue;
+        }
+        let length = read_u16(&data, pos)?;
+        if length < 2 || pos + length > data.len() {
+            return Err(JpegError::Truncated);
+        }
+        let segment = &data[pos + 2..pos + length];
+        pos += length;
+
+        match marker {
+            DQT => {
+                let mut i = 0;
+                while i < segment.len() {
+                    let (precision, id) = (segment[i] >> 4, (segment[i] & 0x0f) as usize);
+                    let size = if precision == 0 { 64 } else { 128 };
+                    if id > 3 || i + 1 + size > segment.len() {
+                        return Err(JpegError::Malformed("quantization table".to_string()));
+                    }
+                    for k in 0..64 {
+                        quant_tables[id][ZIGZAG[k]] = if precision == 0 {
+                            segment[i + 1 + k] as u16
+                        } else {
+                            (segment[i + 1 + k * 2] as u16) << 8 | segment[i + 2 + k * 2] as u16
+                        };
+                    }
+                    i += 1 + size;
+                }
+            },
+            DHT => {
+                let mut i = 0;
+                while i < segment.len() {
+                    if i + 17 > segment.len() {
+                        return Err(JpegError::Malformed("Huffman table".to_string()));
+                    }
+                    let (class, id) = ((segment[i] >> 4) as usize, (segment[i] & 0x0f) as usize);
+                    let counts = &segment[i + 1..i + 17];
+                    let total: usize = counts.iter().map(|c| *c as usize).sum();
+                    if class > 1 || id > 3 || total > 256 || i + 17 + total > segment.len() {
+                        return Err(JpegError::Malformed("Huffman table".to_string()));
+                    }
+                    tables[class * 4 + id] = Some(HuffmanTable::new(counts, &segment[i + 17..i + 17 + total])?);
+                    i += 17 + total;
+                }
+            },
+            SOF_BASELINE | SOF_EXTENDED | SOF_PROGRESSIVE => {
+                if frame.is_some() {
+                    return Err(JpegError::Malformed("more than one frame".to_string()));
+                }
+                frame = Some(read_frame(segment, marker == SOF_PROGRESSIVE, options)?);
+            },
+            0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                return Err(JpegError::Unsupported(format!("coding process of SOF{}", marker - 0xc0)));
+            },
+            DAC => return Err(JpegError::Unsupported("arithmetic coding".to_string())),
+            DNL => return Err(JpegError::Unsupported("DNL marker".to_string())),
+            DRI => restart_interval = read_u16(segment, 0)?,
+            APP1 => {
+                if orientation == 1 {
+                    orientation = exif_orientation(segment);
+                }
+            },
+            APP0 => jfif |= segment.starts_with(b"JFIF\0"),
+            APP14 => {
+                if segment.len() >= 12 && segment.starts_with(b"Adobe") {
+                    adobe_transform = Some(segment[11]);
+                }
+            },
+            SOS => {
+                let frame = match frame {
+                    Some(ref mut frame) => frame,
+                    None => return Err(JpegError::Malformed("scan before the frame header".to_string())),
+                };
+                let count = *segment.get(0).ok_or(JpegError::Truncated)? as usize;
+                if count == 0 || count > 4 || segment.len() < 4 + count * 2 {
+                    return Err(JpegError::Malformed(format!("scan header with {} components", count)));
+                }
+                let mut components = Vec::with_capacity(count);
+                for i in 0..count {
+                    let (id, selectors) = (segment[1 + i * 2], segment[2 + i * 2]);
+                    let index = match frame.components.iter().position(|c| c.id == id) {
+                        Some(index) => index,
+                        None => return Err(JpegError::Malformed(format!("scan of unknown component {}", id))),
+                    };
+                    let component = &mut frame.components[index];
+                    component.dc_table = (selectors >> 4) as usize & 3;
+                    component.ac_table = (selectors & 0x0f) as usize & 3;
+                    components.push(index);
+                }
+                let rest = &segment[1 + count * 2..];
+                let scan = Scan {
+                    components: components,
+                    start: rest[0] as usize,
+                    end: rest[1] as usize,
+                    high: (rest[2] >> 4) as u32,
+                    low: (rest[2] & 0x0f) as u32,
+                };
+                if scan.end > 63 || scan.start > scan.end || scan.low > 13
+                    || frame.progressive && scan.start > 0 && scan.components.len() != 1 {
+                    return Err(JpegError::Malformed("scan parameters".to_string()));
+                }
+                pos = decode_scan(&data, pos, frame, &scan, &tables, restart_interval)?;
+            },
+            _ => (),
+        }
+    }
+
+    let frame = match frame {
+        Some(frame) => frame,
+        None => return Err(JpegError::Malformed("no frame header".to_string())),
+    };
+
+    // dequantize and transform every block into a plane of samples per component
+    let cosines = idct_cosines();
+    let mut planes = Vec::with_capacity(frame.components.len());
+    let mut coefficients = [0f32; 64];
+    let mut samples = [0u8; 64];
+    for c in &frame.components {
+        let stride = c.blocks_wide * 8;
+        let mut plane = vec![0u8; stride * c.blocks_high * 8];
+        let quant = &quant_tables[c.quant];
+        for by in 0..c.blocks_high {
+            for bx in 0..c.blocks_wide {
+                let block = &c.coefficients[(by * c.blocks_wide + bx) * 64..][..64];
+                for i in 0..64 {
+                    // in f32, since a 16-bit quantizer times a shifted coefficient can overflow i32
+                    coefficients[i] = block[i] as f32 * quant[i] as f32;
+                }
+                idct(&coefficients, &cosines, &mut samples);
+                for y in 0..8 {
+                    let start = (by * 8 + y) * stride + bx * 8;
+                    plane[start..start + 8].copy_from_slice(&samples[y * 8..y * 8 + 8]);
+                }
+            }
+        }
+        planes.push((plane, stride));
+    }
+
+    // Adobe's transform flag decides between RGB and YCbCr (or CMYK and YCCK), else four
+    // components are CMYK and component ids of 'R', 'G' and 'B' mark RGB
+    let ids: Vec<u8> = frame.components.iter().map(|c| c.id).collect();
+    let ycc = match adobe_transform {
+        Some(transform) => transform != 0,
+        None => frame.components.len() != 4 && (jfif || ids != b"RGB"),
+    };
+
+    let mut bmp = Bmp::new(frame.width as u32, frame.height as u32);
+    let mut values = [0f32; 4];
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            for (i, c) in frame.components.iter().enumerate() {
+                let (ref plane, stride) = planes[i];
+                let (sx, sy) = (x * c.h / frame.h_max, y * c.v / frame.v_max);
+                values[i] = plane[sy * stride + sx] as f32;
+            }
+            let clamp = |v: f32| v.round().max(0.0).min(255.0) as u8;
+            let (mut r, mut g, mut b) = match frame.components.len() {
+                1 => (values[0], values[0], values[0]),
+                _ if ycc => (
+                    values[0] + 1.402 * (values[2] - 128.0),
+                    values[0] - 0.344136 * (values[1] - 128.0) - 0.714136 * (values[2] - 128.0),
+                    values[0] + 1.772 * (values[1] - 128.0),
+                ),
+                _ => (values[0], values[1], values[2]),
+            };
+            if frame.components.len() == 4 {
+                if ycc {
+                    // YCCK is transformed from the inks rather than their inverse, so flip
+                    // them back to the inverted values plain Adobe CMYK holds
+                    r = 255.0 - r;
+                    g = 255.0 - g;
+                    b = 255.0 - b;
+                }
+                // Adobe stores CMYK inverted, so these are already (255 - ink) values
+                let k = values[3] / 255.0;
+                r *= k;
+                g *= k;
+                b *= k;
+            }
+            bmp.pixels[x][y] = Pixel {r: clamp(r), g: clamp(g), b: clamp(b), a: 255};
+        }
+    }
+    Ok(orient(bmp, orientation))
+}
+
+#[test]
+fn idct_of_dc_only_block_is_flat() {
+    let mut coefficients = [0f32; 64];
+    coefficients[0] = 80.0;
+    let mut samples = [0u8; 64];
+    idct(&coefficients, &idct_cosines(), &mut samples);
+    // the DC coefficient is eight times the block's mean level
+    assert!(samples.iter().all(|s| *s == 138));
+}
+
+#[test]
+fn exif_orientation_rotates() {
+    // a little endian TIFF header with one IFD entry: orientation 6, rotate 90° clockwise
+    let mut segment = b"Exif\0\0II*\0\x08\0\0\0\x01\0".to_vec();
+    segment.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+    assert_eq!(6, exif_orientation(&segment));
+
+    let mut bmp = Bmp::new(3, 2);
+    bmp.pixels[0][0] = Pixel::red();
+    bmp.pixels[2][1] = Pixel::blue();
+    let rotated = orient(bmp, 6);
+    assert_eq!((2, 3), (rotated.width(), rotated.height()));
+    assert_eq!(Pixel::red(), rotated.pixels[1][0]);
+    assert_eq!(Pixel::blue(), rotated.pixels[0][2]);
+}
+
+#[test]
+fn overfull_huffman_table_is_malformed() {
+    // three codes of length one, when only two fit
+    let mut data = vec![0xff, SOI, 0xff, DHT, 0, 22, 0x00, 3];
+    data.extend_from_slice(&[0; 15]);
+    data.extend_from_slice(&[1, 2, 3, 0xff, EOI]);
+    match read_from(&mut &data[..]) {
+        Err(JpegError::Malformed(_)) => (),
+        other => panic!("expected a malformed Huffman table, got {:?}", other.map(|_| ())),
+    }
+}
+
+/// Decodes a 32x16 fixture encoded from gradients of red across, green down and blue
+/// falling both ways, checking every pixel against them.
+#[cfg(test)]
+fn assert_decodes_gradient(data: &[u8], tolerance: i32) {
+    let bmp = read_from(&mut &data[..]).unwrap();
+    assert_eq!((32, 16), (bmp.width(), bmp.height()));
+    for x in 0..32 {
+        for y in 0..16 {
+            let expected = (40 + x * 5, 60 + y * 8, 200 - x * 3 - y * 2);
+            let p = bmp.pixels[x as usize][y as usize];
+            let diff = (p.r as i32 - expected.0).abs().max((p.g as i32 - expected.1).abs()).max((p.b as i32 - expected.2).abs());
+            assert!(diff <= tolerance, "pixel ({}, {}) is {:?}, expected {:?}", x, y, p, expected);
+        }
+    }
+}
+
+#[test]
+fn decode_baseline() {
+    assert_decodes_gradient(include_bytes!("../fixtures/jpeg/baseline.jpg"), 3);
+    // chroma at half width, and at half width and height with a restart every MCU; the
+    // chroma is upsampled by repeating samples, which costs a few levels on a gradient
+    assert_decodes_gradient(include_bytes!("../fixtures/jpeg/h2v1.jpg"), 6);
+    assert_decodes_gradient(include_bytes!("../fixtures/jpeg/h2v2_restart.jpg"), 10);
+}
+
+#[test]
+fn decode_progressive() {
+    // spectral selection and successive approximation scans over 4:2:0, with restarts
+    let data = include_bytes!("../fixtures/jpeg/progressive.jpg");
+    assert_decodes_gradient(data, 10);
+    // the same coefficients as the baseline encoding, just sent in a different order
+    assert_eq!(read_from(&mut &include_bytes!("../fixtures/jpeg/h2v2_restart.jpg")[..]).unwrap().pixels,
+               read_from(&mut &data[..]).unwrap().pixels);
+}
+
+#[test]
+fn decode_ycck() {
+    // Adobe inverted CMYK with K fading down the image, stored as YCCK
+    let data = include_bytes!("../fixtures/jpeg/ycck.jpg");
+    let bmp = read_from(&mut &data[..]).unwrap();
+    for x in 0..32 {
+        for y in 0..16 {
+            let k = 255 - y * 6;
+            let expected = ((40 + x * 5) * k / 255, (60 + y * 8) * k / 255, (200 - x * 3 - y * 2) * k / 255);
+            let p = bmp.pixels[x as usize][y as usize];
+            let diff = (p.r as i32 - expected.0).abs().max((p.g as i32 - expected.1).abs()).max((p.b as i32 - expected.2).abs());
+            assert!(diff <= 4, "pixel ({}, {}) is {:?}, expected {:?}", x, y, p, expected);
+        }
+    }
+}
@@ -0,0 +1,17 @@
+pub mod bmp;
+pub mod dither;
+pub mod epaper;
+pub mod format;
+pub mod gif;
+pub mod jpeg;
+pub mod pcx;
+pub mod png;
+pub mod pnm;
+pub mod printer;
+pub mod qoi;
+pub mod source;
+pub mod svg;
+pub mod terminal;
+pub mod text;
+pub mod tga;
+pub mod y4m;
@@ -1,90 +1,421 @@
+extern crate dither;
 extern crate k_means;
 
-mod bmp;
-mod dither;
-
-use bmp::{Bmp, Pixel};
-use dither::*;
+use dither::bmp::{Bmp, Pixel};
+use dither::dither::*;
+use dither::epaper;
+use dither::epaper::{BitOrder, EpaperOptions, Panel, PlaneOrder};
+use dither::format;
+use dither::format::{Format, FormatResult};
+use dither::gif;
+use dither::gif::{Animation, Disposal, Frame, GifResult};
+use dither::printer;
+use dither::printer::{PrinterOptions, ZplEncoding};
+use dither::source;
+use dither::source::{Language, Order, Packing, SourceOptions};
+use dither::svg;
+use dither::svg::{Marks, SvgOptions};
+use dither::terminal;
+use dither::text;
+use dither::text::{Glyphs, TextOptions};
+use dither::y4m::{Chroma, Y4mHeader, Y4mReader, Y4mResult, Y4mWriter};
 use std::env::args;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
 
-fn main() {
-    let filename = args().nth(1).unwrap();
-    let output_file = args().nth(2).unwrap();
-    let colors: Vec<Pixel> =
-        match args().nth(3) {
-            Some(value) => {
-                match &*value {
-                    "auto" => {
-                        let mut values = vec![];
-                        let bmp = Bmp::load(&filename).unwrap();
-                        for y in 0..bmp.height() as usize {
-                            for x in 0..bmp.width() as usize {
-                                let p = bmp.pixels[x][y];
-                                let p = vec![
-                                    p.r as f32,
-                                    p.g as f32,
-                                    p.b as f32,
-                                ];
-                                values.push(p);
-                            }
-                        }
-                        let groups = 16;
-                        let iterations = 10;
-                        let auto: Vec<Pixel> = k_means::k_means(&values, groups, 3, iterations, 0.0, 255.0).iter()
-                            .map(|v| Pixel {r: v[0] as u8, g: v[1] as u8, b: v[2] as u8 })
-                            .collect();
-                        println!("Auto colors:");
-                        for p in &auto {
-                            println!("  {:?}", p);
+/// Flags that take the following argument as their value.
+const VALUE_FLAGS: &[&str] = &["--format", "--dots", "--zpl", "--bits", "--planes", "--packing", "--order", "--name", "--marks", "--tolerance", "--strength", "--error-limit"];
+
+/// Flags that stand alone, recorded with an empty value.
+const SWITCH_FLAGS: &[&str] = &["--serpentine", "--clamp", "--invert"];
+
+/// Splits the command line into positional arguments and `--flag value` pairs, which may
+/// appear anywhere after the program name.
+fn parse_args() -> (Vec<String>, Vec<(String, String)>) {
+    let mut positional = vec![];
+    let mut flags = vec![];
+    let mut args = args();
+    while let Some(arg) = args.next() {
+        if VALUE_FLAGS.contains(&arg.as_str()) {
+            let value = args.next().unwrap_or_else(|| panic!("{} needs a value", arg));
+            flags.push((arg, value));
+        } else if SWITCH_FLAGS.contains(&arg.as_str()) {
+            flags.push((arg, String::new()));
+        } else {
+            positional.push(arg);
+        }
+    }
+    (positional, flags)
+}
+
+/// The value of the last `name` flag given.
+fn flag<'a>(flags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    flags.iter().rev().find(|(flag, _)| flag == name).map(|(_, value)| value.as_str())
+}
+
+/// Loads `filename`, or reads the image from stdin when it's `-`.
+fn load(filename: &str) -> FormatResult<Bmp> {
+    if filename == "-" {
+        // the format is sniffed from the magic bytes, so buffer all of stdin first
+        let mut data = Vec::new();
+        ::std::io::stdin().read_to_end(&mut data)?;
+        format::read_from(&mut Cursor::new(data))
+    } else {
+        format::load(filename)
+    }
+}
+
+/// The terminal's width in columns, from `$COLUMNS` when the shell exports it.
+fn terminal_columns() -> u32 {
+    ::std::env::var("COLUMNS").ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
+
+/// Saves to `filename`, or writes the image to stdout in `stdout_format` when it's `-`.
+/// `--preview` draws the image in the terminal with ANSI colored half blocks scaled to fit,
+/// and `--preview=sixel` as sixel graphics.
+fn save(bmp: &Bmp, filename: &str, stdout_format: Format) -> FormatResult<()> {
+    let stdout = ::std::io::stdout();
+    let mut stdout = stdout.lock();
+    match filename {
+        "-" => format::write_to(bmp, &mut stdout, stdout_format)?,
+        "--preview" | "--preview=ansi" => terminal::write_ansi(bmp, &mut stdout, Some(terminal_columns()))?,
+        "--preview=sixel" => terminal::write_sixel(bmp, &mut stdout)?,
+        _ => return format::save(bmp, filename),
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Writes the dithered image in one of the device formats to `filename`, or stdout when it's
+/// `-`.  `--format escpos` gives an ESC/POS `GS v 0` raster and `--format zpl` a ZPL label,
+/// with `--dots N` padding rows to the printer's width and `--zpl z64` compressing the
+/// label.  `--format epaper` gives the packed buffer for the `epd-` palette's panel and
+/// `--format epaper-c` the same as a C array named by `--name`, with `--bits lsb` and
+/// `--planes color-first` changing the layout and `--invert` the polarity.  `--format c`
+/// and `--format rust` give source arrays, packed as `--packing 1|2|4|8|rgb565|rgb332`,
+/// optionally in SSD1306 `--order pages` and named by `--name`, with `--bits` as for
+/// e-paper.  `--format svg` gives plotter layers, one per
+/// color, of `--marks circles` or `--marks strokes`.
+fn export(bmp: &Bmp, filename: &str, device_format: &str, colors: &str, flags: &[(String, String)]) -> ::std::io::Result<()> {
+    let mut writer: Box<dyn Write> = if filename == "-" {
+        Box::new(::std::io::stdout())
+    } else {
+        Box::new(BufWriter::new(File::create(filename)?))
+    };
+    let bit_order = match flag(flags, "--bits") {
+        None | Some("msb") => BitOrder::Msb,
+        Some("lsb") => BitOrder::Lsb,
+        Some(o) => panic!("unrecognized bit order '{}'", o),
+    };
+    match device_format {
+        "escpos" | "zpl" => {
+            let options = PrinterOptions {
+                dot_width: flag(flags, "--dots").map(|dots| dots.parse().expect("--dots needs a number")),
+                zpl_encoding: match flag(flags, "--zpl") {
+                    None | Some("hex") => ZplEncoding::Hex,
+                    Some("z64") => ZplEncoding::Z64,
+                    Some(e) => panic!("unrecognized ZPL encoding '{}'", e),
+                },
+            };
+            if device_format == "escpos" {
+                printer::write_escpos(bmp, &mut writer, &options)?;
+            } else {
+                printer::write_zpl(bmp, &mut writer, &options)?;
+            }
+        },
+        "epaper" | "epaper-c" => {
+            let panel = if colors.starts_with("epd-") { Panel::from_name(&colors[4..]) } else { None };
+            let panel = panel.unwrap_or_else(|| panic!("e-paper output needs an epd- palette"));
+            let options = EpaperOptions {
+                bit_order: bit_order,
+                plane_order: match flag(flags, "--planes") {
+                    None | Some("black-first") => PlaneOrder::BlackFirst,
+                    Some("color-first") => PlaneOrder::ColorFirst,
+                    Some(o) => panic!("unrecognized plane order '{}'", o),
+                },
+                invert: flag(flags, "--invert").is_some(),
+            };
+            if device_format == "epaper" {
+                epaper::write_binary(bmp, &mut writer, panel, &options)?;
+            } else {
+                epaper::write_c_array(bmp, &mut writer, panel, &options, flag(flags, "--name").unwrap_or("image"))?;
+            }
+        },
+        "c" | "rust" => {
+            let options = SourceOptions {
+                language: if device_format == "c" { Language::C } else { Language::Rust },
+                packing: match flag(flags, "--packing").unwrap_or("8") {
+                    "rgb565" => Packing::Rgb565,
+                    "rgb332" => Packing::Rgb332,
+                    bits => Packing::Indexed(bits.parse().expect("--packing needs a bit depth or rgb565 or rgb332")),
+                },
+                order: match flag(flags, "--order") {
+                    None | Some("rows") => Order::Rows,
+                    Some("pages") => Order::Pages,
+                    Some(o) => panic!("unrecognized order '{}'", o),
+                },
+                bit_order: bit_order,
+                name: flag(flags, "--name").unwrap_or("image").to_string(),
+            };
+            source::write_source(bmp, &mut writer, &options)?;
+        },
+        "svg" => {
+            let options = SvgOptions {
+                marks: match flag(flags, "--marks") {
+                    None | Some("circles") => Marks::Circles,
+                    Some("strokes") => Marks::Strokes,
+                    Some(m) => panic!("unrecognized marks '{}'", m),
+                },
+                ..SvgOptions::default()
+            };
+            svg::write_svg(bmp, &mut writer, &options)?;
+        },
+        f => panic!("unrecognized format '{}'", f),
+    }
+    writer.flush()
+}
+
+/// Recognizes the text output targets: `--braille` and `--ascii`, with a `-color` suffix
+/// for ANSI colored text and an optional `=RAMP` of light to dense characters for ASCII.
+fn text_options(target: &str) -> Option<TextOptions> {
+    let (name, ramp) = match target.find('=') {
+        Some(i) => (&target[..i], Some(&target[i + 1..])),
+        None => (target, None),
+    };
+    let glyphs = match (name, ramp) {
+        ("--braille", None) | ("--braille-color", None) => Glyphs::Braille,
+        ("--ascii", _) | ("--ascii-color", _) => Glyphs::Ascii(ramp.unwrap_or(text::DEFAULT_RAMP).to_string()),
+        _ => return None,
+    };
+    Some(TextOptions {
+        glyphs: glyphs,
+        ansi: name.ends_with("-color"),
+        ..TextOptions::default()
+    })
+}
+
+/// The colors named by `value`: a built-in palette, `/` separated hex colors, or `auto`
+/// for the 16 colors k-means finds in `images`.
+fn palette(value: &str, images: &[&Bmp]) -> Vec<Pixel> {
+    match value {
+        "auto" => {
+            let mut values = vec![];
+            for bmp in images {
+                for y in 0..bmp.height() as usize {
+                    for x in 0..bmp.width() as usize {
+                        let p = bmp.pixels[x][y];
+                        if p.is_transparent() {
+                            continue;
                         }
-                        auto
-                    },
-                    "bw" => vec![
-                        Pixel::black(),
-                        Pixel::white(),
-                    ],
-                    "rgb" => vec![
-                        Pixel::red(),
-                        Pixel::green(),
-                        Pixel::blue(),
-                    ],
-                    "basic" => vec![
-                        Pixel::red(),
-                        Pixel::green(),
-                        Pixel::blue(),
-                        Pixel::cyan(),
-                        Pixel::magenta(),
-                        Pixel::yellow(),
-                        Pixel::white(),
-                        Pixel::black(),
-                    ],
-                    _ => value.split('/').map(Pixel::parse).collect(),
+                        let p = vec![
+                            p.r as f32,
+                            p.g as f32,
+                            p.b as f32,
+                        ];
+                        values.push(p);
+                    }
                 }
             }
-            _ => panic!("specify colors"),
-        };
-    match args().nth(4) {
-        Some(action) => {
-            let mut bmp = Bmp::load(&filename).unwrap();
-            println!("Loaded bitmap: {:?}", bmp);
-            let delegate = match action.as_str() {
-                "closest" => closest_matrix_dither,
-                "diffuse" => diffuse_matrix_dither,
-                "floyd" => floyd_matrix_dither,
-                "ffloyd" => false_floyd_matrix_dither,
-                "jarvis" => jjn_matrix_dither,
-                "stucki" => stucki_matrix_dither,
-                "atkinson" => atkinson_matrix_dither,
-                "burkes" => burkes_matrix_dither,
-                "sierra" => sierra_matrix_dither,
-                "sierra2" => sierra2_matrix_dither,
-                "sierra_lite" => sierra_lite_matrix_dither,
-                "bayer4" => bayer_4x4,
-                "bayer8" => bayer_8x8,
-                a => panic!("unrecognized action '{}'", a),
+            let groups = 16;
+            let iterations = 10;
+            let auto: Vec<Pixel> = k_means::k_means(&values, groups, 3, iterations, 0.0, 255.0).iter()
+                .map(|v| Pixel {r: v[0] as u8, g: v[1] as u8, b: v[2] as u8, a: 255 })
+                .collect();
+            eprintln!("Auto colors:");
+            for p in &auto {
+                eprintln!("  {:?}", p);
+            }
+            auto
+        },
+        "bw" => vec![
+            Pixel::black(),
+            Pixel::white(),
+        ],
+        "rgb" => vec![
+            Pixel::red(),
+            Pixel::green(),
+            Pixel::blue(),
+        ],
+        "basic" => vec![
+            Pixel::red(),
+            Pixel::green(),
+            Pixel::blue(),
+            Pixel::cyan(),
+            Pixel::magenta(),
+            Pixel::yellow(),
+            Pixel::white(),
+            Pixel::black(),
+        ],
+        // the e-paper panels' own colors
+        panel if panel.starts_with("epd-") && Panel::from_name(&panel[4..]).is_some() => {
+            Panel::from_name(&panel[4..]).unwrap().palette()
+        },
+        _ => value.split('/').map(Pixel::parse).collect(),
+    }
+}
+
+/// The dither named `name`, ordered dithering with a Bayer matrix such as `bayer16`, or
+/// error diffusion with `name` as a kernel such as `"- * 7; 3 5 1 / 16"`.
+///
+/// Error diffusion scans alternate rows in opposite directions with `--serpentine`, passes
+/// on only part of the error with `--strength PERCENT`, keeps to the palette's gamut with
+/// `--clamp` and caps the error per channel with `--error-limit LEVELS`.
+fn action(name: &str, flags: &[(String, String)]) -> Box<dyn Fn(&mut Bmp, &Vec<Pixel>)> {
+    let kernel = match Kernel::named(name) {
+        Some(kernel) => kernel,
+        None if name.starts_with("bayer") => {
+            let size = match name[5..].parse::<usize>() {
+                Ok(size) if size.is_power_of_two() && (2..=64).contains(&size) => size,
+                _ => panic!("unrecognized action '{}': Bayer matrices are bayer2 to bayer64", name),
             };
+            return Box::new(move |bmp: &mut Bmp, colors: &Vec<Pixel>| bayer_dither(bmp, size, colors));
+        },
+        None => match name.parse() {
+            Ok(kernel) => kernel,
+            Err(err) => panic!("unrecognized action '{}': {}", name, err),
+        },
+    };
+    let options = DiffusionOptions {
+        serpentine: flag(flags, "--serpentine").is_some(),
+        strength: match flag(flags, "--strength") {
+            Some(strength) => match strength.trim_end_matches('%').parse() {
+                Ok(strength) if strength <= 100 => strength,
+                _ => panic!("--strength needs a percentage from 0 to 100"),
+            },
+            None => 100,
+        },
+        clamp: flag(flags, "--clamp").is_some(),
+        error_limit: flag(flags, "--error-limit").map(|limit| limit.parse().expect("--error-limit needs a number from 0 to 255")),
+    };
+    Box::new(move |bmp: &mut Bmp, colors: &Vec<Pixel>| matrix_dither_with(bmp, &kernel, colors, &options))
+}
+
+/// How far a pixel may drift before a sequence dithers it afresh: `--tolerance N`, 8 by
+/// default, or `--tolerance none` to dither every frame independently.
+fn tolerance(flags: &[(String, String)]) -> Option<u8> {
+    match flag(flags, "--tolerance") {
+        Some("none") => None,
+        Some(tolerance) => Some(tolerance.parse().expect("--tolerance needs a number or none")),
+        None => Some(8),
+    }
+}
+
+/// Dithers a YUV4MPEG2 video frame by frame to `output_file`, or stdout when it's `-`.
+/// Pixels that stay within the `tolerance` of the color they were last dithered from keep
+/// their dithered color so still regions don't shimmer.
+fn dither_sequence(filename: &str, output_file: &str, colors: &str, delegate: &dyn Fn(&mut Bmp, &Vec<Pixel>), flags: &[(String, String)]) -> Y4mResult<()> {
+    let input: Box<dyn Read> = if filename == "-" {
+        Box::new(::std::io::stdin())
+    } else {
+        Box::new(BufReader::new(File::open(filename)?))
+    };
+    let output: Box<dyn Write> = if output_file == "-" {
+        Box::new(::std::io::stdout())
+    } else {
+        Box::new(BufWriter::new(File::create(output_file)?))
+    };
+    let mut reader = Y4mReader::new(input)?;
+    // full chroma, so the dithered pattern isn't smeared
+    let header = Y4mHeader { chroma: Chroma::C444, ..reader.header.clone() };
+    let mut writer = Y4mWriter::new(output, &header)?;
+    let mut sequence = Sequence::new(tolerance(flags));
+    let mut colors_for_frames = None;
+    let mut count = 0;
+    while let Some(mut frame) = reader.read_frame()? {
+        // palettes like auto come from the first frame
+        let colors = colors_for_frames.get_or_insert_with(|| palette(colors, &[&frame])).clone();
+        sequence.dither(&mut frame, &colors, delegate);
+        writer.write_frame(&frame)?;
+        count += 1;
+    }
+    writer.flush()?;
+    eprintln!("Dithered {} frames", count);
+    Ok(())
+}
+
+/// Dithers every frame of an animated GIF with one palette, found from all the frames for
+/// `auto`, and writes them with the original timings.  Frames are dithered as the screen
+/// looks while they're shown, so each is written whole and cleared before the next.
+/// They're dithered independently unless `--tolerance` asks for still regions to be kept
+/// as they are for video.
+fn dither_animation(filename: &str, output_file: &str, colors: &str, delegate: &dyn Fn(&mut Bmp, &Vec<Pixel>), flags: &[(String, String)]) -> GifResult<()> {
+    let animation = gif::load_animation(filename)?;
+    let screens = animation.composite();
+    let colors = palette(colors, &screens.iter().collect::<Vec<_>>());
+    // slow fades would freeze under the default tolerance, so only apply one when asked
+    let tolerance = if flag(flags, "--tolerance").is_some() { tolerance(flags) } else { None };
+    let mut sequence = Sequence::new(tolerance);
+    let mut output = Animation::new(animation.width, animation.height);
+    output.palette = Some(colors.clone());
+    output.loop_count = animation.loop_count;
+    for (mut screen, frame) in screens.into_iter().zip(animation.frames.iter()) {
+        sequence.dither(&mut screen, &colors, delegate);
+        output.frames.push(Frame {
+            delay: frame.delay,
+            disposal: Disposal::Background,
+            ..Frame::new(screen)
+        });
+    }
+    eprintln!("Dithered {} frames", output.frames.len());
+    if output_file == "-" {
+        let stdout = ::std::io::stdout();
+        let mut stdout = stdout.lock();
+        gif::write_animation_to(&output, &mut stdout)?;
+        stdout.flush()?;
+        Ok(())
+    } else {
+        gif::save_animation(&output, output_file)
+    }
+}
+
+fn main() {
+    let (args, flags) = parse_args();
+    let filename = args[1].clone();
+    let output_file = args[2].clone();
+    if filename.ends_with(".y4m") {
+        let colors = args.get(3).unwrap_or_else(|| panic!("specify colors"));
+        let delegate = action(args.get(4).unwrap_or_else(|| panic!("specify action")), &flags);
+        dither_sequence(&filename, &output_file, colors, &*delegate, &flags).unwrap();
+        return;
+    }
+    if filename.ends_with(".gif") && (output_file.ends_with(".gif") || output_file == "-") && flag(&flags, "--format").is_none() {
+        let colors = args.get(3).unwrap_or_else(|| panic!("specify colors"));
+        let delegate = action(args.get(4).unwrap_or_else(|| panic!("specify action")), &flags);
+        dither_animation(&filename, &output_file, colors, &*delegate, &flags).unwrap();
+        return;
+    }
+    let mut bmp = load(&filename).unwrap();
+    eprintln!("Loaded bitmap: {:?}", bmp);
+    let text_options = text_options(&output_file);
+    if text_options.is_some() {
+        // each character covers a cell of pixels, so fit the image to the terminal first
+        bmp = terminal::downscale(&bmp, terminal_columns() * text::CELL_WIDTH as u32);
+    }
+    let colors = palette(args.get(3).unwrap_or_else(|| panic!("specify colors")), &[&bmp]);
+    // text is drawn from a 1 bit image, whatever the palette
+    let colors = if text_options.is_some() { vec![Pixel::black(), Pixel::white()] } else { colors };
+    match args.get(4) {
+        Some(name) => {
+            let delegate = action(name, &flags);
+            if let Some(ref options) = text_options {
+                let source = bmp.clone();
+                delegate(&mut bmp, &colors);
+                let stdout = ::std::io::stdout();
+                text::write_text(&bmp, Some(&source), &mut stdout.lock(), options).unwrap();
+                return;
+            }
             delegate(&mut bmp, &colors);
-            bmp.save(&output_file).unwrap();
+            if let Some(device_format) = flag(&flags, "--format") {
+                export(&bmp, &output_file, device_format, &args[3], &flags).unwrap();
+                return;
+            }
+            // output to stdout keeps the input's format, or is a BMP when that's read only
+            let stdout_format = Format::from_extension(&filename)
+                .filter(|format| format.is_writable())
+                .unwrap_or(Format::Bmp);
+            save(&bmp, &output_file, stdout_format).unwrap();
         },
         _ => panic!("specify action"),
     }
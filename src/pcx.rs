@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+extern crate byteorder;
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp::{Bmp, LoadOptions, Pixel};
+
+const MANUFACTURER: u8 = 0x0a;
+/// Version 3.0, the first to store a 256 color palette at the end of the file.
+const VERSION: u8 = 5;
+const ENCODING_RLE: u8 = 1;
+/// Marks the 256 color palette that follows the image data.
+const VGA_PALETTE: u8 = 0x0c;
+
+//-------------------------------------------------------------------- PcxError
+
+#[derive(Debug)]
+pub enum PcxError {
+    /// The file didn't start with the ZSoft manufacturer byte.
+    BadMagic(u8),
+    /// The header held invalid values or the 256 color palette was missing.
+    Malformed(String),
+    /// The combination of bits per pixel and color planes isn't supported.
+    Unsupported(u8, u8),
+    /// The image dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The file ended before the image data did.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for PcxError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            PcxError::BadMagic(b) => write!(formatter, "expected PCX manufacturer byte 0x0a, found 0x{:02x}", b),
+            PcxError::Malformed(ref message) => write!(formatter, "malformed PCX: {}", message),
+            PcxError::Unsupported(bpp, planes) => write!(formatter, "unsupported PCX with {} bits per pixel in {} planes", bpp, planes),
+            PcxError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            PcxError::Truncated => write!(formatter, "unexpected end of file"),
+            PcxError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for PcxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PcxError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for PcxError {
+    fn from(err: ::std::io::Error) -> PcxError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => PcxError::Truncated,
+            _ => PcxError::Io(err),
+        }
+    }
+}
+
+pub type PcxResult<T> = Result<T, PcxError>;
+
+//---------------------------------------------------------------------- Reader
+
+pub fn load(path_str: &str) -> PcxResult<Bmp> {
+    let file = File::open(Path::new(path_str))?;
+    read_from(&mut ::std::io::BufReader::new(file))
+}
+
+pub fn read_from<R: Read>(reader: &mut R) -> PcxResult<Bmp> {
+    read_from_with(reader, &LoadOptions::default())
+}
+
+/// Reads 1 bit images in 1 to 4 planes, 2, 4 and 8 bit palette images and 24 or 32 bit
+/// images stored as 8 bit planes.
+pub fn read_from_with<R: Read>(reader: &mut R, options: &LoadOptions) -> PcxResult<Bmp> {
+    let mut header = [0u8; 128];
+    reader.read_exact(&mut header)?;
+    if header[0] != MANUFACTURER {
+        return Err(PcxError::BadMagic(header[0]));
+    }
+    let encoded = header[2] == ENCODING_RLE;
+    let bpp = header[3];
+    let x_min = header[4] as u32 | (header[5] as u32) << 8;
+    let y_min = header[6] as u32 | (header[7] as u32) << 8;
+    let x_max = header[8] as u32 | (header[9] as u32) << 8;
+    let y_max = header[10] as u32 | (header[11] as u32) << 8;
+    let planes = header[65];
+    let bytes_per_line = header[66] as usize | (header[67] as usize) << 8;
+    if x_max < x_min || y_max < y_min {
+        return Err(PcxError::Malformed(format!("window ({}, {}) to ({}, {})", x_min, y_min, x_max, y_max)));
+    }
+    let width = x_max - x_min + 1;
+    let height = y_max - y_min + 1;
+    match (bpp, planes) {
+        (1, 1..=4) | (2, 1) | (4, 1) | (8, 1) | (8, 3) | (8, 4) => (),
+        _ => return Err(PcxError::Unsupported(bpp, planes)),
+    }
+    if !options.allows(width, height) {
+        return Err(PcxError::TooLarge(width, height));
+    }
+    if bytes_per_line * 8 < width as usize * bpp as usize {
+        return Err(PcxError::Malformed(format!("{} bytes per line for {} pixels", bytes_per_line, width)));
+    }
+
+    // runs are allowed to cross lines and planes, so decode the whole image in one go
+    let line_size = bytes_per_line * planes as usize;
+    let size = line_size * height as usize;
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let b = reader.read_u8()?;
+        if encoded && b >= 0xc0 {
+            let value = reader.read_u8()?;
+            for _ in 0..b & 0x3f {
+                data.push(value);
+            }
+        } else {
+            data.push(b);
+        }
+    }
+    data.truncate(size);
+
+    let indexed = planes == 1 || bpp == 1;
+    let mut palette: Vec<Pixel> = header[16..64].chunks(3).map(|c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}).collect();
+    if bpp == 8 && planes == 1 {
+        let mut marker = [0u8; 1];
+        let mut vga = [0u8; 768];
+        // some files omit the palette for grayscale images
+        palette = if reader.read(&mut marker)? == 1 && marker[0] == VGA_PALETTE {
+            reader.read_exact(&mut vga)?;
+            vga.chunks(3).map(|c| Pixel {r: c[0], g: c[1], b: c[2], a: 255}).collect()
+        } else {
+            (0..256).map(|v| Pixel {r: v as u8, g: v as u8, b: v as u8, a: 255}).collect()
+        };
+    } else if bpp == 1 && planes == 1 && palette.iter().take(2).all(|p| *p == Pixel::black()) {
+        // plenty of monochrome files leave the header palette empty
+        palette = vec![Pixel::black(), Pixel::white()];
+    }
+    palette.truncate(1 << (bpp * if indexed { planes } else { 1 }).min(8));
+
+    let mut bmp = Bmp::new(width, height);
+    for y in 0..height as usize {
+        let line = &data[y * line_size..(y + 1) * line_size];
+        for x in 0..width as usize {
+            bmp.pixels[x][y] = if indexed {
+                let mut index = 0;
+                for plane in 0..planes as usize {
+                    let bit = x * bpp as usize;
+                    let byte = line[plane * bytes_per_line + bit / 8];
+                    let value = (byte >> (8 - bpp as usize - bit % 8)) & ((1u16 << bpp) - 1) as u8;
+                    index |= (value as usize) << (plane * bpp as usize);
+                }
+                match palette.get(index) {
+                    Some(p) => *p,
+                    None => Pixel::black(),
+                }
+            } else {
+                let sample = |plane: usize| line[plane * bytes_per_line + x];
+                Pixel {r: sample(0), g: sample(1), b: sample(2), a: if planes == 4 { sample(3) } else { 255 }}
+            };
+        }
+    }
+    if indexed {
+        bmp.palette = Some(palette);
+    }
+    Ok(bmp)
+}
+
+//---------------------------------------------------------------------- Writer
+
+/// Appends `line` run length encoded.  Runs never cross lines, as older readers expect.
+fn rle_encode(line: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < line.len() {
+        let mut run = 1;
+        while i + run < line.len() && run < 63 && line[i + run] == line[i] {
+            run += 1;
+        }
+        // single bytes below 0xc0 can be stored as is
+        if run > 1 || line[i] >= 0xc0 {
+            out.push(0xc0 | run as u8);
+        }
+        out.push(line[i]);
+        i += run;
+    }
+}
+
+pub fn save(bmp: &Bmp, path_str: &str) -> PcxResult<()> {
+    let file = File::create(Path::new(path_str))?;
+    let mut file = ::std::io::BufWriter::new(file);
+    write_to(bmp, &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes the dithering palette as 16 color planar EGA data when it fits and as an 8 bit
+/// image with a 256 color palette otherwise.  Images with more colors are written as 24
+/// bit planes, or 32 when they have alpha.
+pub fn write_to<W: Write>(bmp: &Bmp, writer: &mut W) -> PcxResult<()> {
+    if bmp.width() == 0 || bmp.height() == 0 || bmp.width() > 0x10000 || bmp.height() > 0x10000 {
+        return Err(PcxError::TooLarge(bmp.width(), bmp.height()));
+    }
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    // PCX has no transparency, so only opaque palettes can be used
+    let indexed = bmp.indexed(false);
+    let (bpp, planes) = match indexed {
+        Some(ref indexed) if indexed.palette.len() <= 16 => (1, 4),
+        Some(_) => (8, 1),
+        None if bmp.has_alpha() => (8, 4),
+        None => (8, 3),
+    };
+    // lines are padded to an even number of bytes
+    let bytes_per_line = ((width * bpp + 7) / 8 + 1) & !1;
+
+    let mut header = [0u8; 128];
+    header[0] = MANUFACTURER;
+    header[1] = VERSION;
+    header[2] = ENCODING_RLE;
+    header[3] = bpp as u8;
+    (&mut header[8..]).write_u16::<LittleEndian>((width - 1) as u16)?;
+    (&mut header[10..]).write_u16::<LittleEndian>((height - 1) as u16)?;
+    (&mut header[12..]).write_u16::<LittleEndian>(72)?;
+    (&mut header[14..]).write_u16::<LittleEndian>(72)?;
+    if let Some(ref indexed) = indexed {
+        if planes == 4 {
+            for (i, p) in indexed.palette.iter().enumerate() {
+                header[16 + i * 3..19 + i * 3].copy_from_slice(&[p.r, p.g, p.b]);
+            }
+        }
+    }
+    header[65] = planes as u8;
+    (&mut header[66..]).write_u16::<LittleEndian>(bytes_per_line as u16)?;
+    // color, rather than grayscale, palette
+    header[68] = 1;
+    writer.write_all(&header)?;
+
+    let mut data = Vec::new();
+    let mut line = vec![0u8; bytes_per_line * planes];
+    for y in 0..height {
+        for b in line.iter_mut() {
+            *b = 0;
+        }
+        for x in 0..width {
+            match indexed {
+                Some(ref indexed) if bpp == 1 => {
+                    let index = indexed.indices[y * width + x];
+                    for plane in 0..planes {
+                        if index & (1 << plane) != 0 {
+                            line[plane * bytes_per_line + x / 8] |= 0x80 >> (x % 8);
+                        }
+                    }
+                },
+                Some(ref indexed) => line[x] = indexed.indices[y * width + x],
+                None => {
+                    let p = bmp.pixels[x][y];
+                    for (plane, value) in [p.r, p.g, p.b, p.a].iter().take(planes).enumerate() {
+                        line[plane * bytes_per_line + x] = *value;
+                    }
+                },
+            }
+        }
+        for plane in line.chunks(bytes_per_line) {
+            rle_encode(plane, &mut data);
+        }
+    }
+    writer.write_all(&data)?;
+
+    if let Some(ref indexed) = indexed {
+        if bpp == 8 {
+            writer.write_u8(VGA_PALETTE)?;
+            let mut vga = [0u8; 768];
+            for (i, p) in indexed.palette.iter().enumerate() {
+                vga[i * 3..i * 3 + 3].copy_from_slice(&[p.r, p.g, p.b]);
+            }
+            writer.write_all(&vga)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn pcx_round_trip() {
+    let mut color = Bmp::new(5, 3);
+    for x in 0..5 {
+        color.pixels[x][2] = Pixel {r: 0xc7, g: x as u8 * 60, b: 9, a: 255};
+    }
+    let mut ega = Bmp::new(13, 2);
+    ega.palette = Some(vec![Pixel::black(), Pixel::white(), Pixel::red(), Pixel::cyan(), Pixel::yellow()]);
+    ega.pixels[12][1] = Pixel::yellow();
+    ega.pixels[4][0] = Pixel::cyan();
+    let mut vga = Bmp::new(3, 3);
+    let palette: Vec<Pixel> = (0..40).map(|i| Pixel {r: i * 6, g: 0xff, b: 0xc0, a: 255}).collect();
+    for x in 0..3 {
+        for y in 0..3 {
+            vga.pixels[x][y] = palette[x * 3 + y];
+        }
+    }
+    vga.palette = Some(palette);
+
+    for bmp in &[color, ega, vga] {
+        let mut data = Vec::new();
+        write_to(bmp, &mut data).unwrap();
+        let loaded = read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+        for x in 0..bmp.width() as usize {
+            for y in 0..bmp.height() as usize {
+                assert_eq!(bmp.pixels[x][y], loaded.pixels[x][y]);
+            }
+        }
+    }
+}
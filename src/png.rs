@@ -0,0 +1,536 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+extern crate byteorder;
+use self::byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+extern crate flate2;
+use self::flate2::Compression;
+use self::flate2::Crc;
+use self::flate2::read::ZlibDecoder;
+use self::flate2::write::ZlibEncoder;
+
+use bmp::{Bmp, LoadOptions, Pixel};
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+const GRAYSCALE: u8 = 0;
+const TRUECOLOR: u8 = 2;
+const INDEXED: u8 = 3;
+const GRAYSCALE_ALPHA: u8 = 4;
+const TRUECOLOR_ALPHA: u8 = 6;
+
+/// The Adam7 passes as (x start, y start, x step, y step).
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+//-------------------------------------------------------------------- PngError
+
+#[derive(Debug)]
+pub enum PngError {
+    /// The file didn't start with the 8 byte PNG signature.
+    BadSignature,
+    /// A chunk was missing, out of place, failed its CRC or held invalid values.
+    Malformed(String),
+    /// The bit depth, color type, interlace method or a critical chunk isn't supported.
+    Unsupported(String),
+    /// The image dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The file or the compressed image data ended early.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for PngError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            PngError::BadSignature => write!(formatter, "missing PNG signature"),
+            PngError::Malformed(ref message) => write!(formatter, "malformed PNG: {}", message),
+            PngError::Unsupported(ref message) => write!(formatter, "unsupported PNG: {}", message),
+            PngError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            PngError::Truncated => write!(formatter, "unexpected end of file"),
+            PngError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for PngError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PngError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for PngError {
+    fn from(err: ::std::io::Error) -> PngError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => PngError::Truncated,
+            _ => PngError::Io(err),
+        }
+    }
+}
+
+pub type PngResult<T> = Result<T, PngError>;
+
+//---------------------------------------------------------------------- Header
+
+struct Header {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> PngResult<Header> {
+        if data.len() != 13 {
+            return Err(PngError::Malformed(format!("IHDR is {} bytes instead of 13", data.len())));
+        }
+        let header = Header {
+            width: BigEndian::read_u32(&data[0..]),
+            height: BigEndian::read_u32(&data[4..]),
+            bit_depth: data[8],
+            color_type: data[9],
+            interlaced: data[12] == 1,
+        };
+        let valid_depth = match header.color_type {
+            GRAYSCALE => [1, 2, 4, 8, 16].contains(&header.bit_depth),
+            INDEXED => [1, 2, 4, 8].contains(&header.bit_depth),
+            TRUECOLOR | GRAYSCALE_ALPHA | TRUECOLOR_ALPHA => [8, 16].contains(&header.bit_depth),
+            c => return Err(PngError::Unsupported(format!("color type {}", c))),
+        };
+        if !valid_depth {
+            return Err(PngError::Unsupported(format!("bit depth {} for color type {}", header.bit_depth, header.color_type)));
+        }
+        if data[10] != 0 || data[11] != 0 || data[12] > 1 {
+            return Err(PngError::Unsupported(format!("compression {}, filter {} or interlace {} method", data[10], data[11], data[12])));
+        }
+        if header.width == 0 || header.height == 0 {
+            return Err(PngError::Malformed("zero width or height".to_string()));
+        }
+        Ok(header)
+    }
+    fn channels(&self) -> usize {
+        match self.color_type {
+            GRAYSCALE | INDEXED => 1,
+            GRAYSCALE_ALPHA => 2,
+            TRUECOLOR => 3,
+            _ => 4,
+        }
+    }
+    fn bits_per_pixel(&self) -> usize {
+        self.channels() * self.bit_depth as usize
+    }
+    /// The byte distance filters look back by; at least 1 even for sub-byte pixels.
+    fn filter_distance(&self) -> usize {
+        ((self.bits_per_pixel() + 7) / 8).max(1)
+    }
+    fn row_size(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel() + 7) / 8
+    }
+    /// The (x start, y start, x step, y step, width, height) of each pass holding pixels.
+    fn passes(&self) -> Vec<(usize, usize, usize, usize, usize, usize)> {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        if !self.interlaced {
+            return vec![(0, 0, 1, 1, width, height)];
+        }
+        ADAM7.iter()
+            .map(|&(x0, y0, dx, dy)| (x0, y0, dx, dy, (width + dx - 1 - x0) / dx, (height + dy - 1 - y0) / dy))
+            .filter(|&(_, _, _, _, w, h)| w > 0 && h > 0)
+            .collect()
+    }
+}
+
+//---------------------------------------------------------------------- Reader
+
+fn read_chunk<R: Read>(reader: &mut R) -> PngResult<([u8; 4], Vec<u8>)> {
+    let length = reader.read_u32::<BigEndian>()?;
+    if length > 0x7fff_ffff {
+        return Err(PngError::Malformed(format!("chunk length {}", length)));
+    }
+    let mut kind = [0u8; 4];
+    reader.read_exact(&mut kind)?;
+    // read through `take` so that a bogus length can't allocate more than the file holds
+    let mut data = Vec::new();
+    reader.take(length as u64).read_to_end(&mut data)?;
+    if data.len() != length as usize {
+        return Err(PngError::Truncated);
+    }
+    let crc = reader.read_u32::<BigEndian>()?;
+    let mut expected = Crc::new();
+    expected.update(&kind);
+    expected.update(&data);
+    if crc != expected.sum() {
+        return Err(PngError::Malformed(format!("CRC mismatch in {} chunk", String::from_utf8_lossy(&kind))));
+    }
+    Ok((kind, data))
+}
+
+/// Reverses the per row filters, returning the rows without their filter bytes.
+fn unfilter(data: &[u8], row_size: usize, rows: usize, distance: usize) -> PngResult<Vec<u8>> {
+    let mut out = vec![0u8; row_size * rows];
+    for y in 0..rows {
+        let filter = data[y * (row_size + 1)];
+        let raw = &data[y * (row_size + 1) + 1..(y + 1) * (row_size + 1)];
+        let (previous, current) = out.split_at_mut(y * row_size);
+        let prior = if y == 0 { None } else { Some(&previous[(y - 1) * row_size..]) };
+        let current = &mut current[..row_size];
+        for i in 0..row_size {
+            let a = if i >= distance { current[i - distance] } else { 0 };
+            let b = prior.map_or(0, |p| p[i]);
+            let c = if i >= distance { prior.map_or(0, |p| p[i - distance]) } else { 0 };
+            current[i] = raw[i].wrapping_add(match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                f => return Err(PngError::Malformed(format!("filter type {}", f))),
+            });
+        }
+    }
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reads the `index`th sample of a row packed at `bit_depth` bits per sample.
+fn sample(row: &[u8], index: usize, bit_depth: u8) -> u16 {
+    match bit_depth {
+        8 => row[index] as u16,
+        16 => BigEndian::read_u16(&row[index * 2..]),
+        d => {
+            let bit = index * d as usize;
+            let shift = 8 - d as usize - bit % 8;
+            ((row[bit / 8] >> shift) & ((1u16 << d) - 1) as u8) as u16
+        },
+    }
+}
+
+pub fn load(path_str: &str) -> PngResult<Bmp> {
+    let file = File::open(Path::new(path_str))?;
+    read_from(&mut ::std::io::BufReader::new(file))
+}
+
+pub fn read_from<R: Read>(reader: &mut R) -> PngResult<Bmp> {
+    read_from_with(reader, &LoadOptions::default())
+}
+
+pub fn read_from_with<R: Read>(reader: &mut R, options: &LoadOptions) -> PngResult<Bmp> {
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(PngError::BadSignature);
+    }
+
+    let (kind, data) = read_chunk(reader)?;
+    if &kind != b"IHDR" {
+        return Err(PngError::Malformed("first chunk isn't IHDR".to_string()));
+    }
+    let header = Header::parse(&data)?;
+    if !options.allows(header.width, header.height) {
+        return Err(PngError::TooLarge(header.width, header.height));
+    }
+
+    let mut palette: Vec<Pixel> = Vec::new();
+    let mut transparency = Vec::new();
+    let mut compressed = Vec::new();
+    loop {
+        let (kind, data) = read_chunk(reader)?;
+        match &kind {
+            b"PLTE" => {
+                palette = data.chunks(3)
+                    .filter(|c| c.len() == 3)
+                    .map(|c| Pixel {r: c[0], g: c[1], b: c[2], a: 255})
+                    .collect();
+            },
+            b"tRNS" => transparency = data,
+            b"IDAT" => compressed.extend_from_slice(&data),
+            b"IEND" => break,
+            k if k[0] & 0x20 == 0 => {
+                // the upper case first letter marks a critical chunk we can't ignore
+                return Err(PngError::Unsupported(format!("critical chunk {}", String::from_utf8_lossy(k))));
+            },
+            _ => (),
+        }
+    }
+    if header.color_type == INDEXED {
+        if palette.is_empty() {
+            return Err(PngError::Malformed("indexed image without a PLTE chunk".to_string()));
+        }
+        for (p, a) in palette.iter_mut().zip(transparency.iter()) {
+            p.a = *a;
+        }
+    }
+
+    // decompress no more than the image needs, which also guards against zlib bombs
+    let passes = header.passes();
+    let expected: usize = passes.iter().map(|&(_, _, _, _, w, h)| (header.row_size(w) + 1) * h).sum();
+    let mut raw = Vec::with_capacity(expected);
+    ZlibDecoder::new(&compressed[..]).take(expected as u64).read_to_end(&mut raw)?;
+    if raw.len() != expected {
+        return Err(PngError::Truncated);
+    }
+
+    // the transparent color for gray and truecolor images, at the image's own bit depth
+    let transparent_key: Option<Vec<u16>> = match header.color_type {
+        GRAYSCALE if transparency.len() >= 2 => Some(vec![BigEndian::read_u16(&transparency)]),
+        TRUECOLOR if transparency.len() >= 6 => Some((0..3).map(|i| BigEndian::read_u16(&transparency[i * 2..])).collect()),
+        _ => None,
+    };
+    let max = (1u32 << header.bit_depth) - 1;
+    let scale = |v: u16| ((v as u32 * 255 + max / 2) / max) as u8;
+
+    let mut bmp = Bmp::new(header.width, header.height);
+    let channels = header.channels();
+    let mut samples = vec![0u16; channels];
+    let mut offset = 0;
+    for (x0, y0, dx, dy, pass_width, pass_height) in passes {
+        let row_size = header.row_size(pass_width);
+        let pass_size = (row_size + 1) * pass_height;
+        let rows = unfilter(&raw[offset..offset + pass_size], row_size, pass_height, header.filter_distance())?;
+        offset += pass_size;
+
+        for py in 0..pass_height {
+            let row = &rows[py * row_size..(py + 1) * row_size];
+            for px in 0..pass_width {
+                for (c, s) in samples.iter_mut().enumerate() {
+                    *s = sample(row, px * channels + c, header.bit_depth);
+                }
+                let transparent = transparent_key.as_ref().map_or(false, |key| &key[..] == &samples[..]);
+                let pixel = match header.color_type {
+                    INDEXED => match palette.get(samples[0] as usize) {
+                        Some(p) => *p,
+                        None => return Err(PngError::Malformed(format!("palette index {} out of range", samples[0]))),
+                    },
+                    GRAYSCALE => { let v = scale(samples[0]); Pixel {r: v, g: v, b: v, a: 255} },
+                    GRAYSCALE_ALPHA => { let v = scale(samples[0]); Pixel {r: v, g: v, b: v, a: scale(samples[1])} },
+                    TRUECOLOR => Pixel {r: scale(samples[0]), g: scale(samples[1]), b: scale(samples[2]), a: 255},
+                    _ => Pixel {r: scale(samples[0]), g: scale(samples[1]), b: scale(samples[2]), a: scale(samples[3])},
+                };
+                bmp.pixels[x0 + px * dx][y0 + py * dy] = if transparent { Pixel { a: 0, ..pixel } } else { pixel };
+            }
+        }
+    }
+    if header.color_type == INDEXED {
+        bmp.palette = Some(palette);
+    }
+    Ok(bmp)
+}
+
+//---------------------------------------------------------------------- Writer
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> PngResult<()> {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_u32::<BigEndian>(data.len() as u32)?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    writer.write_u32::<BigEndian>(crc.sum())?;
+    Ok(())
+}
+
+/// Applies `filter` to `current`, appending the filter byte and the filtered row to `out`.
+fn filter_row(filter: u8, current: &[u8], prior: &[u8], distance: usize, out: &mut Vec<u8>) {
+    out.push(filter);
+    for i in 0..current.len() {
+        let a = if i >= distance { current[i - distance] } else { 0 };
+        let b = prior[i];
+        let c = if i >= distance { prior[i - distance] } else { 0 };
+        out.push(current[i].wrapping_sub(match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c),
+        }));
+    }
+}
+
+pub fn save(bmp: &Bmp, path_str: &str) -> PngResult<()> {
+    let file = File::create(Path::new(path_str))?;
+    let mut file = ::std::io::BufWriter::new(file);
+    write_to(bmp, &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes an indexed image at the smallest bit depth that holds the dithering palette when
+/// every pixel is found in it, and an 8 bit truecolor image otherwise.
+pub fn write_to<W: Write>(bmp: &Bmp, writer: &mut W) -> PngResult<()> {
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let indexed = bmp.indexed(true);
+    let (color_type, bit_depth) = match indexed {
+        Some(ref indexed) => (INDEXED, match indexed.palette.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        }),
+        None if bmp.has_alpha() => (TRUECOLOR_ALPHA, 8),
+        None => (TRUECOLOR, 8),
+    };
+    let header = Header {
+        width: bmp.width(),
+        height: bmp.height(),
+        bit_depth: bit_depth,
+        color_type: color_type,
+        interlaced: false,
+    };
+
+    writer.write_all(SIGNATURE)?;
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.write_u32::<BigEndian>(header.width)?;
+    ihdr.write_u32::<BigEndian>(header.height)?;
+    ihdr.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+    write_chunk(writer, b"IHDR", &ihdr)?;
+
+    if let Some(ref indexed) = indexed {
+        let plte: Vec<u8> = indexed.palette.iter().flat_map(|p| vec![p.r, p.g, p.b]).collect();
+        write_chunk(writer, b"PLTE", &plte)?;
+        // trailing opaque entries can be left out of tRNS
+        if let Some(last) = indexed.palette.iter().rposition(|p| p.a != 255) {
+            let trns: Vec<u8> = indexed.palette[..last + 1].iter().map(|p| p.a).collect();
+            write_chunk(writer, b"tRNS", &trns)?;
+        }
+    }
+
+    let row_size = header.row_size(width);
+    let distance = header.filter_distance();
+    let mut filtered = Vec::with_capacity((row_size + 1) * height);
+    let mut prior = vec![0u8; row_size];
+    let mut current = vec![0u8; row_size];
+    let mut candidate = Vec::with_capacity(row_size + 1);
+    for y in 0..height {
+        for b in current.iter_mut() {
+            *b = 0;
+        }
+        for x in 0..width {
+            let p = bmp.pixels[x][y];
+            match indexed {
+                Some(ref indexed) => {
+                    let bit = x * bit_depth as usize;
+                    current[bit / 8] |= indexed.indices[y * width + x] << (8 - bit_depth as usize - bit % 8);
+                },
+                None if color_type == TRUECOLOR_ALPHA => current[x * 4..x * 4 + 4].copy_from_slice(&[p.r, p.g, p.b, p.a]),
+                None => current[x * 3..x * 3 + 3].copy_from_slice(&[p.r, p.g, p.b]),
+            }
+        }
+
+        if color_type == INDEXED {
+            // filtering rarely helps palette images
+            filter_row(0, &current, &prior, distance, &mut filtered);
+        } else {
+            // pick the filter with the smallest sum of absolute differences
+            let mut best = (u64::max_value(), 0);
+            for filter in 0..5 {
+                candidate.clear();
+                filter_row(filter, &current, &prior, distance, &mut candidate);
+                let cost = candidate[1..].iter().map(|b| (*b as i8 as i64).abs() as u64).sum();
+                if cost < best.0 {
+                    best = (cost, filter);
+                }
+            }
+            filter_row(best.1, &current, &prior, distance, &mut filtered);
+        }
+        ::std::mem::swap(&mut prior, &mut current);
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&filtered)?;
+    write_chunk(writer, b"IDAT", &encoder.finish()?)?;
+    write_chunk(writer, b"IEND", &[])?;
+    Ok(())
+}
+
+#[test]
+fn png_round_trip() {
+    let mut color = Bmp::new(5, 4);
+    for x in 0..5 {
+        for y in 0..4 {
+            color.pixels[x][y] = Pixel {r: x as u8 * 50, g: y as u8 * 60, b: 7, a: 255};
+        }
+    }
+    let mut alpha = Bmp::new(3, 3);
+    alpha.pixels[1][2] = Pixel {r: 10, g: 20, b: 30, a: 40};
+    let mut indexed = Bmp::new(11, 3);
+    indexed.palette = Some(vec![Pixel::black(), Pixel::white(), Pixel::red()]);
+    indexed.pixels[10][2] = Pixel::red();
+    indexed.pixels[3][1] = Pixel {r: 1, g: 1, b: 1, a: 0};
+
+    for bmp in &[color, alpha, indexed] {
+        let mut data = Vec::new();
+        write_to(bmp, &mut data).unwrap();
+        let loaded = read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+        for x in 0..bmp.width() as usize {
+            for y in 0..bmp.height() as usize {
+                let (expected, actual) = (bmp.pixels[x][y], loaded.pixels[x][y]);
+                if expected.is_transparent() {
+                    assert!(actual.is_transparent());
+                } else {
+                    assert_eq!(expected, actual);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn read_interlaced_gray() {
+    // a 5x5 2 bit grayscale image where each pixel's value is (x + y) % 4, stored with Adam7
+    let header = Header { width: 5, height: 5, bit_depth: 2, color_type: GRAYSCALE, interlaced: true };
+    let mut raw = Vec::new();
+    for (x0, y0, dx, dy, w, h) in header.passes() {
+        for py in 0..h {
+            let mut row = vec![0u8; header.row_size(w)];
+            for px in 0..w {
+                let v = ((x0 + px * dx + y0 + py * dy) % 4) as u8;
+                row[px / 4] |= v << (6 - 2 * (px % 4));
+            }
+            filter_row(1, &row, &vec![0u8; row.len()], 1, &mut raw);
+        }
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&raw).unwrap();
+    let mut data = SIGNATURE.to_vec();
+    write_chunk(&mut data, b"IHDR", &[0, 0, 0, 5, 0, 0, 0, 5, 2, GRAYSCALE, 0, 0, 1]).unwrap();
+    write_chunk(&mut data, b"IDAT", &encoder.finish().unwrap()).unwrap();
+    write_chunk(&mut data, b"IEND", &[]).unwrap();
+
+    let bmp = read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+    for x in 0..5 {
+        for y in 0..5 {
+            let v = ((x + y) % 4) as u8 * 85;
+            assert_eq!(Pixel {r: v, g: v, b: v, a: 255}, bmp.pixels[x][y]);
+        }
+    }
+}
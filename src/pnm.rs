@@ -0,0 +1,453 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+use bmp::{Bmp, LoadOptions, Pixel};
+
+//-------------------------------------------------------------------- PnmError
+
+#[derive(Debug)]
+pub enum PnmError {
+    /// The file didn't start with one of 'P1' through 'P7'.
+    BadMagic(u8, u8),
+    /// The header was missing a field or contained something unparseable.
+    Malformed(String),
+    UnsupportedMaxval(u32),
+    /// The image dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The file ended before all of the samples described by its header were read.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for PnmError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            PnmError::BadMagic(p, n) => write!(formatter, "expected a 'P1' to 'P7' magic but found {:?}", [p, n]),
+            PnmError::Malformed(ref message) => write!(formatter, "malformed header: {}", message),
+            PnmError::UnsupportedMaxval(maxval) => write!(formatter, "unsupported maxval {}", maxval),
+            PnmError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            PnmError::Truncated => write!(formatter, "unexpected end of file"),
+            PnmError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for PnmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PnmError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for PnmError {
+    fn from(err: ::std::io::Error) -> PnmError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => PnmError::Truncated,
+            _ => PnmError::Io(err),
+        }
+    }
+}
+
+pub type PnmResult<T> = Result<T, PnmError>;
+
+//---------------------------------------------------------------------- Header
+
+/// The kind of image stored, independent of whether it's written as ASCII or binary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PnmFormat {
+    /// PBM, 1 bit per pixel where 1 is black.  `P1` or `P4`.
+    Bitmap,
+    /// PGM, a single gray sample per pixel.  `P2` or `P5`.
+    Graymap,
+    /// PPM, red, green and blue samples per pixel.  `P3` or `P6`.
+    Pixmap,
+    /// PAM, any of the above optionally followed by an alpha sample.  `P7`.
+    Arbitrary,
+}
+
+struct Header {
+    format: PnmFormat,
+    ascii: bool,
+    width: u32,
+    height: u32,
+    /// Samples per pixel; for PAM a depth of 2 or 4 means the last sample is alpha.
+    depth: u32,
+    maxval: u32,
+}
+
+//---------------------------------------------------------------------- Reader
+
+/// Reads whitespace separated header tokens, skipping `#` comments.
+struct Tokens<'a, R: 'a> {
+    reader: &'a mut R,
+}
+
+impl<'a, R: Read> Tokens<'a, R> {
+    fn byte(&mut self) -> PnmResult<u8> {
+        let mut b = [0u8; 1];
+        self.reader.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+    /// Returns the first byte that isn't whitespace or part of a comment.
+    fn skip_whitespace(&mut self) -> PnmResult<u8> {
+        loop {
+            match self.byte()? {
+                b'#' => while self.byte()? != b'\n' {},
+                b if b.is_ascii_whitespace() => (),
+                b => return Ok(b),
+            }
+        }
+    }
+    /// Reads the next token, consuming exactly one whitespace byte after it unless the
+    /// token ends the file.
+    fn token(&mut self) -> PnmResult<String> {
+        let mut token = vec![self.skip_whitespace()?];
+        loop {
+            match self.byte() {
+                Ok(b) if b.is_ascii_whitespace() => break,
+                Ok(b) => token.push(b),
+                Err(PnmError::Truncated) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(String::from_utf8_lossy(&token).into_owned())
+    }
+    fn number(&mut self, name: &str) -> PnmResult<u32> {
+        let token = self.token()?;
+        token.parse().map_err(|_| PnmError::Malformed(format!("expected {} but found '{}'", name, token)))
+    }
+    /// Reads a single `0` or `1` from a plain PBM, where the digits needn't be separated.
+    fn bit(&mut self) -> PnmResult<u32> {
+        match self.skip_whitespace()? {
+            b'0' => Ok(0),
+            b'1' => Ok(1),
+            b => Err(PnmError::Malformed(format!("expected a bit but found '{}'", b as char))),
+        }
+    }
+}
+
+fn read_header<R: Read>(tokens: &mut Tokens<R>) -> PnmResult<Header> {
+    let p = tokens.byte()?;
+    let n = tokens.byte()?;
+    let (format, ascii) = match (p, n) {
+        (b'P', b'1') => (PnmFormat::Bitmap, true),
+        (b'P', b'2') => (PnmFormat::Graymap, true),
+        (b'P', b'3') => (PnmFormat::Pixmap, true),
+        (b'P', b'4') => (PnmFormat::Bitmap, false),
+        (b'P', b'5') => (PnmFormat::Graymap, false),
+        (b'P', b'6') => (PnmFormat::Pixmap, false),
+        (b'P', b'7') => return read_pam_header(tokens),
+        _ => return Err(PnmError::BadMagic(p, n)),
+    };
+    let width = tokens.number("width")?;
+    let height = tokens.number("height")?;
+    let (depth, maxval) = match format {
+        PnmFormat::Bitmap => (1, 1),
+        PnmFormat::Graymap => (1, tokens.number("maxval")?),
+        _ => (3, tokens.number("maxval")?),
+    };
+    Ok(Header {
+        format: format,
+        ascii: ascii,
+        width: width,
+        height: height,
+        depth: depth,
+        maxval: maxval,
+    })
+}
+
+fn read_pam_header<R: Read>(tokens: &mut Tokens<R>) -> PnmResult<Header> {
+    let mut width = None;
+    let mut height = None;
+    let mut depth = None;
+    let mut maxval = None;
+    let mut tuple_type = String::new();
+    loop {
+        match tokens.token()?.as_str() {
+            "WIDTH" => width = Some(tokens.number("width")?),
+            "HEIGHT" => height = Some(tokens.number("height")?),
+            "DEPTH" => depth = Some(tokens.number("depth")?),
+            "MAXVAL" => maxval = Some(tokens.number("maxval")?),
+            "TUPLTYPE" => tuple_type = tokens.token()?,
+            "ENDHDR" => break,
+            t => return Err(PnmError::Malformed(format!("unknown PAM header field '{}'", t))),
+        }
+    }
+    let missing = |name: &str| PnmError::Malformed(format!("missing {}", name));
+    let depth = depth.ok_or_else(|| missing("DEPTH"))?;
+    if depth == 0 || depth > 4 {
+        return Err(PnmError::Malformed(format!("unsupported depth {} for tuple type '{}'", depth, tuple_type)));
+    }
+    Ok(Header {
+        format: PnmFormat::Arbitrary,
+        ascii: false,
+        width: width.ok_or_else(|| missing("WIDTH"))?,
+        height: height.ok_or_else(|| missing("HEIGHT"))?,
+        depth: depth,
+        maxval: maxval.ok_or_else(|| missing("MAXVAL"))?,
+    })
+}
+
+pub fn load(path_str: &str) -> PnmResult<Bmp> {
+    let file = File::open(Path::new(path_str))?;
+    read_from(&mut ::std::io::BufReader::new(file))
+}
+
+pub fn read_from<R: Read>(reader: &mut R) -> PnmResult<Bmp> {
+    read_from_with(reader, &LoadOptions::default())
+}
+
+pub fn read_from_with<R: Read>(reader: &mut R, options: &LoadOptions) -> PnmResult<Bmp> {
+    let mut tokens = Tokens { reader: reader };
+    let header = read_header(&mut tokens)?;
+    if header.maxval == 0 || header.maxval > 65535 {
+        return Err(PnmError::UnsupportedMaxval(header.maxval));
+    }
+    if !options.allows(header.width, header.height) {
+        return Err(PnmError::TooLarge(header.width, header.height));
+    }
+
+    let width = header.width as usize;
+    let height = header.height as usize;
+    let depth = header.depth as usize;
+    let sample_size = if header.maxval > 255 { 2 } else { 1 };
+    let row_size = match (header.format, header.ascii) {
+        (_, true) => 0,
+        (PnmFormat::Bitmap, false) => (width + 7) / 8,
+        _ => width * depth * sample_size,
+    };
+    let scale = |v: u32| ((v.min(header.maxval) * 255 + header.maxval / 2) / header.maxval) as u8;
+
+    let mut bmp = Bmp::new(header.width, header.height);
+    let mut row = vec![0u8; row_size];
+    let mut samples = vec![0u32; width * depth];
+    for y in 0..height {
+        // gather the row's raw samples
+        if header.ascii {
+            for s in samples.iter_mut() {
+                *s = match header.format {
+                    PnmFormat::Bitmap => tokens.bit()?,
+                    _ => tokens.number("sample")?,
+                };
+            }
+        } else {
+            tokens.reader.read_exact(&mut row)?;
+            for (i, s) in samples.iter_mut().enumerate() {
+                *s = match (header.format, sample_size) {
+                    (PnmFormat::Bitmap, _) => (row[i / 8] >> (7 - i % 8)) as u32 & 1,
+                    (_, 1) => row[i] as u32,
+                    _ => (row[i * 2] as u32) << 8 | row[i * 2 + 1] as u32,
+                };
+            }
+        }
+
+        for x in 0..width {
+            let s = &samples[x * depth..(x + 1) * depth];
+            bmp.pixels[x][y] = match (header.format, depth) {
+                (PnmFormat::Bitmap, _) if s[0] == 1 => Pixel::black(),
+                (PnmFormat::Bitmap, _) => Pixel::white(),
+                (_, 1) => { let v = scale(s[0]); Pixel {r: v, g: v, b: v, a: 255} },
+                (_, 2) => { let v = scale(s[0]); Pixel {r: v, g: v, b: v, a: scale(s[1])} },
+                (_, 3) => Pixel {r: scale(s[0]), g: scale(s[1]), b: scale(s[2]), a: 255},
+                _ => Pixel {r: scale(s[0]), g: scale(s[1]), b: scale(s[2]), a: scale(s[3])},
+            };
+        }
+    }
+    if header.format == PnmFormat::Bitmap {
+        bmp.palette = Some(vec![Pixel::black(), Pixel::white()]);
+    }
+    Ok(bmp)
+}
+
+//---------------------------------------------------------------------- Writer
+
+/// Options controlling how `write_to_with` encodes an image.
+#[derive(Clone, Debug, Default)]
+pub struct PnmOptions {
+    /// The kind of image to write; when `None` the smallest format able to hold the pixels
+    /// is picked, so black and white images become true 1 bit PBMs.
+    pub format: Option<PnmFormat>,
+    /// Write the plain (`P1` to `P3`) variants.  PAM has no plain variant.
+    pub ascii: bool,
+    /// Write samples with a maxval of 65535 instead of 255.
+    pub sixteen_bit: bool,
+}
+
+impl PnmOptions {
+    /// Picks the format implied by a `.pbm`, `.pgm`, `.ppm` or `.pam` extension.
+    pub fn for_path(path_str: &str) -> PnmOptions {
+        let extension = Path::new(path_str).extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        let format = match extension.as_ref().map(|e| e.as_str()) {
+            Some("pbm") => Some(PnmFormat::Bitmap),
+            Some("pgm") => Some(PnmFormat::Graymap),
+            Some("ppm") => Some(PnmFormat::Pixmap),
+            Some("pam") => Some(PnmFormat::Arbitrary),
+            _ => None,
+        };
+        PnmOptions { format: format, ..PnmOptions::default() }
+    }
+}
+
+/// The smallest format that can hold every pixel of `bmp` without loss.
+fn smallest_format(bmp: &Bmp) -> PnmFormat {
+    if bmp.has_alpha() {
+        return PnmFormat::Arbitrary;
+    }
+    let pixels = || bmp.pixels.iter().flat_map(|column| column.iter());
+    if pixels().all(|p| *p == Pixel::black() || *p == Pixel::white()) {
+        PnmFormat::Bitmap
+    } else if pixels().all(|p| p.r == p.g && p.g == p.b) {
+        PnmFormat::Graymap
+    } else {
+        PnmFormat::Pixmap
+    }
+}
+
+pub fn save(bmp: &Bmp, path_str: &str) -> PnmResult<()> {
+    let file = File::create(Path::new(path_str))?;
+    let mut file = ::std::io::BufWriter::new(file);
+    write_to_with(bmp, &mut file, &PnmOptions::for_path(path_str))?;
+    file.flush()?;
+    Ok(())
+}
+
+pub fn write_to<W: Write>(bmp: &Bmp, writer: &mut W) -> PnmResult<()> {
+    write_to_with(bmp, writer, &PnmOptions::default())
+}
+
+pub fn write_to_with<W: Write>(bmp: &Bmp, writer: &mut W, options: &PnmOptions) -> PnmResult<()> {
+    let format = options.format.unwrap_or_else(|| smallest_format(bmp));
+    let ascii = options.ascii && format != PnmFormat::Arbitrary;
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let maxval: u32 = if options.sixteen_bit { 65535 } else { 255 };
+    let gray = bmp.pixels.iter().all(|column| column.iter().all(|p| p.r == p.g && p.g == p.b));
+    let alpha = bmp.has_alpha();
+
+    let magic = match (format, ascii) {
+        (PnmFormat::Bitmap, true) => "P1",
+        (PnmFormat::Graymap, true) => "P2",
+        (PnmFormat::Pixmap, true) => "P3",
+        (PnmFormat::Bitmap, false) => "P4",
+        (PnmFormat::Graymap, false) => "P5",
+        (PnmFormat::Pixmap, false) => "P6",
+        (PnmFormat::Arbitrary, _) => "P7",
+    };
+    match format {
+        PnmFormat::Bitmap => write!(writer, "{}\n{} {}\n", magic, width, height)?,
+        PnmFormat::Graymap | PnmFormat::Pixmap => write!(writer, "{}\n{} {}\n{}\n", magic, width, height, maxval)?,
+        PnmFormat::Arbitrary => {
+            let (depth, tuple_type) = match (gray, alpha) {
+                (true, false) => (1, "GRAYSCALE"),
+                (true, true) => (2, "GRAYSCALE_ALPHA"),
+                (false, false) => (3, "RGB"),
+                (false, true) => (4, "RGB_ALPHA"),
+            };
+            write!(writer, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+                   width, height, depth, maxval, tuple_type)?;
+        },
+    }
+
+    let scale = |v: u8| v as u32 * maxval / 255;
+    let mut samples = Vec::with_capacity(width * 4);
+    for y in 0..height {
+        samples.clear();
+        for x in 0..width {
+            let p = bmp.pixels[x][y];
+            match format {
+                PnmFormat::Bitmap => samples.push(if p.luma() < 128 { 1 } else { 0 }),
+                PnmFormat::Graymap => samples.push(scale(p.luma())),
+                PnmFormat::Pixmap => samples.extend_from_slice(&[scale(p.r), scale(p.g), scale(p.b)]),
+                PnmFormat::Arbitrary => {
+                    if gray {
+                        samples.push(scale(p.r));
+                    } else {
+                        samples.extend_from_slice(&[scale(p.r), scale(p.g), scale(p.b)]);
+                    }
+                    if alpha {
+                        samples.push(scale(p.a));
+                    }
+                },
+            }
+        }
+
+        if ascii {
+            // keep lines under the 70 characters the format recommends
+            let per_line = if format == PnmFormat::Bitmap { 35 } else { 12 };
+            for line in samples.chunks(per_line) {
+                let line: Vec<String> = line.iter().map(|s| s.to_string()).collect();
+                writeln!(writer, "{}", line.join(" "))?;
+            }
+        } else if format == PnmFormat::Bitmap {
+            let mut row = vec![0u8; (width + 7) / 8];
+            for (i, s) in samples.iter().enumerate() {
+                row[i / 8] |= (*s as u8) << (7 - i % 8);
+            }
+            writer.write_all(&row)?;
+        } else if maxval > 255 {
+            for s in &samples {
+                writer.write_all(&[(s >> 8) as u8, *s as u8])?;
+            }
+        } else {
+            let row: Vec<u8> = samples.iter().map(|s| *s as u8).collect();
+            writer.write_all(&row)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn pnm_round_trip() {
+    let mut bw = Bmp::new(10, 3);
+    bw.pixels[0][0] = Pixel::white();
+    bw.pixels[9][2] = Pixel::white();
+    let mut gray = Bmp::new(3, 2);
+    gray.pixels[1][1] = Pixel {r: 100, g: 100, b: 100, a: 255};
+    let mut color = Bmp::new(3, 2);
+    color.pixels[2][0] = Pixel {r: 1, g: 2, b: 3, a: 255};
+    let mut alpha = Bmp::new(2, 2);
+    alpha.pixels[0][1] = Pixel {r: 10, g: 20, b: 30, a: 40};
+
+    for bmp in &[bw, gray, color, alpha] {
+        for &(ascii, sixteen_bit) in &[(false, false), (true, false), (false, true), (true, true)] {
+            let mut data = Vec::new();
+            let options = PnmOptions { format: None, ascii: ascii, sixteen_bit: sixteen_bit };
+            write_to_with(bmp, &mut data, &options).unwrap();
+            let loaded = read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+            assert_eq!(bmp.pixels, loaded.pixels);
+        }
+    }
+}
+
+#[test]
+fn write_bw_as_pbm() {
+    let mut bmp = Bmp::new(9, 1);
+    bmp.pixels[8][0] = Pixel::white();
+    let mut data = Vec::new();
+    write_to(&bmp, &mut data).unwrap();
+    assert_eq!(b"P4\n9 1\n\xff\x00", &data[..]);
+}
+
+#[test]
+fn read_plain_pbm_without_separators() {
+    let data = b"P1\n# a comment\n3 2\n010\n1 0 1";
+    let bmp = read_from(&mut ::std::io::Cursor::new(&data[..])).unwrap();
+    assert_eq!(vec![Pixel::white(), Pixel::black()], bmp.pixels[0]);
+    assert_eq!(vec![Pixel::black(), Pixel::white()], bmp.pixels[1]);
+}
+
+#[test]
+fn read_plain_pgm_without_final_newline() {
+    let data = b"P2\n2 1\n255\n0 255";
+    let bmp = read_from(&mut ::std::io::Cursor::new(&data[..])).unwrap();
+    assert_eq!(Pixel::black(), bmp.pixels[0][0]);
+    assert_eq!(Pixel::white(), bmp.pixels[1][0]);
+}
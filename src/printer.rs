@@ -0,0 +1,173 @@
+use std::io::{Error, ErrorKind, Result, Write};
+
+extern crate flate2;
+use self::flate2::Compression;
+use self::flate2::write::ZlibEncoder;
+
+use bmp::Bmp;
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// How the `^GF` graphic field data is encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZplEncoding {
+    /// Two uppercase hex digits per byte.
+    Hex,
+    /// zlib compressed, base64 encoded and CRC checked, as `:Z64:data:crc`.
+    Z64,
+}
+
+/// Options shared by the printer writers.
+#[derive(Clone, Debug)]
+pub struct PrinterOptions {
+    /// The printer's width in dots.  Narrower images are centered and padded with white to
+    /// fill it; wider ones are rejected.  Without it rows are only padded to whole bytes.
+    pub dot_width: Option<u32>,
+    pub zpl_encoding: ZplEncoding,
+}
+
+impl Default for PrinterOptions {
+    fn default() -> PrinterOptions {
+        PrinterOptions {
+            dot_width: None,
+            zpl_encoding: ZplEncoding::Hex,
+        }
+    }
+}
+
+/// Packs the image one bit per dot, most significant bit first, with set bits for dark
+/// pixels, returning the bytes per row and the data.
+fn pack_dots(bmp: &Bmp, options: &PrinterOptions) -> Result<(usize, Vec<u8>)> {
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let dots = match options.dot_width {
+        Some(dots) if (dots as usize) < width => {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("image is {} dots wide but the printer only {}", width, dots)));
+        },
+        Some(dots) => dots as usize,
+        None => width,
+    };
+    let row_size = (dots + 7) / 8;
+    let left = (dots - width) / 2;
+    let mut data = vec![0u8; row_size * height];
+    for y in 0..height {
+        for x in 0..width {
+            let p = bmp.pixels[x][y];
+            if !p.is_transparent() && p.luma() < 128 {
+                let dot = left + x;
+                data[y * row_size + dot / 8] |= 0x80 >> (dot % 8);
+            }
+        }
+    }
+    Ok((row_size, data))
+}
+
+//---------------------------------------------------------------------- EscPos
+
+/// Writes an ESC/POS printer reset followed by a `GS v 0` raster bit image.
+pub fn write_escpos<W: Write>(bmp: &Bmp, writer: &mut W, options: &PrinterOptions) -> Result<()> {
+    let (row_size, data) = pack_dots(bmp, options)?;
+    let height = bmp.height() as usize;
+    if row_size > 0xffff || height > 0xffff {
+        return Err(Error::new(ErrorKind::InvalidInput, "image is too large for a GS v 0 raster"));
+    }
+    let mut out = Vec::with_capacity(data.len() + 10);
+    // ESC @ initialize, then GS v 0 in normal density with little endian byte width and height
+    out.extend_from_slice(&[0x1b, b'@', 0x1d, b'v', b'0', 0]);
+    out.extend_from_slice(&[row_size as u8, (row_size >> 8) as u8, height as u8, (height >> 8) as u8]);
+    out.extend_from_slice(&data);
+    writer.write_all(&out)
+}
+
+//------------------------------------------------------------------------- Zpl
+
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - i * 6)) & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The CRC-16/XMODEM checksum ZPL expects after Z64 data.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Writes a complete ZPL label holding the image as a `^GF` graphic field at the origin.
+pub fn write_zpl<W: Write>(bmp: &Bmp, writer: &mut W, options: &PrinterOptions) -> Result<()> {
+    let (row_size, data) = pack_dots(bmp, options)?;
+    let field = match options.zpl_encoding {
+        ZplEncoding::Hex => {
+            let mut hex = String::with_capacity(data.len() * 2 + row_size);
+            for row in data.chunks(row_size.max(1)) {
+                for b in row {
+                    hex.push_str(&format!("{:02X}", b));
+                }
+                hex.push('\n');
+            }
+            hex
+        },
+        ZplEncoding::Z64 => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(&data)?;
+            let encoded = base64(&encoder.finish()?);
+            format!(":Z64:{}:{:04X}", encoded, crc16(encoded.as_bytes()))
+        },
+    };
+    let mut out = String::new();
+    out.push_str("^XA\n");
+    if let Some(dots) = options.dot_width {
+        out.push_str(&format!("^PW{}\n", dots));
+    }
+    out.push_str(&format!("^FO0,0^GFA,{},{},{},\n", data.len(), data.len(), row_size));
+    out.push_str(&field);
+    out.push_str("^FS\n^XZ\n");
+    writer.write_all(out.as_bytes())
+}
+
+#[test]
+fn escpos_raster_is_padded_and_centered() {
+    use bmp::Pixel;
+    let mut bmp = Bmp::new(4, 2);
+    for x in 0..4 {
+        for y in 0..2 {
+            bmp.pixels[x][y] = Pixel::white();
+        }
+    }
+    bmp.pixels[0][0] = Pixel::black();
+    bmp.pixels[3][1] = Pixel::black();
+    let mut out = Vec::new();
+    write_escpos(&bmp, &mut out, &PrinterOptions { dot_width: Some(12), ..PrinterOptions::default() }).unwrap();
+    // the image starts 4 dots in, so its first and last dots are bits 4 and 7
+    assert_eq!(vec![0x1b, b'@', 0x1d, b'v', b'0', 0, 2, 0, 2, 0, 0x08, 0x00, 0x01, 0x00], out);
+
+    assert!(write_escpos(&bmp, &mut Vec::new(), &PrinterOptions { dot_width: Some(3), ..PrinterOptions::default() }).is_err());
+}
+
+#[test]
+fn zpl_encodings() {
+    let bmp = Bmp::new(9, 1);
+    let mut out = Vec::new();
+    write_zpl(&bmp, &mut out, &PrinterOptions::default()).unwrap();
+    assert_eq!("^XA\n^FO0,0^GFA,2,2,2,\nFF80\n^FS\n^XZ\n", String::from_utf8(out).unwrap());
+
+    assert_eq!("TWFu", base64(b"Man"));
+    assert_eq!("TWE=", base64(b"Ma"));
+    assert_eq!(0x31c3, crc16(b"123456789"));
+}
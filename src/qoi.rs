@@ -0,0 +1,247 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+extern crate byteorder;
+use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp::{Bmp, LoadOptions, Pixel};
+
+const MAGIC: &[u8] = b"qoif";
+const END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xc0;
+const OP_RGB: u8 = 0xfe;
+const OP_RGBA: u8 = 0xff;
+const MASK_2: u8 = 0xc0;
+
+//-------------------------------------------------------------------- QoiError
+
+#[derive(Debug)]
+pub enum QoiError {
+    /// The file didn't start with `qoif`.
+    BadMagic,
+    /// The header held invalid values.
+    Malformed(String),
+    /// The image dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The file ended before the image data did.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for QoiError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            QoiError::BadMagic => write!(formatter, "missing QOI magic"),
+            QoiError::Malformed(ref message) => write!(formatter, "malformed QOI: {}", message),
+            QoiError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            QoiError::Truncated => write!(formatter, "unexpected end of file"),
+            QoiError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for QoiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            QoiError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for QoiError {
+    fn from(err: ::std::io::Error) -> QoiError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => QoiError::Truncated,
+            _ => QoiError::Io(err),
+        }
+    }
+}
+
+pub type QoiResult<T> = Result<T, QoiError>;
+
+fn hash(p: &Pixel) -> usize {
+    (p.r as usize * 3 + p.g as usize * 5 + p.b as usize * 7 + p.a as usize * 11) % 64
+}
+
+//---------------------------------------------------------------------- Reader
+
+pub fn load(path_str: &str) -> QoiResult<Bmp> {
+    let file = File::open(Path::new(path_str))?;
+    read_from(&mut ::std::io::BufReader::new(file))
+}
+
+pub fn read_from<R: Read>(reader: &mut R) -> QoiResult<Bmp> {
+    read_from_with(reader, &LoadOptions::default())
+}
+
+pub fn read_from_with<R: Read>(reader: &mut R, options: &LoadOptions) -> QoiResult<Bmp> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(QoiError::BadMagic);
+    }
+    let width = reader.read_u32::<BigEndian>()?;
+    let height = reader.read_u32::<BigEndian>()?;
+    let channels = reader.read_u8()?;
+    let colorspace = reader.read_u8()?;
+    if channels != 3 && channels != 4 || colorspace > 1 {
+        return Err(QoiError::Malformed(format!("{} channels and colorspace {}", channels, colorspace)));
+    }
+    if !options.allows(width, height) {
+        return Err(QoiError::TooLarge(width, height));
+    }
+
+    let mut bmp = Bmp::new(width, height);
+    let mut seen = [Pixel {r: 0, g: 0, b: 0, a: 0}; 64];
+    let mut p = Pixel {r: 0, g: 0, b: 0, a: 255};
+    let mut run = 0;
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            if run > 0 {
+                run -= 1;
+            } else {
+                let op = reader.read_u8()?;
+                match op {
+                    OP_RGB => {
+                        p.r = reader.read_u8()?;
+                        p.g = reader.read_u8()?;
+                        p.b = reader.read_u8()?;
+                    },
+                    OP_RGBA => {
+                        p.r = reader.read_u8()?;
+                        p.g = reader.read_u8()?;
+                        p.b = reader.read_u8()?;
+                        p.a = reader.read_u8()?;
+                    },
+                    _ => match op & MASK_2 {
+                        OP_INDEX => p = seen[op as usize],
+                        OP_DIFF => {
+                            p.r = p.r.wrapping_add((op >> 4) & 0x03).wrapping_sub(2);
+                            p.g = p.g.wrapping_add((op >> 2) & 0x03).wrapping_sub(2);
+                            p.b = p.b.wrapping_add(op & 0x03).wrapping_sub(2);
+                        },
+                        OP_LUMA => {
+                            let second = reader.read_u8()?;
+                            let dg = (op & 0x3f).wrapping_sub(32);
+                            p.r = p.r.wrapping_add(dg).wrapping_add(second >> 4).wrapping_sub(8);
+                            p.g = p.g.wrapping_add(dg);
+                            p.b = p.b.wrapping_add(dg).wrapping_add(second & 0x0f).wrapping_sub(8);
+                        },
+                        _ => run = (op & 0x3f) as usize,
+                    },
+                }
+                seen[hash(&p)] = p;
+            }
+            bmp.pixels[x][y] = p;
+        }
+    }
+    // the end marker is left unread; nothing after the pixels matters
+    Ok(bmp)
+}
+
+//---------------------------------------------------------------------- Writer
+
+pub fn save(bmp: &Bmp, path_str: &str) -> QoiResult<()> {
+    let file = File::create(Path::new(path_str))?;
+    let mut file = ::std::io::BufWriter::new(file);
+    write_to(bmp, &mut file)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes an sRGB image with an alpha channel only when some pixel isn't opaque.
+pub fn write_to<W: Write>(bmp: &Bmp, writer: &mut W) -> QoiResult<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_u32::<BigEndian>(bmp.width())?;
+    writer.write_u32::<BigEndian>(bmp.height())?;
+    writer.write_u8(if bmp.has_alpha() { 4 } else { 3 })?;
+    writer.write_u8(0)?;
+
+    let mut data = Vec::new();
+    let mut seen = [Pixel {r: 0, g: 0, b: 0, a: 0}; 64];
+    let mut previous = Pixel {r: 0, g: 0, b: 0, a: 255};
+    let mut run = 0;
+    for y in 0..bmp.height() as usize {
+        for x in 0..bmp.width() as usize {
+            let p = bmp.pixels[x][y];
+            if p == previous {
+                run += 1;
+                if run == 62 {
+                    data.push(OP_RUN | (run - 1));
+                    run = 0;
+                }
+                continue;
+            }
+            if run > 0 {
+                data.push(OP_RUN | (run - 1));
+                run = 0;
+            }
+
+            let index = hash(&p);
+            if seen[index] == p {
+                data.push(OP_INDEX | index as u8);
+            } else {
+                seen[index] = p;
+                if p.a != previous.a {
+                    data.extend_from_slice(&[OP_RGBA, p.r, p.g, p.b, p.a]);
+                } else {
+                    let dr = p.r.wrapping_sub(previous.r) as i8;
+                    let dg = p.g.wrapping_sub(previous.g) as i8;
+                    let db = p.b.wrapping_sub(previous.b) as i8;
+                    let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+                    if (-2..2).contains(&dr) && (-2..2).contains(&dg) && (-2..2).contains(&db) {
+                        data.push(OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8);
+                    } else if (-32..32).contains(&dg) && (-8..8).contains(&dr_dg) && (-8..8).contains(&db_dg) {
+                        data.push(OP_LUMA | (dg + 32) as u8);
+                        data.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+                    } else {
+                        data.extend_from_slice(&[OP_RGB, p.r, p.g, p.b]);
+                    }
+                }
+            }
+            previous = p;
+        }
+    }
+    if run > 0 {
+        data.push(OP_RUN | (run - 1));
+    }
+    data.extend_from_slice(&END_MARKER);
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+#[test]
+fn qoi_round_trip() {
+    let mut bmp = Bmp::new(100, 3);
+    for x in 0..100 {
+        bmp.pixels[x][0] = Pixel {r: x as u8, g: x as u8 * 2, b: 255 - x as u8, a: 255};
+        bmp.pixels[x][1] = Pixel {r: (x * 37) as u8, g: (x * 11) as u8, b: 7, a: if x % 10 == 0 { 0 } else { 255 }};
+    }
+    let mut data = Vec::new();
+    write_to(&bmp, &mut data).unwrap();
+    assert!(data.ends_with(&END_MARKER));
+    let loaded = read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+    for x in 0..100 {
+        for y in 0..3 {
+            assert_eq!(bmp.pixels[x][y], loaded.pixels[x][y]);
+        }
+    }
+}
+
+#[test]
+fn reject_huge_headers() {
+    let data = b"qoif\x00\x00\x40\x00\x00\x00\x40\x00\x04\x00";
+    match read_from(&mut &data[..]) {
+        Err(QoiError::TooLarge(16384, 16384)) => (),
+        r => panic!("unexpected result {:?}", r.map(|_| ())),
+    }
+}
@@ -0,0 +1,209 @@
+use std::io::{Error, ErrorKind, Result, Write};
+
+use bmp::{Bmp, Pixel};
+use epaper::BitOrder;
+
+/// The language the arrays are declared in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    /// A C header of `const uint8_t` arrays with the sizes as defines.
+    C,
+    /// Rust `pub const` byte arrays with the sizes as `usize` constants.
+    Rust,
+}
+
+/// How pixels are packed into bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packing {
+    /// Palette indices at 1, 2, 4 or 8 bits per pixel, with the palette as a second array.
+    Indexed(u8),
+    /// 16 bit 5-6-5 color, two bytes per pixel.
+    Rgb565,
+    /// 8 bit 3-3-2 color.
+    Rgb332,
+}
+
+/// The order pixels are visited in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    /// Left to right along each row, rows padded to a whole byte.
+    Rows,
+    /// SSD1306 style pages: each byte holds a column of `8 / bpp` vertical pixels, bytes
+    /// run left to right across the page, and pages run top to bottom.  Color packings,
+    /// with a whole byte or more per pixel, run down each column in turn.
+    Pages,
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceOptions {
+    pub language: Language,
+    pub packing: Packing,
+    pub order: Order,
+    /// Which end of a byte the first pixel takes; for `Rgb565` whether the low byte of
+    /// each pixel comes first.
+    pub bit_order: BitOrder,
+    /// The array name; the size constants are named after it in upper case.
+    pub name: String,
+}
+
+impl Default for SourceOptions {
+    fn default() -> SourceOptions {
+        SourceOptions {
+            language: Language::C,
+            packing: Packing::Indexed(8),
+            order: Order::Rows,
+            bit_order: BitOrder::Msb,
+            name: "image".to_string(),
+        }
+    }
+}
+
+/// Packs values of `bits` bits each, `per_byte` of them into every byte, visiting pixels in
+/// `order`.
+fn pack_values<F: Fn(usize, usize) -> u8>(width: usize, height: usize, bits: usize, options: &SourceOptions, value: F) -> Vec<u8> {
+    let per_byte = 8 / bits;
+    let shift = |slot: usize| match options.bit_order {
+        BitOrder::Msb => 8 - bits * (slot + 1),
+        BitOrder::Lsb => bits * slot,
+    };
+    let mut data = vec![];
+    match options.order {
+        Order::Rows => {
+            for y in 0..height {
+                for x in (0..width).step_by(per_byte) {
+                    let mut byte = 0;
+                    for slot in 0..per_byte.min(width - x) {
+                        byte |= value(x + slot, y) << shift(slot);
+                    }
+                    data.push(byte);
+                }
+            }
+        },
+        Order::Pages => {
+            for page in (0..height).step_by(per_byte) {
+                for x in 0..width {
+                    let mut byte = 0;
+                    for slot in 0..per_byte.min(height - page) {
+                        byte |= value(x, page + slot) << shift(slot);
+                    }
+                    data.push(byte);
+                }
+            }
+        },
+    }
+    data
+}
+
+/// Packs the image's pixels as `options` describes, returning the data and, for indexed
+/// packings, the palette.
+pub fn pack(bmp: &Bmp, options: &SourceOptions) -> Result<(Vec<u8>, Option<Vec<Pixel>>)> {
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    match options.packing {
+        Packing::Indexed(bits) => {
+            if ![1, 2, 4, 8].contains(&bits) {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("can't pack {} bits per pixel", bits)));
+            }
+            let indexed = match bmp.indexed(true) {
+                Some(indexed) if indexed.palette.len() <= 1 << bits => indexed,
+                _ => return Err(Error::new(ErrorKind::InvalidInput, format!("indexed output needs an image dithered to at most {} colors", 1 << bits))),
+            };
+            let data = pack_values(width, height, bits as usize, options, |x, y| indexed.indices[y * width + x]);
+            Ok((data, Some(indexed.palette)))
+        },
+        Packing::Rgb332 => {
+            let data = pack_values(width, height, 8, options, |x, y| {
+                let p = bmp.pixels[x][y];
+                (p.r & 0xe0) | (p.g & 0xe0) >> 3 | p.b >> 6
+            });
+            Ok((data, None))
+        },
+        Packing::Rgb565 => {
+            let rgb565 = |x: usize, y: usize| {
+                let p = bmp.pixels[x][y];
+                (p.r as u16 & 0xf8) << 8 | (p.g as u16 & 0xfc) << 3 | p.b as u16 >> 3
+            };
+            let mut data = Vec::with_capacity(width * height * 2);
+            let mut push = |v: u16| match options.bit_order {
+                BitOrder::Msb => data.extend_from_slice(&[(v >> 8) as u8, v as u8]),
+                BitOrder::Lsb => data.extend_from_slice(&[v as u8, (v >> 8) as u8]),
+            };
+            match options.order {
+                Order::Rows => (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).for_each(|(x, y)| push(rgb565(x, y))),
+                Order::Pages => (0..width).flat_map(|x| (0..height).map(move |y| (x, y))).for_each(|(x, y)| push(rgb565(x, y))),
+            }
+            Ok((data, None))
+        },
+    }
+}
+
+/// Writes a byte array declaration named `name`.
+pub fn write_array<W: Write>(writer: &mut W, language: Language, name: &str, data: &[u8]) -> Result<()> {
+    let mut out = match language {
+        Language::C => format!("const uint8_t {}[{}] = {{\n", name, data.len()),
+        Language::Rust => format!("pub const {}: [u8; {}] = [\n", name.to_uppercase(), data.len()),
+    };
+    for line in data.chunks(16) {
+        let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02x},", b)).collect();
+        out.push_str(&format!("    {}\n", bytes.join(" ")));
+    }
+    out.push_str(match language {
+        Language::C => "};\n",
+        Language::Rust => "];\n",
+    });
+    writer.write_all(out.as_bytes())
+}
+
+/// Writes a size constant named `name`.
+pub fn write_constant<W: Write>(writer: &mut W, language: Language, name: &str, value: usize) -> Result<()> {
+    match language {
+        Language::C => writeln!(writer, "#define {} {}", name.to_uppercase(), value),
+        Language::Rust => writeln!(writer, "pub const {}: usize = {};", name.to_uppercase(), value),
+    }
+}
+
+/// Writes the image as source: its size, the packed pixel data and, for indexed packings,
+/// the palette as red, green, blue triples.
+pub fn write_source<W: Write>(bmp: &Bmp, writer: &mut W, options: &SourceOptions) -> Result<()> {
+    let (data, palette) = pack(bmp, options)?;
+    if options.language == Language::C {
+        writer.write_all(b"#include <stdint.h>\n\n")?;
+    }
+    write_constant(writer, options.language, &format!("{}_width", options.name), bmp.width() as usize)?;
+    write_constant(writer, options.language, &format!("{}_height", options.name), bmp.height() as usize)?;
+    writer.write_all(b"\n")?;
+    if let Some(palette) = palette {
+        let rgb: Vec<u8> = palette.iter().flat_map(|p| vec![p.r, p.g, p.b]).collect();
+        write_array(writer, options.language, &format!("{}_palette", options.name), &rgb)?;
+        writer.write_all(b"\n")?;
+    }
+    write_array(writer, options.language, &options.name, &data)
+}
+
+#[test]
+fn pack_rows_and_pages() {
+    let mut bmp = Bmp::new(3, 9);
+    bmp.palette = Some(vec![Pixel::black(), Pixel::white()]);
+    bmp.pixels[0][0] = Pixel::white();
+    bmp.pixels[2][0] = Pixel::white();
+    bmp.pixels[1][8] = Pixel::white();
+    let mut options = SourceOptions { packing: Packing::Indexed(1), ..SourceOptions::default() };
+    let (data, _) = pack(&bmp, &options).unwrap();
+    assert_eq!(vec![0xa0, 0, 0, 0, 0, 0, 0, 0, 0x40], data);
+
+    // pages of 8 rows, the top pixel in bit 0
+    options.order = Order::Pages;
+    options.bit_order = BitOrder::Lsb;
+    let (data, _) = pack(&bmp, &options).unwrap();
+    assert_eq!(vec![0x01, 0x00, 0x01, 0x00, 0x01, 0x00], data);
+
+    options.packing = Packing::Indexed(4);
+    assert!(pack(&bmp, &SourceOptions { packing: Packing::Indexed(3), ..options.clone() }).is_err());
+    bmp.pixels[0][0] = Pixel::red();
+    assert!(pack(&bmp, &options).is_err());
+
+    options.packing = Packing::Rgb565;
+    let (data, palette) = pack(&bmp, &options).unwrap();
+    assert_eq!(None, palette);
+    assert_eq!(vec![0x00, 0xf8], data[..2].to_vec());
+}
@@ -0,0 +1,131 @@
+use std::io::{Error, ErrorKind, Result, Write};
+
+use bmp::{Bmp, Pixel};
+
+/// What each dithered pixel is drawn as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Marks {
+    /// A small circle around the pixel's center.
+    Circles,
+    /// A short horizontal stroke across the pixel.
+    Strokes,
+}
+
+/// Options controlling how `write_svg` turns a dithered image into plotter paths.
+#[derive(Clone, Debug)]
+pub struct SvgOptions {
+    pub marks: Marks,
+    /// Draw runs of same colored pixels along a row as single line segments.
+    pub merge_runs: bool,
+    /// The distance between pixel centers on paper, in millimetres.
+    pub pitch: f64,
+    /// The circle diameter or stroke length, as a fraction of the pitch.
+    pub dot_size: f64,
+    /// The pen's line width, as a fraction of the pitch.
+    pub pen_width: f64,
+    /// The paper color, which is left undrawn.
+    pub paper: Option<Pixel>,
+}
+
+impl Default for SvgOptions {
+    fn default() -> SvgOptions {
+        SvgOptions {
+            marks: Marks::Circles,
+            merge_runs: true,
+            pitch: 1.0,
+            dot_size: 0.6,
+            pen_width: 0.3,
+            paper: Some(Pixel::white()),
+        }
+    }
+}
+
+/// Formats a coordinate with at most three decimals and no trailing zeros.
+fn number(v: f64) -> String {
+    let s = format!("{:.3}", v);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Writes the dithered image as an SVG for pen plotters, with one Inkscape layer per
+/// palette color so each can be drawn with its own pen.  The image is in pixel units,
+/// scaled to `pitch` millimetres per pixel.
+pub fn write_svg<W: Write>(bmp: &Bmp, writer: &mut W, options: &SvgOptions) -> Result<()> {
+    let indexed = match bmp.indexed(true) {
+        Some(indexed) => indexed,
+        None => return Err(Error::new(ErrorKind::InvalidInput, "SVG output needs an image dithered to at most 256 colors")),
+    };
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let half = options.dot_size / 2.0;
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(&format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:inkscape=\"http://www.inkscape.org/namespaces/inkscape\" width=\"{}mm\" height=\"{}mm\" viewBox=\"0 0 {} {}\">\n",
+        number(width as f64 * options.pitch), number(height as f64 * options.pitch), width, height));
+    for (color, p) in indexed.palette.iter().enumerate() {
+        if Some(color as u8) == indexed.transparent || Some(*p) == options.paper {
+            continue;
+        }
+        let mut marks = String::new();
+        for y in 0..height {
+            let row = &indexed.indices[y * width..(y + 1) * width];
+            let cy = number(y as f64 + 0.5);
+            let mut x = 0;
+            while x < width {
+                if row[x] as usize != color {
+                    x += 1;
+                    continue;
+                }
+                let mut end = x + 1;
+                if options.merge_runs {
+                    while end < width && row[end] as usize == color {
+                        end += 1;
+                    }
+                }
+                // runs are lines between the first and last marks' extents
+                let (x1, x2) = match options.marks {
+                    Marks::Circles => (x as f64 + 0.5, end as f64 - 0.5),
+                    Marks::Strokes => (x as f64 + 0.5 - half, end as f64 - 0.5 + half),
+                };
+                if options.marks == Marks::Circles && end == x + 1 {
+                    marks.push_str(&format!("    <circle cx=\"{}\" cy=\"{}\" r=\"{}\"/>\n", number(x1), cy, number(half)));
+                } else {
+                    marks.push_str(&format!("    <line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\"/>\n", number(x1), cy, number(x2), cy));
+                }
+                x = end;
+            }
+        }
+        if marks.is_empty() {
+            continue;
+        }
+        let hex = format!("#{:02x}{:02x}{:02x}", p.r, p.g, p.b);
+        out.push_str(&format!(
+            "  <g id=\"pen{}\" inkscape:groupmode=\"layer\" inkscape:label=\"{} {}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\" stroke-linecap=\"round\">\n",
+            color, color, hex, hex, number(options.pen_width)));
+        out.push_str(&marks);
+        out.push_str("  </g>\n");
+    }
+    out.push_str("</svg>\n");
+    writer.write_all(out.as_bytes())
+}
+
+#[test]
+fn runs_merge_into_lines() {
+    let mut bmp = Bmp::new(5, 1);
+    bmp.palette = Some(vec![Pixel::black(), Pixel::white(), Pixel::red()]);
+    bmp.pixels[1][0] = Pixel::white();
+    bmp.pixels[2][0] = Pixel::white();
+    bmp.pixels[4][0] = Pixel::red();
+    let mut out = Vec::new();
+    write_svg(&bmp, &mut out, &SvgOptions::default()).unwrap();
+    let svg = String::from_utf8(out).unwrap();
+    assert_eq!(2, svg.matches("<g ").count());
+    assert!(svg.contains("<circle cx=\"0.5\" cy=\"0.5\" r=\"0.3\"/>\n    <circle cx=\"3.5\" cy=\"0.5\" r=\"0.3\"/>"));
+    assert!(svg.contains("stroke=\"#ff0000\" stroke-width=\"0.3\" stroke-linecap=\"round\">\n    <circle cx=\"4.5\""));
+
+    let mut out = Vec::new();
+    bmp.pixels[1][0] = Pixel::black();
+    write_svg(&bmp, &mut out, &SvgOptions { marks: Marks::Strokes, ..SvgOptions::default() }).unwrap();
+    assert!(String::from_utf8(out).unwrap().contains("<line x1=\"0.2\" y1=\"0.5\" x2=\"1.8\" y2=\"0.5\"/>\n    <line x1=\"3.2\" y1=\"0.5\" x2=\"3.8\""));
+}
@@ -0,0 +1,203 @@
+use std::io::{Error, ErrorKind, Result, Write};
+
+use bmp::{Bmp, Pixel};
+
+/// Characters per sixel are 6 pixels tall.
+const SIXEL_HEIGHT: usize = 6;
+
+//----------------------------------------------------------------------- Sixel
+
+/// Appends a sixel character, or a run of one, in its shortest encoding.
+fn push_sixel_run(out: &mut Vec<u8>, sixel: u8, run: usize) {
+    if run > 3 {
+        out.extend_from_slice(format!("!{}", run).as_bytes());
+        out.push(sixel);
+    } else {
+        for _ in 0..run {
+            out.push(sixel);
+        }
+    }
+}
+
+/// Renders the image as DEC sixel graphics, with the dithering palette as the color
+/// registers.  Transparent pixels are left unpainted so the terminal's background shows.
+pub fn write_sixel<W: Write>(bmp: &Bmp, writer: &mut W) -> Result<()> {
+    let indexed = match bmp.indexed(true) {
+        Some(indexed) => indexed,
+        None => return Err(Error::new(ErrorKind::InvalidInput, "sixel output needs an image dithered to at most 256 colors")),
+    };
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let percent = |v: u8| (v as u32 * 100 + 127) / 255;
+
+    let mut out = Vec::new();
+    // 1:1 pixel aspect ratio with unpainted pixels left transparent
+    out.extend_from_slice(format!("\x1bP0;1;0q\"1;1;{};{}", width, height).as_bytes());
+    for (i, p) in indexed.palette.iter().enumerate() {
+        out.extend_from_slice(format!("#{};2;{};{};{}", i, percent(p.r), percent(p.g), percent(p.b)).as_bytes());
+    }
+
+    let mut used = vec![false; indexed.palette.len()];
+    for band in (0..height).step_by(SIXEL_HEIGHT) {
+        let rows = SIXEL_HEIGHT.min(height - band);
+        for u in used.iter_mut() {
+            *u = false;
+        }
+        for y in band..band + rows {
+            for &index in &indexed.indices[y * width..(y + 1) * width] {
+                used[index as usize] = true;
+            }
+        }
+        if let Some(transparent) = indexed.transparent {
+            used[transparent as usize] = false;
+        }
+
+        let mut first = true;
+        for (color, _) in used.iter().enumerate().filter(|&(_, used)| *used) {
+            if !first {
+                // back to the start of the band for the next color
+                out.push(b'$');
+            }
+            first = false;
+            out.extend_from_slice(format!("#{}", color).as_bytes());
+            let (mut sixel, mut run) = (0u8, 0);
+            for x in 0..width {
+                let mut bits = 0;
+                for i in 0..rows {
+                    if indexed.indices[(band + i) * width + x] as usize == color {
+                        bits |= 1 << i;
+                    }
+                }
+                let next = b'?' + bits;
+                if next != sixel && run > 0 {
+                    push_sixel_run(&mut out, sixel, run);
+                    run = 0;
+                }
+                sixel = next;
+                run += 1;
+            }
+            // unpainted pixels at the end of a line needn't be sent
+            if sixel != b'?' {
+                push_sixel_run(&mut out, sixel, run);
+            }
+        }
+        out.push(b'-');
+    }
+    out.extend_from_slice(b"\x1b\\");
+    writer.write_all(&out)
+}
+
+//------------------------------------------------------------------------ Ansi
+
+/// Shrinks the image to `width` pixels wide, keeping its aspect ratio, by averaging the
+/// pixels each output pixel covers.  Images already narrow enough are copied as is.
+pub fn downscale(bmp: &Bmp, width: u32) -> Bmp {
+    let (source_width, source_height) = (bmp.width() as usize, bmp.height() as usize);
+    let width = (width as usize).max(1).min(source_width.max(1));
+    let height = ((source_height * width + source_width / 2) / source_width.max(1)).max(1);
+    let mut out = Bmp::new(width as u32, height as u32);
+    for x in 0..width {
+        let (x0, x1) = (x * source_width / width, ((x + 1) * source_width / width).max(x * source_width / width + 1));
+        for y in 0..height {
+            let (y0, y1) = (y * source_height / height, ((y + 1) * source_height / height).max(y * source_height / height + 1));
+            let mut sum = [0u32; 4];
+            for sx in x0..x1.min(source_width) {
+                for sy in y0..y1.min(source_height) {
+                    let p = bmp.pixels[sx][sy];
+                    // weight colors by alpha so transparent pixels don't darken their neighbors
+                    sum[0] += p.r as u32 * p.a as u32;
+                    sum[1] += p.g as u32 * p.a as u32;
+                    sum[2] += p.b as u32 * p.a as u32;
+                    sum[3] += p.a as u32;
+                }
+            }
+            let count = ((x1.min(source_width) - x0) * (y1.min(source_height) - y0)) as u32;
+            out.pixels[x][y] = if sum[3] == 0 {
+                Pixel {r: 0, g: 0, b: 0, a: 0}
+            } else {
+                Pixel {
+                    r: (sum[0] / sum[3]) as u8,
+                    g: (sum[1] / sum[3]) as u8,
+                    b: (sum[2] / sum[3]) as u8,
+                    a: (sum[3] / count) as u8,
+                }
+            };
+        }
+    }
+    out
+}
+
+/// Renders the image as 24 bit ANSI colored upper half blocks, two pixel rows per line of
+/// text, shrinking it first to at most `columns` pixels wide.  Mostly transparent pixels
+/// show the terminal's background.
+pub fn write_ansi<W: Write>(bmp: &Bmp, writer: &mut W, columns: Option<u32>) -> Result<()> {
+    let scaled;
+    let bmp = match columns {
+        Some(columns) if bmp.width() > columns => {
+            scaled = downscale(bmp, columns);
+            &scaled
+        },
+        _ => bmp,
+    };
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let visible = |p: Pixel| if p.a >= 128 { Some((p.r, p.g, p.b)) } else { None };
+
+    let mut out = Vec::new();
+    for y in (0..height).step_by(2) {
+        let (mut last_fg, mut last_bg) = (None, None);
+        for x in 0..width {
+            let top = visible(bmp.pixels[x][y]);
+            let bottom = if y + 1 < height { visible(bmp.pixels[x][y + 1]) } else { None };
+            // the foreground paints the glyph; an upper half block unless only the bottom shows
+            let (glyph, fg, bg) = match (top, bottom) {
+                (Some(_), _) => ("\u{2580}", top, bottom),
+                (None, Some(_)) => ("\u{2584}", bottom, None),
+                (None, None) => (" ", None, None),
+            };
+            if fg.is_some() && fg != last_fg {
+                let (r, g, b) = fg.unwrap();
+                out.extend_from_slice(format!("\x1b[38;2;{};{};{}m", r, g, b).as_bytes());
+                last_fg = fg;
+            }
+            if bg != last_bg || x == 0 {
+                match bg {
+                    Some((r, g, b)) => out.extend_from_slice(format!("\x1b[48;2;{};{};{}m", r, g, b).as_bytes()),
+                    None => out.extend_from_slice(b"\x1b[49m"),
+                }
+                last_bg = bg;
+            }
+            out.extend_from_slice(glyph.as_bytes());
+        }
+        out.extend_from_slice(b"\x1b[0m\n");
+    }
+    writer.write_all(&out)
+}
+
+#[test]
+fn sixel_encodes_bands_and_runs() {
+    let mut bmp = Bmp::new(5, 7);
+    bmp.palette = Some(vec![Pixel::black(), Pixel::white()]);
+    for x in 0..5 {
+        bmp.pixels[x][6] = Pixel::white();
+    }
+    bmp.pixels[4][0] = Pixel {r: 0, g: 0, b: 0, a: 0};
+    let mut out = Vec::new();
+    write_sixel(&bmp, &mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert_eq!("\x1bP0;1;0q\"1;1;5;7#0;2;0;0;0#1;2;100;100;100#2;2;0;0;0#0!4~}-#1!5@-\x1b\\", text);
+}
+
+#[test]
+fn downscale_averages() {
+    let mut bmp = Bmp::new(4, 2);
+    for y in 0..2 {
+        bmp.pixels[0][y] = Pixel::white();
+        bmp.pixels[2][y] = Pixel::red();
+        bmp.pixels[3][y] = Pixel::red();
+    }
+    let small = downscale(&bmp, 2);
+    assert_eq!((2, 1), (small.width(), small.height()));
+    assert_eq!(Pixel {r: 127, g: 127, b: 127, a: 255}, small.pixels[0][0]);
+    assert_eq!(Pixel::red(), small.pixels[1][0]);
+}
@@ -0,0 +1,143 @@
+use std::io::{Result, Write};
+
+use bmp::{Bmp, Pixel};
+
+/// Text cells cover this many pixels across and down, matching a braille character's dots.
+pub const CELL_WIDTH: usize = 2;
+pub const CELL_HEIGHT: usize = 4;
+
+/// A light to dense ramp for `Glyphs::Ascii`.
+pub const DEFAULT_RAMP: &str = " .:-=+*#%@";
+
+/// How a cell of dots is drawn.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Glyphs {
+    /// One U+2800 braille pattern per cell, raising a dot for every lit pixel.
+    Braille,
+    /// A character from the ramp, chosen by how many of the cell's pixels are lit.
+    Ascii(String),
+}
+
+/// Options controlling how `write_text` renders a 1 bit image.
+#[derive(Clone, Debug)]
+pub struct TextOptions {
+    pub glyphs: Glyphs,
+    /// Color each character with the average color of its cell in the source image.
+    pub ansi: bool,
+    /// Light dark pixels instead of bright ones, for dark text on a light background.
+    pub invert: bool,
+}
+
+impl Default for TextOptions {
+    fn default() -> TextOptions {
+        TextOptions {
+            glyphs: Glyphs::Braille,
+            ansi: false,
+            invert: false,
+        }
+    }
+}
+
+/// The bit of a braille pattern for the dot at (x, y) within its cell, following the
+/// dot numbering 1-2-3-7 down the left column and 4-5-6-8 down the right.
+fn braille_bit(x: usize, y: usize) -> u32 {
+    match (x, y) {
+        (0, 3) => 0x40,
+        (1, 3) => 0x80,
+        (0, y) => 1 << y,
+        (_, y) => 1 << (y + 3),
+    }
+}
+
+/// Renders a dithered 1 bit image as text, one character per 2x4 pixel cell.  `source`,
+/// when given, is the image before dithering and supplies the ANSI colors; it must be the
+/// same size as `dots`.
+pub fn write_text<W: Write>(dots: &Bmp, source: Option<&Bmp>, writer: &mut W, options: &TextOptions) -> Result<()> {
+    let width = dots.width() as usize;
+    let height = dots.height() as usize;
+    let ramp: Vec<char> = match options.glyphs {
+        Glyphs::Ascii(ref ramp) if !ramp.is_empty() => ramp.chars().collect(),
+        _ => DEFAULT_RAMP.chars().collect(),
+    };
+    let colors = if options.ansi { source.or(Some(dots)) } else { None };
+
+    let mut out = String::new();
+    for cell_y in 0..(height + CELL_HEIGHT - 1) / CELL_HEIGHT {
+        let mut last_color = None;
+        for cell_x in 0..(width + CELL_WIDTH - 1) / CELL_WIDTH {
+            let mut pattern = 0;
+            let mut lit = 0;
+            let mut sum = [0u32; 4];
+            for dy in 0..CELL_HEIGHT {
+                for dx in 0..CELL_WIDTH {
+                    let (x, y) = (cell_x * CELL_WIDTH + dx, cell_y * CELL_HEIGHT + dy);
+                    if x >= width || y >= height {
+                        continue;
+                    }
+                    let p = dots.pixels[x][y];
+                    if !p.is_transparent() && (p.luma() >= 128) != options.invert {
+                        pattern |= braille_bit(dx, dy);
+                        lit += 1;
+                    }
+                    if let Some(colors) = colors {
+                        let c = colors.pixels[x][y];
+                        if !c.is_transparent() {
+                            sum[0] += c.r as u32;
+                            sum[1] += c.g as u32;
+                            sum[2] += c.b as u32;
+                            sum[3] += 1;
+                        }
+                    }
+                }
+            }
+
+            if sum[3] > 0 && lit > 0 {
+                let color = Pixel {r: (sum[0] / sum[3]) as u8, g: (sum[1] / sum[3]) as u8, b: (sum[2] / sum[3]) as u8, a: 255};
+                if last_color != Some(color) {
+                    out.push_str(&format!("\x1b[38;2;{};{};{}m", color.r, color.g, color.b));
+                    last_color = Some(color);
+                }
+            }
+            out.push(match options.glyphs {
+                Glyphs::Braille => ::std::char::from_u32(0x2800 + pattern).unwrap_or(' '),
+                Glyphs::Ascii(_) => ramp[lit * (ramp.len() - 1) / (CELL_WIDTH * CELL_HEIGHT)],
+            });
+        }
+        if last_color.is_some() {
+            out.push_str("\x1b[0m");
+        }
+        out.push('\n');
+    }
+    writer.write_all(out.as_bytes())
+}
+
+#[test]
+fn braille_cells() {
+    // lighting the left column and the bottom right dot gives dots 1, 2, 3, 7 and 8
+    let mut bmp = Bmp::new(3, 4);
+    for y in 0..4 {
+        bmp.pixels[0][y] = Pixel::white();
+    }
+    bmp.pixels[1][3] = Pixel::white();
+    bmp.pixels[2][0] = Pixel::white();
+    let mut out = Vec::new();
+    write_text(&bmp, None, &mut out, &TextOptions::default()).unwrap();
+    assert_eq!("\u{28c7}\u{2801}\n", String::from_utf8(out).unwrap());
+}
+
+#[test]
+fn ascii_ramp_by_density() {
+    let mut bmp = Bmp::new(4, 4);
+    for y in 0..4 {
+        bmp.pixels[2][y] = Pixel::white();
+        bmp.pixels[3][y] = Pixel::white();
+    }
+    let options = TextOptions { glyphs: Glyphs::Ascii(" +#".to_string()), ..TextOptions::default() };
+    let mut out = Vec::new();
+    write_text(&bmp, None, &mut out, &options).unwrap();
+    assert_eq!(" #\n", String::from_utf8(out.clone()).unwrap());
+
+    out.clear();
+    write_text(&bmp, None, &mut out, &TextOptions { invert: true, ..options }).unwrap();
+    assert_eq!("# \n", String::from_utf8(out).unwrap());
+}
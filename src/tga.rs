@@ -0,0 +1,351 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+extern crate byteorder;
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp::{Bmp, LoadOptions, Pixel};
+
+const COLORMAPPED: u8 = 1;
+const TRUECOLOR: u8 = 2;
+const GRAYSCALE: u8 = 3;
+/// Added to an image type for its run length encoded variant.
+const RLE: u8 = 8;
+
+const RIGHT_TO_LEFT: u8 = 0x10;
+const TOP_TO_BOTTOM: u8 = 0x20;
+
+//-------------------------------------------------------------------- TgaError
+
+#[derive(Debug)]
+pub enum TgaError {
+    /// The header held invalid values or a color map index was out of range.
+    Malformed(String),
+    /// The image type or pixel depth isn't supported.
+    Unsupported(String),
+    /// The image dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The file ended before the image data did.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for TgaError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            TgaError::Malformed(ref message) => write!(formatter, "malformed TGA: {}", message),
+            TgaError::Unsupported(ref message) => write!(formatter, "unsupported TGA: {}", message),
+            TgaError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            TgaError::Truncated => write!(formatter, "unexpected end of file"),
+            TgaError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for TgaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            TgaError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for TgaError {
+    fn from(err: ::std::io::Error) -> TgaError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => TgaError::Truncated,
+            _ => TgaError::Io(err),
+        }
+    }
+}
+
+pub type TgaResult<T> = Result<T, TgaError>;
+
+//---------------------------------------------------------------------- Reader
+
+/// Decodes one little endian pixel or color map entry of `depth` bits.  Alpha is only
+/// honored when the descriptor says the image has alpha bits.
+fn decode_color(data: &[u8], depth: u8, alpha: bool) -> Pixel {
+    match depth {
+        15 | 16 => {
+            let v = data[0] as u16 | (data[1] as u16) << 8;
+            let scale = |c: u16| ((c & 0x1f) * 255 / 31) as u8;
+            Pixel {
+                r: scale(v >> 10),
+                g: scale(v >> 5),
+                b: scale(v),
+                a: if alpha && depth == 16 && v & 0x8000 == 0 { 0 } else { 255 },
+            }
+        },
+        24 => Pixel {r: data[2], g: data[1], b: data[0], a: 255},
+        32 => Pixel {r: data[2], g: data[1], b: data[0], a: if alpha { data[3] } else { 255 }},
+        // 8 bit grayscale
+        _ => Pixel {r: data[0], g: data[0], b: data[0], a: 255},
+    }
+}
+
+pub fn load(path_str: &str) -> TgaResult<Bmp> {
+    let file = File::open(Path::new(path_str))?;
+    read_from(&mut ::std::io::BufReader::new(file))
+}
+
+pub fn read_from<R: Read>(reader: &mut R) -> TgaResult<Bmp> {
+    read_from_with(reader, &LoadOptions::default())
+}
+
+pub fn read_from_with<R: Read>(reader: &mut R, options: &LoadOptions) -> TgaResult<Bmp> {
+    let mut header = [0u8; 18];
+    reader.read_exact(&mut header)?;
+    let id_length = header[0] as u64;
+    let has_map = header[1] == 1;
+    let image_type = header[2];
+    let map_first = header[3] as usize | (header[4] as usize) << 8;
+    let map_length = header[5] as usize | (header[6] as usize) << 8;
+    let map_depth = header[7];
+    let width = header[12] as u32 | (header[13] as u32) << 8;
+    let height = header[14] as u32 | (header[15] as u32) << 8;
+    let depth = header[16];
+    let descriptor = header[17];
+    let alpha = descriptor & 0x0f != 0;
+
+    if header[1] > 1 {
+        return Err(TgaError::Malformed(format!("color map type {}", header[1])));
+    }
+    let base_type = image_type & !RLE;
+    let valid_depth = match base_type {
+        COLORMAPPED => depth == 8 && has_map,
+        TRUECOLOR => [15, 16, 24, 32].contains(&depth),
+        GRAYSCALE => depth == 8,
+        _ => return Err(TgaError::Unsupported(format!("image type {}", image_type))),
+    };
+    if !valid_depth {
+        return Err(TgaError::Unsupported(format!("{} bits per pixel for image type {}", depth, image_type)));
+    }
+    if has_map && ![15, 16, 24, 32].contains(&map_depth) {
+        return Err(TgaError::Unsupported(format!("{} bit color map entries", map_depth)));
+    }
+    if !options.allows(width, height) {
+        return Err(TgaError::TooLarge(width, height));
+    }
+
+    // skip the image ID, then read the color map even for truecolor images, which may carry
+    // one for no particular reason
+    let skipped = ::std::io::copy(&mut reader.take(id_length), &mut ::std::io::sink())?;
+    if skipped != id_length {
+        return Err(TgaError::Truncated);
+    }
+    let mut palette = Vec::new();
+    if has_map {
+        let entry_size = (map_depth as usize + 7) / 8;
+        let mut data = vec![0u8; map_length * entry_size];
+        reader.read_exact(&mut data)?;
+        palette = data.chunks(entry_size).map(|c| decode_color(c, map_depth, alpha)).collect();
+    }
+
+    let pixel_size = (depth as usize + 7) / 8;
+    let count = width as usize * height as usize;
+    let mut data = Vec::with_capacity(count * pixel_size);
+    if image_type & RLE != 0 {
+        let mut packet = vec![0u8; pixel_size];
+        while data.len() < count * pixel_size {
+            let packet_header = reader.read_u8()?;
+            let length = (packet_header & 0x7f) as usize + 1;
+            if packet_header & 0x80 != 0 {
+                reader.read_exact(&mut packet)?;
+                for _ in 0..length {
+                    data.extend_from_slice(&packet);
+                }
+            } else {
+                let start = data.len();
+                data.resize(start + length * pixel_size, 0);
+                reader.read_exact(&mut data[start..])?;
+            }
+        }
+        // runs may overshoot the last pixel in sloppy files
+        data.truncate(count * pixel_size);
+    } else {
+        data.resize(count * pixel_size, 0);
+        reader.read_exact(&mut data)?;
+    }
+
+    let mut bmp = Bmp::new(width, height);
+    for (i, color) in data.chunks(pixel_size).enumerate() {
+        let (column, row) = (i % width as usize, i / width as usize);
+        let x = if descriptor & RIGHT_TO_LEFT != 0 { width as usize - 1 - column } else { column };
+        let y = if descriptor & TOP_TO_BOTTOM != 0 { row } else { height as usize - 1 - row };
+        bmp.pixels[x][y] = if base_type == COLORMAPPED {
+            let index = color[0] as usize;
+            match index.checked_sub(map_first).and_then(|i| palette.get(i)) {
+                Some(p) => *p,
+                None => return Err(TgaError::Malformed(format!("color map index {} out of range", index))),
+            }
+        } else {
+            decode_color(color, depth, alpha)
+        };
+    }
+    if base_type == COLORMAPPED && palette.len() <= 256 {
+        bmp.palette = Some(palette);
+    }
+    Ok(bmp)
+}
+
+//---------------------------------------------------------------------- Writer
+
+/// Options controlling how `tga::write_to_with` encodes an image.
+#[derive(Clone, Debug, Default)]
+pub struct TgaOptions {
+    /// Run length encode the pixels, with packets never crossing a row.
+    pub rle: bool,
+}
+
+/// Appends one row of `size` byte pixels as TGA run length packets.
+fn rle_encode(row: &[u8], size: usize, out: &mut Vec<u8>) {
+    let pixels: Vec<&[u8]> = row.chunks(size).collect();
+    let mut i = 0;
+    while i < pixels.len() {
+        let mut run = 1;
+        while i + run < pixels.len() && run < 128 && pixels[i + run] == pixels[i] {
+            run += 1;
+        }
+        if run > 1 {
+            out.push(0x80 | (run - 1) as u8);
+            out.extend_from_slice(pixels[i]);
+            i += run;
+            continue;
+        }
+        // gather literals up to the next run of at least two
+        let start = i;
+        while i < pixels.len() && i - start < 128 && (i + 1 >= pixels.len() || pixels[i + 1] != pixels[i]) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        for p in &pixels[start..i] {
+            out.extend_from_slice(p);
+        }
+    }
+}
+
+pub fn save(bmp: &Bmp, path_str: &str) -> TgaResult<()> {
+    save_with(bmp, path_str, &TgaOptions::default())
+}
+
+pub fn save_with(bmp: &Bmp, path_str: &str, options: &TgaOptions) -> TgaResult<()> {
+    let file = File::create(Path::new(path_str))?;
+    let mut file = ::std::io::BufWriter::new(file);
+    write_to_with(bmp, &mut file, options)?;
+    file.flush()?;
+    Ok(())
+}
+
+pub fn write_to<W: Write>(bmp: &Bmp, writer: &mut W) -> TgaResult<()> {
+    write_to_with(bmp, writer, &TgaOptions::default())
+}
+
+/// Writes an 8 bit colormapped image using the dithering palette when every pixel is
+/// found in it, and a 24 or 32 bit truecolor image otherwise.
+pub fn write_to_with<W: Write>(bmp: &Bmp, writer: &mut W, options: &TgaOptions) -> TgaResult<()> {
+    if bmp.width() > 0xffff || bmp.height() > 0xffff {
+        return Err(TgaError::TooLarge(bmp.width(), bmp.height()));
+    }
+    let width = bmp.width() as usize;
+    let height = bmp.height() as usize;
+    let indexed = bmp.indexed(true);
+    let alpha = match indexed {
+        Some(ref indexed) => indexed.palette.iter().any(|p| p.a != 255),
+        None => bmp.has_alpha(),
+    };
+    let color_size = if alpha { 4 } else { 3 };
+    let encode = |p: &Pixel, out: &mut Vec<u8>| {
+        out.extend_from_slice(&[p.b, p.g, p.r]);
+        if alpha {
+            out.push(p.a);
+        }
+    };
+
+    let image_type = if indexed.is_some() { COLORMAPPED } else { TRUECOLOR } + if options.rle { RLE } else { 0 };
+    let (map_length, pixel_size) = match indexed {
+        Some(ref indexed) => (indexed.palette.len(), 1),
+        None => (0, color_size),
+    };
+    writer.write_u8(0)?;
+    writer.write_u8(if indexed.is_some() { 1 } else { 0 })?;
+    writer.write_u8(image_type)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(map_length as u16)?;
+    writer.write_u8(if indexed.is_some() { color_size as u8 * 8 } else { 0 })?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(0)?;
+    writer.write_u16::<LittleEndian>(width as u16)?;
+    writer.write_u16::<LittleEndian>(height as u16)?;
+    writer.write_u8(pixel_size as u8 * 8)?;
+    // rows are stored bottom up, the format's default
+    writer.write_u8(if alpha { 8 } else { 0 })?;
+
+    let mut data = Vec::new();
+    if let Some(ref indexed) = indexed {
+        for p in &indexed.palette {
+            encode(p, &mut data);
+        }
+    }
+    let mut row = Vec::with_capacity(width * pixel_size);
+    for y in (0..height).rev() {
+        row.clear();
+        for x in 0..width {
+            match indexed {
+                Some(ref indexed) => row.push(indexed.indices[y * width + x]),
+                None => encode(&bmp.pixels[x][y], &mut row),
+            }
+        }
+        if options.rle {
+            rle_encode(&row, pixel_size, &mut data);
+        } else {
+            data.extend_from_slice(&row);
+        }
+    }
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+#[test]
+fn tga_round_trip() {
+    let mut color = Bmp::new(7, 3);
+    for x in 0..7 {
+        color.pixels[x][1] = Pixel {r: x as u8 * 30, g: 5, b: 200, a: 255};
+    }
+    color.pixels[6][2] = Pixel {r: 1, g: 2, b: 3, a: 100};
+    let mut indexed = Bmp::new(200, 2);
+    indexed.palette = Some(vec![Pixel::black(), Pixel::white(), Pixel::blue()]);
+    indexed.pixels[150][0] = Pixel::white();
+    indexed.pixels[3][1] = Pixel::blue();
+
+    for bmp in &[color, indexed] {
+        for rle in &[false, true] {
+            let mut data = Vec::new();
+            write_to_with(bmp, &mut data, &TgaOptions { rle: *rle }).unwrap();
+            let loaded = read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+            for x in 0..bmp.width() as usize {
+                for y in 0..bmp.height() as usize {
+                    assert_eq!(bmp.pixels[x][y], loaded.pixels[x][y]);
+                }
+            }
+            assert_eq!(bmp.palette.is_some(), loaded.palette.is_some());
+        }
+    }
+}
+
+#[test]
+fn read_top_down_rle_16_bit() {
+    // a 3x2 top down image: a run of two reds, then a raw white and three blue pixels
+    let mut data = vec![0, 0, TRUECOLOR + RLE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 2, 0, 16, TOP_TO_BOTTOM];
+    data.extend_from_slice(&[0x81, 0x00, 0x7c, 0x00, 0xff, 0x7f, 0x82, 0x1f, 0x00]);
+    let bmp = read_from(&mut ::std::io::Cursor::new(data)).unwrap();
+    assert_eq!(Pixel::red(), bmp.pixels[0][0]);
+    assert_eq!(Pixel::red(), bmp.pixels[1][0]);
+    assert_eq!(Pixel::white(), bmp.pixels[2][0]);
+    assert_eq!(Pixel::blue(), bmp.pixels[2][1]);
+}
@@ -0,0 +1,359 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::{ErrorKind, Read, Write};
+
+use bmp::{Bmp, LoadOptions, Pixel};
+
+const MAGIC: &[u8] = b"YUV4MPEG2";
+const FRAME: &[u8] = b"FRAME";
+
+//-------------------------------------------------------------------- Y4mError
+
+#[derive(Debug)]
+pub enum Y4mError {
+    /// The stream didn't start with `YUV4MPEG2`.
+    BadMagic,
+    /// The stream or frame header held invalid values.
+    Malformed(String),
+    /// The stream uses a colorspace or bit depth that isn't supported.
+    Unsupported(String),
+    /// The frame dimensions exceed the configured `LoadOptions` limits.
+    TooLarge(u32, u32),
+    /// The stream ended partway through a frame.
+    Truncated,
+    Io(::std::io::Error),
+}
+
+impl fmt::Display for Y4mError {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        match *self {
+            Y4mError::BadMagic => write!(formatter, "missing YUV4MPEG2 magic"),
+            Y4mError::Malformed(ref message) => write!(formatter, "malformed Y4M: {}", message),
+            Y4mError::Unsupported(ref message) => write!(formatter, "unsupported Y4M: {}", message),
+            Y4mError::TooLarge(width, height) => write!(formatter, "image dimensions {}x{} exceed the allowed limits", width, height),
+            Y4mError::Truncated => write!(formatter, "unexpected end of file"),
+            Y4mError::Io(ref err) => write!(formatter, "{}", err),
+        }
+    }
+}
+
+impl Error for Y4mError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            Y4mError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for Y4mError {
+    fn from(err: ::std::io::Error) -> Y4mError {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => Y4mError::Truncated,
+            _ => Y4mError::Io(err),
+        }
+    }
+}
+
+pub type Y4mResult<T> = Result<T, Y4mError>;
+
+//------------------------------------------------------------------- Y4mHeader
+
+/// The chroma layout of a stream's frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chroma {
+    /// Chroma at half resolution both ways, whatever its siting.
+    C420,
+    /// Chroma at half horizontal resolution.
+    C422,
+    /// Chroma at full resolution.
+    C444,
+    /// Luma only.
+    Mono,
+}
+
+impl Chroma {
+    /// The chroma planes' subsampling, as right shifts of the width and height.
+    fn shifts(&self) -> (u32, u32) {
+        match *self {
+            Chroma::C420 => (1, 1),
+            Chroma::C422 => (1, 0),
+            Chroma::C444 | Chroma::Mono => (0, 0),
+        }
+    }
+}
+
+/// The stream parameters from a YUV4MPEG2 header.
+#[derive(Clone, Debug)]
+pub struct Y4mHeader {
+    pub width: u32,
+    pub height: u32,
+    /// Frames per second, as a numerator and denominator.
+    pub frame_rate: (u32, u32),
+    /// The pixel aspect ratio, or (0, 0) when unknown.
+    pub aspect: (u32, u32),
+    pub chroma: Chroma,
+    /// Samples use the full 0-255 range rather than video's 16-235.
+    pub full_range: bool,
+}
+
+impl Y4mHeader {
+    pub fn new(width: u32, height: u32) -> Y4mHeader {
+        Y4mHeader {
+            width: width,
+            height: height,
+            frame_rate: (25, 1),
+            aspect: (1, 1),
+            chroma: Chroma::C444,
+            full_range: false,
+        }
+    }
+
+    /// The chroma planes' dimensions.
+    fn chroma_size(&self) -> (usize, usize) {
+        let (sx, sy) = self.chroma.shifts();
+        (((self.width + (1 << sx) - 1) >> sx) as usize, ((self.height + (1 << sy) - 1) >> sy) as usize)
+    }
+}
+
+/// Parses a `n:d` ratio.
+fn parse_ratio(value: &str) -> Y4mResult<(u32, u32)> {
+    let mut parts = value.splitn(2, ':').map(|v| v.parse::<u32>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(n)), Some(Ok(d))) => Ok((n, d)),
+        _ => Err(Y4mError::Malformed(format!("bad ratio '{}'", value))),
+    }
+}
+
+/// Reads a header line, up to and not including its newline.
+fn read_line<R: Read>(reader: &mut R) -> Y4mResult<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            return if line.is_empty() { Ok(None) } else { Err(Y4mError::Truncated) };
+        }
+        if byte[0] == b'\n' {
+            return Ok(Some(line));
+        }
+        if line.len() > 4096 {
+            return Err(Y4mError::Malformed("header line too long".to_string()));
+        }
+        line.push(byte[0]);
+    }
+}
+
+//------------------------------------------------------------------ Conversion
+
+fn clamp(v: f32) -> u8 {
+    v.round().max(0.0).min(255.0) as u8
+}
+
+/// Converts BT.601 Y'CbCr to RGB.
+fn to_rgb(y: u8, cb: u8, cr: u8, full_range: bool) -> Pixel {
+    let (y, cb, cr) = (y as f32, cb as f32 - 128.0, cr as f32 - 128.0);
+    let (y, cb, cr) = if full_range {
+        (y, cb, cr)
+    } else {
+        ((y - 16.0) * 255.0 / 219.0, cb * 255.0 / 224.0, cr * 255.0 / 224.0)
+    };
+    Pixel {
+        r: clamp(y + 1.402 * cr),
+        g: clamp(y - 0.344136 * cb - 0.714136 * cr),
+        b: clamp(y + 1.772 * cb),
+        a: 255,
+    }
+}
+
+/// Converts RGB to BT.601 Y'CbCr.
+fn to_ycbcr(p: Pixel, full_range: bool) -> (f32, f32, f32) {
+    let (r, g, b) = (p.r as f32, p.g as f32, p.b as f32);
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let cb = -0.168736 * r - 0.331264 * g + 0.5 * b;
+    let cr = 0.5 * r - 0.418688 * g - 0.081312 * b;
+    if full_range {
+        (y, cb + 128.0, cr + 128.0)
+    } else {
+        (16.0 + y * 219.0 / 255.0, 128.0 + cb * 224.0 / 255.0, 128.0 + cr * 224.0 / 255.0)
+    }
+}
+
+//------------------------------------------------------------------- Y4mReader
+
+/// Reads the frames of a YUV4MPEG2 stream one at a time.
+pub struct Y4mReader<R: Read> {
+    reader: R,
+    pub header: Y4mHeader,
+}
+
+impl<R: Read> Y4mReader<R> {
+    pub fn new(reader: R) -> Y4mResult<Y4mReader<R>> {
+        Y4mReader::new_with(reader, &LoadOptions::default())
+    }
+
+    /// Reads the stream header, rejecting frames larger than `options` allows.
+    pub fn new_with(mut reader: R, options: &LoadOptions) -> Y4mResult<Y4mReader<R>> {
+        let line = match read_line(&mut reader)? {
+            Some(ref line) if line.starts_with(MAGIC) => String::from_utf8_lossy(&line[MAGIC.len()..]).into_owned(),
+            _ => return Err(Y4mError::BadMagic),
+        };
+        let mut header = Y4mHeader::new(0, 0);
+        header.chroma = Chroma::C420;
+        for tag in line.split(' ').filter(|tag| !tag.is_empty()) {
+            let value = tag.get(1..).unwrap_or("");
+            match tag.as_bytes()[0] {
+                b'W' => header.width = value.parse().map_err(|_| Y4mError::Malformed(format!("bad width '{}'", value)))?,
+                b'H' => header.height = value.parse().map_err(|_| Y4mError::Malformed(format!("bad height '{}'", value)))?,
+                b'F' => header.frame_rate = parse_ratio(value)?,
+                b'A' => header.aspect = parse_ratio(value)?,
+                b'I' if value != "p" && value != "?" => return Err(Y4mError::Unsupported("interlaced frames".to_string())),
+                b'C' => header.chroma = match value {
+                    "420" | "420jpeg" | "420paldv" | "420mpeg2" => Chroma::C420,
+                    "422" => Chroma::C422,
+                    "444" => Chroma::C444,
+                    "mono" => Chroma::Mono,
+                    _ => return Err(Y4mError::Unsupported(format!("colorspace '{}'", value))),
+                },
+                b'X' if value == "COLORRANGE=FULL" => header.full_range = true,
+                _ => {},
+            }
+        }
+        if header.width == 0 || header.height == 0 {
+            return Err(Y4mError::Malformed("missing frame size".to_string()));
+        }
+        if !options.allows(header.width, header.height) {
+            return Err(Y4mError::TooLarge(header.width, header.height));
+        }
+        Ok(Y4mReader {
+            reader: reader,
+            header: header,
+        })
+    }
+
+    /// Reads the next frame, or `None` at the end of the stream.
+    pub fn read_frame(&mut self) -> Y4mResult<Option<Bmp>> {
+        match read_line(&mut self.reader)? {
+            Some(ref line) if line.starts_with(FRAME) => {},
+            Some(_) => return Err(Y4mError::Malformed("expected a FRAME header".to_string())),
+            None => return Ok(None),
+        }
+        let width = self.header.width as usize;
+        let height = self.header.height as usize;
+        let mut luma = vec![0u8; width * height];
+        self.reader.read_exact(&mut luma)?;
+        let (chroma_width, chroma_height) = self.header.chroma_size();
+        let (mut cb, mut cr) = (vec![128u8; chroma_width * chroma_height], vec![128u8; chroma_width * chroma_height]);
+        if self.header.chroma != Chroma::Mono {
+            self.reader.read_exact(&mut cb)?;
+            self.reader.read_exact(&mut cr)?;
+        }
+
+        let (sx, sy) = self.header.chroma.shifts();
+        let mut bmp = Bmp::new(self.header.width, self.header.height);
+        for y in 0..height {
+            for x in 0..width {
+                let c = (y >> sy) * chroma_width + (x >> sx);
+                bmp.pixels[x][y] = to_rgb(luma[y * width + x], cb[c], cr[c], self.header.full_range);
+            }
+        }
+        Ok(Some(bmp))
+    }
+}
+
+//------------------------------------------------------------------- Y4mWriter
+
+/// Writes frames as a YUV4MPEG2 stream.
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    header: Y4mHeader,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Writes the stream header; every frame must then match its size.
+    pub fn new(mut writer: W, header: &Y4mHeader) -> Y4mResult<Y4mWriter<W>> {
+        let chroma = match header.chroma {
+            Chroma::C420 => "420jpeg",
+            Chroma::C422 => "422",
+            Chroma::C444 => "444",
+            Chroma::Mono => "mono",
+        };
+        let mut line = format!("YUV4MPEG2 W{} H{} F{}:{} Ip A{}:{} C{}",
+                               header.width, header.height, header.frame_rate.0, header.frame_rate.1,
+                               header.aspect.0, header.aspect.1, chroma);
+        if header.full_range {
+            line.push_str(" XCOLORRANGE=FULL");
+        }
+        line.push('\n');
+        writer.write_all(line.as_bytes())?;
+        Ok(Y4mWriter {
+            writer: writer,
+            header: header.clone(),
+        })
+    }
+
+    /// Writes a frame, averaging the chroma of each subsampled block.  Transparent pixels
+    /// are written as they are, since the stream has no alpha.
+    pub fn write_frame(&mut self, bmp: &Bmp) -> Y4mResult<()> {
+        if bmp.width() != self.header.width || bmp.height() != self.header.height {
+            return Err(Y4mError::Malformed(format!("frame is {}x{} but the stream is {}x{}", bmp.width(), bmp.height(), self.header.width, self.header.height)));
+        }
+        let width = self.header.width as usize;
+        let height = self.header.height as usize;
+        let (chroma_width, chroma_height) = self.header.chroma_size();
+        let (sx, sy) = self.header.chroma.shifts();
+        let mut luma = Vec::with_capacity(width * height);
+        let mut sums = vec![(0f32, 0f32, 0u32); chroma_width * chroma_height];
+        for y in 0..height {
+            for x in 0..width {
+                let (l, cb, cr) = to_ycbcr(bmp.pixels[x][y], self.header.full_range);
+                luma.push(clamp(l));
+                let sum = &mut sums[(y >> sy) * chroma_width + (x >> sx)];
+                *sum = (sum.0 + cb, sum.1 + cr, sum.2 + 1);
+            }
+        }
+        let mut out = Vec::with_capacity(FRAME.len() + 1 + luma.len() + sums.len() * 2);
+        out.extend_from_slice(FRAME);
+        out.push(b'\n');
+        out.extend_from_slice(&luma);
+        if self.header.chroma != Chroma::Mono {
+            out.extend(sums.iter().map(|&(cb, _, n)| clamp(cb / n as f32)));
+            out.extend(sums.iter().map(|&(_, cr, n)| clamp(cr / n as f32)));
+        }
+        self.writer.write_all(&out)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Y4mResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn y4m_round_trip() {
+    let mut bmp = Bmp::new(3, 2);
+    bmp.pixels[0][0] = Pixel::white();
+    bmp.pixels[1][0] = Pixel::red();
+    bmp.pixels[2][1] = Pixel::blue();
+    let mut out = Vec::new();
+    {
+        let mut writer = Y4mWriter::new(&mut out, &Y4mHeader::new(3, 2)).unwrap();
+        writer.write_frame(&bmp).unwrap();
+        writer.write_frame(&bmp).unwrap();
+    }
+    assert!(out.starts_with(b"YUV4MPEG2 W3 H2 F25:1 Ip A1:1 C444\nFRAME\n"));
+
+    let mut reader = Y4mReader::new(&out[..]).unwrap();
+    assert_eq!((3, 2, Chroma::C444), (reader.header.width, reader.header.height, reader.header.chroma));
+    for _ in 0..2 {
+        let frame = reader.read_frame().unwrap().unwrap();
+        for x in 0..3 {
+            for y in 0..2 {
+                let (a, b) = (frame.pixels[x][y], bmp.pixels[x][y]);
+                assert!((a.r as i32 - b.r as i32).abs() <= 2 && (a.g as i32 - b.g as i32).abs() <= 2 && (a.b as i32 - b.b as i32).abs() <= 2);
+            }
+        }
+    }
+    assert!(reader.read_frame().unwrap().is_none());
+}